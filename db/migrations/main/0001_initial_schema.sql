@@ -0,0 +1,41 @@
+-- Schema of the database as it was before migrations
+-- existed (it used to only live in db.empty.sqlite).
+CREATE TABLE IF NOT EXISTS "tags" (
+	"id"	INTEGER,
+	"name"	TEXT UNIQUE,
+	"main_tag"	INTEGER DEFAULT 1,
+	PRIMARY KEY("id" AUTOINCREMENT)
+);
+CREATE TABLE IF NOT EXISTS "article_tags" (
+	"article_id"	INTEGER,
+	"tag_id"	INTEGER,
+	PRIMARY KEY("article_id","tag_id")
+);
+CREATE TABLE IF NOT EXISTS "users" (
+	"id"	INTEGER,
+	"name"	INTEGER UNIQUE,
+	PRIMARY KEY("id" AUTOINCREMENT)
+);
+CREATE TABLE IF NOT EXISTS "comments" (
+	"id"	INTEGER,
+	"article_id"	INTEGER NOT NULL,
+	"author"	TEXT,
+	"comment"	TEXT,
+	"date"	INTEGER,
+	"client_ip"	TEXT,
+	PRIMARY KEY("id" AUTOINCREMENT)
+);
+CREATE TABLE IF NOT EXISTS "articles" (
+	"id"	INTEGER,
+	"title"	TEXT,
+	"article_url"	NUMERIC UNIQUE,
+	"thumb_image"	TEXT,
+	"date"	INTEGER,
+	"user_id"	INTEGER,
+	"summary"	TEXT,
+	"content"	TEXT,
+	"published"	INTEGER NOT NULL DEFAULT 1,
+	"short"	INTEGER NOT NULL DEFAULT 0,
+	PRIMARY KEY("id" AUTOINCREMENT)
+);
+CREATE VIRTUAL TABLE IF NOT EXISTS articles_ft USING FTS5(id, title, content);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS "api_tokens" (
+	"id"	INTEGER,
+	"name"	TEXT NOT NULL,
+	"token_hash"	TEXT NOT NULL UNIQUE,
+	"scopes"	TEXT NOT NULL DEFAULT '',
+	"date"	INTEGER,
+	PRIMARY KEY("id" AUTOINCREMENT)
+);
@@ -0,0 +1,24 @@
+-- Snapshots of articles taken right before they get
+-- updated, deleted or restored. The "author" is the name
+-- of the API token that made the change, "date" is when
+-- it happened and "article_date" is the date the article
+-- had at the time. Tags are a comma separated list of IDs.
+CREATE TABLE IF NOT EXISTS "article_revisions" (
+	"id"	INTEGER,
+	"article_id"	INTEGER NOT NULL,
+	"action"	TEXT NOT NULL,
+	"author"	TEXT,
+	"date"	INTEGER NOT NULL,
+	"title"	TEXT,
+	"article_url"	TEXT,
+	"thumb_image"	TEXT,
+	"article_date"	INTEGER,
+	"user_id"	INTEGER,
+	"summary"	TEXT,
+	"content"	TEXT,
+	"published"	INTEGER,
+	"short"	INTEGER,
+	"tag_ids"	TEXT,
+	PRIMARY KEY("id" AUTOINCREMENT)
+);
+CREATE INDEX IF NOT EXISTS "article_revisions_article_id" ON "article_revisions" ("article_id");
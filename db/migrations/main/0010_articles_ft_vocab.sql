@@ -0,0 +1,8 @@
+-- Vocabulary of the fulltext index, for the search
+-- suggestions. The "row" table has one row per term with
+-- the amount of documents it's in, the "instance" one has
+-- every occurrence with its document, so that terms can be
+-- counted for published articles only (drafts are indexed
+-- too).
+CREATE VIRTUAL TABLE IF NOT EXISTS articles_ft_vocab USING fts5vocab(articles_ft, 'row');
+CREATE VIRTUAL TABLE IF NOT EXISTS articles_ft_instance USING fts5vocab(articles_ft, 'instance');
@@ -0,0 +1,16 @@
+-- The stats database used to be a copy of db.empty.sqlite,
+-- only the article_stats table is actually used.
+CREATE TABLE IF NOT EXISTS "article_stats" (
+	"id"	INTEGER,
+	"article_id"	INTEGER NOT NULL,
+	"pseudo_ua"	TEXT,
+	"pseudo_ip"	TEXT,
+	"geoip"	TEXT,
+	"client_ua"	TEXT,
+	"client_ip"	TEXT,
+	"date"	INTEGER,
+	"country"	TEXT DEFAULT NULL,
+	"region"	TEXT DEFAULT NULL,
+	"city"	TEXT DEFAULT NULL,
+	PRIMARY KEY("id" AUTOINCREMENT)
+);
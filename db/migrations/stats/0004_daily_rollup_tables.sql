@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS "article_stats_daily" (
+	"day"	TEXT NOT NULL,
+	"article_id"	INTEGER NOT NULL,
+	"views"	INTEGER NOT NULL DEFAULT 0,
+	"bot_views"	INTEGER NOT NULL DEFAULT 0,
+	"unique_visitors"	INTEGER NOT NULL DEFAULT 0,
+	PRIMARY KEY("day","article_id")
+);
+CREATE TABLE IF NOT EXISTS "country_stats_daily" (
+	"day"	TEXT NOT NULL,
+	"country"	TEXT NOT NULL,
+	"views"	INTEGER NOT NULL DEFAULT 0,
+	"bot_views"	INTEGER NOT NULL DEFAULT 0,
+	"unique_visitors"	INTEGER NOT NULL DEFAULT 0,
+	PRIMARY KEY("day","country")
+);
@@ -0,0 +1,19 @@
+-- The country rollup gets the article, region and city so
+-- that the location reports (which can be for a single
+-- article) keep working for rolled up days. Rows rolled up
+-- before that have article 0 and no region or city.
+ALTER TABLE "country_stats_daily" RENAME TO "country_stats_daily_old";
+CREATE TABLE "country_stats_daily" (
+	"day"	TEXT NOT NULL,
+	"article_id"	INTEGER NOT NULL,
+	"country"	TEXT NOT NULL,
+	"region"	TEXT NOT NULL DEFAULT '',
+	"city"	TEXT NOT NULL DEFAULT '',
+	"views"	INTEGER NOT NULL DEFAULT 0,
+	"bot_views"	INTEGER NOT NULL DEFAULT 0,
+	"unique_visitors"	INTEGER NOT NULL DEFAULT 0,
+	PRIMARY KEY("day","article_id","country","region","city")
+);
+INSERT INTO "country_stats_daily" (day, article_id, country, views, bot_views, unique_visitors)
+SELECT day, 0, country, views, bot_views, unique_visitors FROM "country_stats_daily_old";
+DROP TABLE "country_stats_daily_old";
@@ -4,8 +4,11 @@ use tokio::fs::{read_dir, read_to_string, remove_file, DirEntry};
 use tokio::io;
 use tokio::task;
 //use std::io;
-use super::dtos::{ImportedArticleDto, JsonStatus, JsonStatusType};
-use crate::db::entities::{Article, ArticleUpdate};
+use super::dtos::{ImportedArticleDto, ImportedArticleTagDto, JsonStatus, JsonStatusType};
+use super::related::RelatedArticles;
+use super::scheduler::PublishScheduler;
+use crate::utils::time_utils;
+use crate::db::entities::{Article, ArticleUpdate, RevisionAction, Tag};
 use crate::db::{self, Pool};
 use derive_more::Display;
 use log::{error, warn};
@@ -59,6 +62,51 @@ impl From<ImportError> for JsonStatus {
   }
 }
 
+// Everything that can go wrong when saving a single
+// article, whether it comes from an import file or
+// from the admin API.
+#[derive(Debug, Display)]
+pub enum SaveError {
+  #[display(fmt = "Article ID doesn't exist")]
+  ArticleNotFound(i32),
+  #[display(fmt = "Tag with ID {} does not exist", _0)]
+  TagNotFound(i32),
+  #[display(fmt = "Tag {} does not exist", _0)]
+  TagNameNotFound(String),
+  #[display(fmt = "Tags need an id or a name")]
+  InvalidTag,
+  #[display(fmt = "User with ID {} does not exist", _0)]
+  UserNotFound(i32),
+  #[display(fmt = "Article URL {} already exists", _0)]
+  DuplicateUrl(String),
+  #[display(fmt = "Field userId is required when inserting articles")]
+  MissingUserId,
+  #[display(fmt = "Invalid date {}, dates have to be like 2021-03-07T21:59:00+01:00", _0)]
+  InvalidDate(String),
+  #[display(fmt = "Import service is currently busy")]
+  Busy,
+  #[display(fmt = "Database error: {}", _0)]
+  Database(Report<color_eyre::Handler>),
+}
+
+impl From<Report<color_eyre::Handler>> for SaveError {
+  fn from(r: Report<color_eyre::Handler>) -> Self {
+    SaveError::Database(r)
+  }
+}
+
+impl From<SaveError> for JsonStatus {
+  fn from(e: SaveError) -> Self {
+    match e {
+      SaveError::ArticleNotFound(id) => {
+        JsonStatus::new_with_id(JsonStatusType::Error, &e.to_string(), id)
+      }
+      SaveError::Database(r) => r.into(),
+      _ => JsonStatus::new(JsonStatusType::Error, &e.to_string()),
+    }
+  }
+}
+
 // My database errors use eyre, so uh... Yeah.
 impl From<Report<color_eyre::Handler>> for JsonStatus {
   fn from(r: Report<color_eyre::Handler>) -> Self {
@@ -74,10 +122,19 @@ impl From<Report<color_eyre::Handler>> for JsonStatus {
 pub struct ImportService {
   import_path: PathBuf,
   is_import_locked: AtomicBool,
+  // Imports can schedule articles, the scheduler has to
+  // know about it.
+  publish_scheduler: PublishScheduler,
+  // Same thing for the related articles cache.
+  related_articles: RelatedArticles,
 }
 
 impl ImportService {
-  pub fn open(path: &str) -> Result<Self, io::Error> {
+  pub fn open(
+    path: &str,
+    publish_scheduler: PublishScheduler,
+    related_articles: RelatedArticles,
+  ) -> Result<Self, io::Error> {
     // We have to check if the directory is writable.
     // I also suddenly decided coding like this is much
     // clearer:
@@ -88,6 +145,8 @@ impl ImportService {
       (false, true) => Ok(Self {
         import_path,
         is_import_locked: AtomicBool::new(false),
+        publish_scheduler,
+        related_articles,
       }),
       _ => Err(io::Error::new(
         io::ErrorKind::PermissionDenied,
@@ -99,15 +158,23 @@ impl ImportService {
   // Main method for the service.
   // I guess the pool could be owned by the struct, I
   // can probably clone it.
-  pub async fn import_articles(&self, pool: &Pool) -> Result<Vec<JsonStatus>, JsonStatus> {
+  // The author is saved with the article revisions, it's
+  // the name of the API token that was used.
+  pub async fn import_articles(
+    &self,
+    pool: &Pool,
+    author: &str,
+  ) -> Result<Vec<JsonStatus>, JsonStatus> {
     // Have to check if an import is already in progress.
     // Lock for import otherwise.
     if self.check_lock_set_if_unlocked() {
       warn!("An import was attempted while the import service is locked");
       return Err(locked_status_message());
     }
-    let result = self.import_articles_no_lock(pool).await;
+    let result = self.import_articles_no_lock(pool, author).await;
     self.unlock();
+    self.publish_scheduler.wake_up();
+    self.related_articles.invalidate();
     result
   }
 
@@ -139,10 +206,35 @@ impl ImportService {
       ),
     };
     self.unlock();
+    self.related_articles.invalidate();
     response
   }
 
-  async fn import_articles_no_lock(&self, pool: &Pool) -> Result<Vec<JsonStatus>, JsonStatus> {
+  // Used by the admin API to save articles one at a
+  // time. Shares the import lock so that it can't
+  // interfere with a running import.
+  pub async fn save_single_article(
+    &self,
+    pool: &Pool,
+    article: ImportedArticleDto,
+    author: &str,
+  ) -> Result<JsonStatus, SaveError> {
+    if self.check_lock_set_if_unlocked() {
+      warn!("Article save attempted while the import service is locked");
+      return Err(SaveError::Busy);
+    }
+    let result = save_article_blocking(pool, article, author).await;
+    self.unlock();
+    self.publish_scheduler.wake_up();
+    self.related_articles.invalidate();
+    result
+  }
+
+  async fn import_articles_no_lock(
+    &self,
+    pool: &Pool,
+    author: &str,
+  ) -> Result<Vec<JsonStatus>, JsonStatus> {
     // List all the files in the import directory.
     // The only possible IOError means the directory
     // could not be read for some reason, which is
@@ -159,138 +251,24 @@ impl ImportService {
     // except await isn't allowed in there. So it's
     // time for a good old for.
     let mut statuses: Vec<JsonStatus> = Vec::new();
-    // TODO: This whole loop should be in a tokio blocking
-    // task as DB functions are currently not using
-    // async await:
-    'outer: for file in files {
+    // Articles are saved one by one on the blocking thread
+    // pool, file operations stay async.
+    for file in files {
       match parse_article(file.path()).await {
         Ok(article) => {
-          // Check what we're doing and if we have
-          // everything required to do it.
-          // - action = 1 and id is present => Delete
-          // - no action but id present => Update
-          // - no action, no id => Insert
-          // When inserting and "short" is absent,
-          // default to make it true.
-          // Check if the article exist if we got
-          // an id first:
-          if let Some(id) = article.id {
-            if !db::article_exists(pool, id)? {
-              statuses.push(JsonStatus::new_with_id(
-                JsonStatusType::Error,
-                "Article ID doesn't exist",
-                id,
-              ));
+          match save_article_blocking(pool, article, author).await {
+            Ok(status) => statuses.push(status),
+            // Database errors abort the whole import.
+            Err(SaveError::Database(r)) => return Err(r.into()),
+            // Historically a missing userId still gets the
+            // file deleted, every other validation error
+            // leaves the file where it is.
+            Err(e @ SaveError::MissingUserId) => statuses.push(e.into()),
+            Err(e) => {
+              statuses.push(e.into());
               continue;
             }
           }
-          match (article.id, article.action) {
-            (Some(id), Some(1)) => {
-              // Deleting.
-              db::delete_article(pool, id)?;
-              statuses.push(JsonStatus::new_with_id(
-                JsonStatusType::Success,
-                "Article deleted",
-                id,
-              ));
-            }
-            _ => {
-              // Inserting or updating.
-              // If tags are present, do they all exist?
-              if let Some(tags) = &article.tags {
-                for tag in tags {
-                  if !db::tag_exists(pool, tag.id)? {
-                    statuses.push(JsonStatus::new(
-                      JsonStatusType::Error,
-                      &format!("Tag with ID {} does not exist", tag.id),
-                    ));
-                    continue 'outer;
-                  }
-                }
-              }
-              // If user ID is present, does it exist?
-              // We could cache that stuff.
-              if let Some(user_id) = article.user_id {
-                if !db::user_exists(pool, user_id)? {
-                  statuses.push(JsonStatus::new(
-                    JsonStatusType::Error,
-                    &format!("User with ID {} does not exist", user_id),
-                  ));
-                  continue 'outer;
-                }
-              }
-              // When article_url is present, check that it doesn't
-              // exist already (it could be that it's the current
-              // article when updating).
-              // Note that I'm currently allowing inserting an
-              // article (thus not a short) with no article URL, even
-              // though that shouldn't be allowed.
-              if let Some(article_url) = &article.article_url {
-                let valid_url = match (db::article_id_by_url(pool, &article_url)?, article.id) {
-                  (Some(id_for_url), Some(id)) => id_for_url == id,
-                  (Some(_), None) => false,
-                  _ => true,
-                };
-                if !valid_url {
-                  statuses.push(JsonStatus::new(
-                    JsonStatusType::Error,
-                    &format!("Article URL {} already exists", article_url),
-                  ));
-                  continue 'outer;
-                }
-              }
-              let mut delete_article = false;
-              // Check if updating or inserting:
-              match (article.id, article.user_id) {
-                (Some(_), _) => {
-                  // Updating, let's convert the ImportedArticle to the special
-                  // update entity:
-                  let update_entity: ArticleUpdate = article.clone().into();
-                  // The call returns the number of articles affected but I
-                  // just don't care.
-                  db::udpate_article(pool, &update_entity)?;
-                  statuses.push(JsonStatus::new_with_id(
-                    JsonStatusType::Success,
-                    "Entity has been updated",
-                    update_entity.id,
-                  ));
-                  delete_article = true;
-                }
-                (None, Some(_)) => {
-                  // Inserting. Converting to the entity will let us know if it's
-                  // a short or not.
-                  // We make it mut because the DB function will set the new ID
-                  // after insertion. It also returns it so this is kinda dumb.
-                  let mut article_to_insert: Article = article.clone().into();
-                  let new_id = db::insert_article(pool, &mut article_to_insert)?;
-                  statuses.push(JsonStatus::new_with_id(
-                    JsonStatusType::Success,
-                    &format!(
-                      "Inserted new {}",
-                      if article_to_insert.short == 0 {
-                        "article"
-                      } else {
-                        "short"
-                      }
-                    ),
-                    new_id,
-                  ));
-                  delete_article = true;
-                }
-                _ => {
-                  // Missing user_id for insertion:
-                  statuses.push(JsonStatus::new(
-                    JsonStatusType::Error,
-                    "Field userId is required when inserting articles",
-                  ));
-                }
-              }
-              if delete_article {
-                // Delete the file. I guess there's an async function
-                // for that.
-              }
-            }
-          }
           if let Err(delete_err) = remove_file(file.path()).await {
             // Couldn't delete the file for some reason, let's add
             // a weird message to the statuses:
@@ -400,6 +378,207 @@ fn locked_status_message() -> JsonStatus {
   JsonStatus::new(JsonStatusType::Error, "Import service is currently busy")
 }
 
+// Check what we're doing and if we have everything
+// required to do it:
+// - action = 1 and id is present => Delete
+// - no action but id present => Update
+// - no action, no id => Insert
+// When inserting and "short" is absent, default to
+// make it true.
+// This used to be the body of the import loop, the
+// admin API uses it too now.
+// Same as save_article, on the blocking thread pool.
+async fn save_article_blocking(
+  pool: &Pool,
+  article: ImportedArticleDto,
+  author: &str,
+) -> Result<JsonStatus, SaveError> {
+  let author = author.to_string();
+  db::run_blocking(pool, move |pool| Ok(save_article(pool, &article, &author)))
+    .await
+    .map_err(SaveError::Database)?
+}
+
+// Updates and deletions save the previous state of the
+// article as a revision first.
+pub fn save_article(
+  pool: &Pool,
+  article: &ImportedArticleDto,
+  author: &str,
+) -> Result<JsonStatus, SaveError> {
+  // Check if the article exist if we got an id first:
+  if let Some(id) = article.id {
+    if !db::article_exists(pool, id)? {
+      return Err(SaveError::ArticleNotFound(id));
+    }
+  }
+  // publishAt is a date, null cancels the schedule.
+  let publish_at: Option<Option<i64>> = match &article.publish_at {
+    Some(Some(date)) => Some(Some(
+      time_utils::rfc3339_timestamp(date).ok_or_else(|| SaveError::InvalidDate(date.clone()))?,
+    )),
+    Some(None) => Some(None),
+    None => None,
+  };
+  let scheduled = matches!(publish_at, Some(Some(_)));
+  if let (Some(id), Some(1)) = (article.id, article.action) {
+    // Deleting only moves the article to the trash, it's
+    // gone for good when the trash gets purged.
+    let now = time_utils::current_timestamp();
+    db::with_revision(pool, id, RevisionAction::Delete, author, |conn| {
+      db::trash_article_tx(conn, id, now)
+    })?;
+    return Ok(JsonStatus::new_with_id(
+      JsonStatusType::Success,
+      "Article moved to trash",
+      id,
+    ));
+  }
+  // Inserting or updating.
+  // If tags are present, do they all exist?
+  let tags = match &article.tags {
+    Some(tags) => Some(resolve_tags(
+      pool,
+      tags,
+      article.create_tags.unwrap_or(false),
+    )?),
+    None => None,
+  };
+  // If user ID is present, does it exist?
+  // We could cache that stuff.
+  if let Some(user_id) = article.user_id {
+    if !db::user_exists(pool, user_id)? {
+      return Err(SaveError::UserNotFound(user_id));
+    }
+  }
+  // When article_url is present, check that it doesn't
+  // exist already (it could be that it's the current
+  // article when updating).
+  // Note that I'm currently allowing inserting an
+  // article (thus not a short) with no article URL, even
+  // though that shouldn't be allowed.
+  if let Some(article_url) = &article.article_url {
+    let valid_url = match (db::article_id_by_url(pool, article_url)?, article.id) {
+      (Some(id_for_url), Some(id)) => id_for_url == id,
+      (Some(_), None) => false,
+      _ => true,
+    };
+    if !valid_url {
+      return Err(SaveError::DuplicateUrl(article_url.clone()));
+    }
+  }
+  // Check if updating or inserting:
+  match (article.id, article.user_id) {
+    (Some(_), _) => {
+      // Updating, let's convert the ImportedArticle to the special
+      // update entity:
+      let mut update_entity: ArticleUpdate = article.clone().into();
+      if let Some(mut tags) = tags {
+        create_missing_tags(pool, &mut tags)?;
+        update_entity.tags = Some(tags);
+      }
+      // Scheduling unpublishes the article anyway.
+      if scheduled {
+        update_entity.published = None;
+      }
+      // The revision and the changes are saved together.
+      // The call returns the number of articles affected but I
+      // just don't care.
+      db::with_revision(pool, update_entity.id, RevisionAction::Update, author, |conn| {
+        let mut count = db::update_article_tx(conn, &update_entity)?;
+        if let Some(publish_at) = publish_at {
+          count += db::schedule_article_tx(conn, update_entity.id, publish_at)?;
+        }
+        Ok(count)
+      })?;
+      Ok(JsonStatus::new_with_id(
+        JsonStatusType::Success,
+        "Entity has been updated",
+        update_entity.id,
+      ))
+    }
+    (None, Some(_)) => {
+      // Inserting. Converting to the entity will let us know if it's
+      // a short or not.
+      // We make it mut because the DB function will set the new ID
+      // after insertion. It also returns it so this is kinda dumb.
+      let mut article_to_insert: Article = article.clone().into();
+      if let Some(mut tags) = tags {
+        create_missing_tags(pool, &mut tags)?;
+        article_to_insert.tags = tags;
+      }
+      // Scheduled articles must not be visible, even for
+      // a split second.
+      if scheduled {
+        article_to_insert.published = 0;
+      }
+      let new_id = db::insert_article(pool, &mut article_to_insert)?;
+      if let Some(Some(publish_at)) = publish_at {
+        db::schedule_article(pool, new_id, Some(publish_at))?;
+      }
+      Ok(JsonStatus::new_with_id(
+        JsonStatusType::Success,
+        &format!(
+          "Inserted new {}",
+          if article_to_insert.short == 0 {
+            "article"
+          } else {
+            "short"
+          }
+        ),
+        new_id,
+      ))
+    }
+    // Missing user_id for insertion:
+    _ => Err(SaveError::MissingUserId),
+  }
+}
+
+// Gives every tag its ID, tags without one are looked up
+// by name. Unknown tags are an error, unless create is
+// true, in which case they're left with an ID of -1 for
+// create_missing_tags to insert once the whole article
+// has been validated. Duplicates are removed.
+fn resolve_tags(
+  pool: &Pool,
+  tags: &[ImportedArticleTagDto],
+  create: bool,
+) -> Result<Vec<Tag>, SaveError> {
+  let mut resolved: Vec<Tag> = Vec::with_capacity(tags.len());
+  for tag in tags {
+    let found = match (tag.id, &tag.name) {
+      (Some(id), _) => db::tag_by_id(pool, id)?.ok_or(SaveError::TagNotFound(id))?,
+      (None, Some(name)) => {
+        let name = name.trim();
+        match db::tag_by_name(pool, name)? {
+          Some(found) => found,
+          // Tags created by imports are never main tags,
+          // that has to be decided with the tags API.
+          None if create && !name.is_empty() => Tag {
+            id: -1,
+            name: name.to_string(),
+            main_tag: 0,
+          },
+          None => return Err(SaveError::TagNameNotFound(name.to_string())),
+        }
+      }
+      (None, None) => return Err(SaveError::InvalidTag),
+    };
+    // Tag names are unique.
+    if !resolved.iter().any(|t| t.name == found.name) {
+      resolved.push(found);
+    }
+  }
+  Ok(resolved)
+}
+
+fn create_missing_tags(pool: &Pool, tags: &mut [Tag]) -> Result<(), SaveError> {
+  for tag in tags.iter_mut().filter(|t| t.id < 0) {
+    db::insert_tag(pool, tag)?;
+  }
+  Ok(())
+}
+
 // Ignores the chain of errors when reading
 // file modified date, just returns "0" if
 // something went wrong.
@@ -448,7 +627,7 @@ mod tests {
       .unwrap();
     assert_eq!(32, parsed_article.id.unwrap());
     assert_eq!("some_url", parsed_article.article_url.unwrap());
-    assert_eq!(7, parsed_article.tags.unwrap()[0].id);
+    assert_eq!(Some(7), parsed_article.tags.unwrap()[0].id);
   }
 
   #[tokio::test]
@@ -459,4 +638,11 @@ mod tests {
     assert_eq!(42, parsed_article.id.unwrap());
     assert_eq!(1, parsed_article.action.unwrap());
   }
+
+  #[test]
+  fn save_error_to_status_keeps_article_id() {
+    let status: JsonStatus = SaveError::ArticleNotFound(12).into();
+    assert_eq!("error", status.status);
+    assert_eq!(Some(12), status.id);
+  }
 }
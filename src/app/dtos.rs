@@ -2,6 +2,7 @@ use serde::{Deserialize, Serialize};
 use derive_more::Display;
 use super::helpers;
 use crate::db::entities::*;
+use crate::db::PageStart;
 use crate::utils::{
   self, 
   time_utils, 
@@ -19,6 +20,25 @@ use crate::config::SiteInfo;
 // just re-export the entity?
 pub use crate::db::entities::Tag as TagDto;
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagWithCountDto {
+  #[serde(flatten)]
+  pub tag: TagDto,
+  pub article_count: i64,
+  pub short_count: i64
+}
+
+impl From<TagArticleCount> for TagWithCountDto {
+  fn from(tag_count: TagArticleCount) -> Self {
+    Self {
+      tag: tag_count.tag,
+      article_count: tag_count.articles,
+      short_count: tag_count.shorts
+    }
+  }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ArticleDto {
@@ -27,6 +47,7 @@ pub struct ArticleDto {
   pub summary: String,
   pub thumb_image: Option<String>,
   pub author: String,
+  pub author_id: i32,
   pub comments_count: i64,
   pub title: String,
   #[serde(rename = "articleURL")]
@@ -47,6 +68,7 @@ impl From<Article> for ArticleDto {
       summary: article.summary,
       thumb_image: article.thumb_image,
       author: article.author,
+      author_id: article.user_id,
       comments_count: article.comments_count,
       title: article.title,
       article_url: article.article_url,
@@ -56,6 +78,14 @@ impl From<Article> for ArticleDto {
   }
 }
 
+// Body of the listings when they're browsed with cursors.
+#[derive(Debug, Serialize)]
+pub struct CursorPageDto<T> {
+  pub items: Vec<T>,
+  pub next: Option<String>,
+  pub prev: Option<String>
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CommentDto {
@@ -64,7 +94,14 @@ pub struct CommentDto {
   pub article_id: Option<i32>,
   pub author: String,
   pub comment: String,
-  pub date: String
+  pub date: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub status: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub parent_id: Option<i32>,
+  // Only present when comments are requested as a tree.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub replies: Option<Vec<CommentDto>>
 }
 
 impl From<Comment> for CommentDto {
@@ -77,7 +114,10 @@ impl From<Comment> for CommentDto {
       date: time_utils::timestamp_to_date_string(
         comment.date,
         time_utils::DateFormat::Standard
-      )
+      ),
+      status: Some(comment.status.to_string()),
+      parent_id: comment.parent_id,
+      replies: None
     }
   }
 }
@@ -92,6 +132,78 @@ impl CommentDto {
     self.article_id = None;
     self
   }
+
+  // Public comment listings only ever have approved
+  // comments, no need to repeat it.
+  pub fn remove_status(mut self) -> Self {
+    self.status = None;
+    self
+  }
+}
+
+// Nests the replies inside of their parent comment.
+// Expects the comments to be ordered by id, which means
+// parents always come before their replies. Comments
+// whose parent isn't in the list end up at the top level.
+pub fn comments_to_tree(comments: Vec<CommentDto>) -> Vec<CommentDto> {
+  let ids: Vec<i32> = comments.iter().map(|c| c.id).collect();
+  let (roots, mut replies): (Vec<CommentDto>, Vec<CommentDto>) = comments
+    .into_iter()
+    .partition(|c| match c.parent_id {
+      Some(parent_id) => !ids.contains(&parent_id),
+      None => true
+    });
+  roots
+    .into_iter()
+    .map(|root| attach_replies(root, &mut replies))
+    .collect()
+}
+
+// Comment pages are fetched with one extra top-level
+// comment (see helpers::trim_page) which has to go away
+// with all of its replies. Expects the comments to be
+// ordered by id, replies always come after their parent.
+// Returns whether there are previous and next pages.
+pub fn trim_comment_page(
+  comments: &mut Vec<CommentDto>,
+  max: usize,
+  start: PageStart
+) -> (bool, bool) {
+  let mut root_ids: Vec<i32> = comments
+    .iter()
+    .filter(|c| c.parent_id.is_none())
+    .map(|c| c.id)
+    .collect();
+  let more_pages = helpers::trim_page(&mut root_ids, max, start);
+  let mut kept: Vec<i32> = Vec::with_capacity(comments.len());
+  comments.retain(|c| {
+    let keep = match c.parent_id {
+      Some(parent_id) => kept.contains(&parent_id),
+      None => root_ids.contains(&c.id)
+    };
+    if keep {
+      kept.push(c.id);
+    }
+    keep
+  });
+  more_pages
+}
+
+fn attach_replies(
+  mut parent: CommentDto,
+  replies: &mut Vec<CommentDto>
+) -> CommentDto {
+  let (children, rest): (Vec<CommentDto>, Vec<CommentDto>) = replies
+    .drain(..)
+    .partition(|c| c.parent_id == Some(parent.id));
+  *replies = rest;
+  parent.replies = Some(
+    children
+      .into_iter()
+      .map(|child| attach_replies(child, replies))
+      .collect()
+  );
+  parent
 }
 
 // I actually have to be strict with what
@@ -99,7 +211,7 @@ impl CommentDto {
 // to create custom deserializing functions
 // as shown here:
 // https://stackoverflow.com/questions/37870428/convert-two-types-into-a-single-type-with-serde
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct ImportedArticleDto {
   pub id: Option<i32>,
@@ -127,6 +239,16 @@ pub struct ImportedArticleDto {
   pub content: Option<String>,
   pub published: Option<bool>,
   pub tags: Option<Vec<ImportedArticleTagDto>>,
+  // Tags referenced by name that don't exist are
+  // created when this is true.
+  pub create_tags: Option<bool>,
+  // Date at which the article gets published, null
+  // cancels the schedule.
+  #[serde(
+    default,
+    deserialize_with = "serde_utils::deserialize_null_value"
+  )]
+  pub publish_at: Option<Option<String>>,
   pub short: Option<bool>,
   // Extra field to allow deletion when set 
   // to "1" or "delete":
@@ -199,8 +321,10 @@ impl From<ImportedArticleDto> for ArticleUpdate {
 // to work with the article import process:
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
+// Tags can be referenced by name instead of ID, the ID
+// wins when both are present.
 pub struct ImportedArticleTagDto {
-  pub id: i32,
+  pub id: Option<i32>,
   pub name: Option<String>
 }
 
@@ -211,7 +335,9 @@ pub struct ImportedArticleTagDto {
 impl From<ImportedArticleTagDto> for Tag {
   fn from(dto: ImportedArticleTagDto) -> Self {
     Self {
-      id: dto.id,
+      // Tags given by name only get their ID from the
+      // import service.
+      id: dto.id.unwrap_or(-1),
       name: dto.name.unwrap_or(String::new()),
       // lol that main_tag thing, I don't 
       // even remember what it was supposed
@@ -221,11 +347,240 @@ impl From<ImportedArticleTagDto> for Tag {
   }
 }
 
+// Body of the tag admin endpoints. Both fields are
+// required when creating a tag, except main_tag which
+// defaults to 1 like in the database.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagBodyDto {
+  pub name: Option<String>,
+  pub main_tag: Option<i32>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagMergeDto {
+  pub into: i32
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleDto {
+  pub publish_at: String
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviewLinkDto {
+  pub article_id: i32,
+  pub expires: String,
+  // Gets the article as JSON, same as /article:
+  pub url: String,
+  // Renders the article template, same as /render-article:
+  pub render_url: String
+}
+
+// The content is only there when getting a single
+// revision.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArticleRevisionDto {
+  pub id: i32,
+  pub article_id: i32,
+  pub action: String,
+  pub author: Option<String>,
+  pub date: String,
+  pub title: String,
+  #[serde(rename = "articleURL")]
+  pub article_url: Option<String>,
+  pub thumb_image: Option<String>,
+  pub article_date: String,
+  pub user_id: i32,
+  pub summary: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub content: Option<String>,
+  pub published: bool,
+  pub short: bool,
+  pub tag_ids: Vec<i32>
+}
+
+impl From<ArticleRevision> for ArticleRevisionDto {
+  fn from(revision: ArticleRevision) -> Self {
+    Self {
+      id: revision.id,
+      article_id: revision.article_id,
+      action: revision.action,
+      author: revision.author,
+      date: time_utils::timestamp_to_date_string(
+        revision.date,
+        time_utils::DateFormat::Standard
+      ),
+      title: revision.title,
+      article_url: revision.article_url,
+      thumb_image: revision.thumb_image,
+      article_date: time_utils::timestamp_to_date_string(
+        revision.article_date,
+        time_utils::DateFormat::Standard
+      ),
+      user_id: revision.user_id,
+      summary: revision.summary,
+      content: revision.content,
+      published: revision.published == 1,
+      short: revision.short == 1,
+      tag_ids: revision.tag_ids
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldDiffDto {
+  pub field: &'static str,
+  pub diff: String
+}
+
+// Only the fields that changed are listed. A "to" of
+// None means the current state of the article.
+#[derive(Debug, Serialize)]
+pub struct RevisionDiffDto {
+  pub from: i32,
+  pub to: Option<i32>,
+  pub changes: Vec<FieldDiffDto>
+}
+
+impl RevisionDiffDto {
+  pub fn new(from: &ArticleRevision, to: &ArticleRevision, to_id: Option<i32>) -> Self {
+    let date = |timestamp| time_utils::timestamp_to_date_string(
+      timestamp,
+      time_utils::DateFormat::Standard
+    );
+    let tags = |ids: &[i32]| ids.iter()
+      .map(|id| id.to_string())
+      .collect::<Vec<String>>()
+      .join(",");
+    let fields: Vec<(&'static str, String, String)> = vec![
+      ("title", from.title.clone(), to.title.clone()),
+      (
+        "articleURL",
+        from.article_url.clone().unwrap_or_default(),
+        to.article_url.clone().unwrap_or_default()
+      ),
+      (
+        "thumbImage",
+        from.thumb_image.clone().unwrap_or_default(),
+        to.thumb_image.clone().unwrap_or_default()
+      ),
+      ("date", date(from.article_date), date(to.article_date)),
+      ("userId", from.user_id.to_string(), to.user_id.to_string()),
+      ("summary", from.summary.clone(), to.summary.clone()),
+      (
+        "content",
+        from.content.clone().unwrap_or_default(),
+        to.content.clone().unwrap_or_default()
+      ),
+      ("published", from.published.to_string(), to.published.to_string()),
+      ("tagIds", tags(&from.tag_ids), tags(&to.tag_ids))
+    ];
+    Self {
+      from: from.id,
+      to: to_id,
+      changes: fields.into_iter()
+        .filter_map(|(field, old, new)| {
+          text_utils::line_diff(&old, &new).map(|diff| FieldDiffDto { field, diff })
+        })
+        .collect()
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrashedArticleDto {
+  pub id: i32,
+  pub title: String,
+  #[serde(rename = "articleURL")]
+  pub article_url: Option<String>,
+  pub short: bool,
+  pub deleted_at: String
+}
+
+impl From<TrashedArticle> for TrashedArticleDto {
+  fn from(article: TrashedArticle) -> Self {
+    Self {
+      id: article.id,
+      title: article.title,
+      article_url: article.article_url,
+      short: article.short == 1,
+      deleted_at: time_utils::timestamp_to_date_string(
+        article.deleted_at,
+        time_utils::DateFormat::Standard
+      )
+    }
+  }
+}
+
+// Response of the search suggestions endpoint. Completions
+// are for the last word of the query, corrections are only
+// given when the search found nothing.
+#[derive(Debug, Serialize)]
+pub struct SuggestionsDto {
+  pub completions: Vec<String>,
+  pub corrections: Vec<CorrectionDto>
+}
+
+#[derive(Debug, Serialize)]
+pub struct CorrectionDto {
+  pub term: String,
+  pub suggestions: Vec<String>
+}
+
+// Body of the author admin endpoints. The profile fields
+// can be set to null (or to an empty string) to remove
+// them, same as thumbImage for articles.
+#[derive(Debug, Deserialize)]
+pub struct AuthorBodyDto {
+  pub name: Option<String>,
+  #[serde(default, deserialize_with = "serde_utils::deserialize_null_value")]
+  pub bio: Option<Option<String>>,
+  #[serde(default, deserialize_with = "serde_utils::deserialize_null_value")]
+  pub avatar: Option<Option<String>>,
+  #[serde(default, deserialize_with = "serde_utils::deserialize_null_value")]
+  pub link: Option<Option<String>>
+}
+
+impl AuthorBodyDto {
+  // Empty strings become null.
+  pub fn normalized(self) -> Self {
+    let empty_to_none = |field: Option<Option<String>>| {
+      field.map(serde_utils::empty_string_to_none)
+    };
+    Self {
+      name: self.name,
+      bio: empty_to_none(self.bio),
+      avatar: empty_to_none(self.avatar),
+      link: empty_to_none(self.link)
+    }
+  }
+}
+
 // Format I'm using for the search requests. The
 // include thingy is kinda useless but it's historical.
+// Everything else is optional.
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct SearchBody {
-  pub include: Vec<String>
+  pub include: Vec<String>,
+  pub start: Option<usize>,
+  pub max: Option<usize>,
+  // Either "articles", "shorts" or "all" (the default).
+  #[serde(rename = "type")]
+  pub article_type: Option<String>,
+  pub tags: Option<Vec<String>>,
+  pub tag_ids: Option<Vec<i32>>,
+  // Either "all" or "any", same as the article listings.
+  #[serde(rename = "match")]
+  pub match_mode: Option<String>,
+  // Days as YYYY-MM-DD, both included.
+  pub from: Option<String>,
+  pub to: Option<String>
 }
 
 // The object that respresents search results:
@@ -477,6 +832,119 @@ impl<'a> RenderedArticle<'a> {
   }
 }
 
+// Stats reporting DTOs. The from and to dates of the
+// range are repeated in every response.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsRangeDto {
+  pub from: String,
+  pub to: String
+}
+
+impl StatsRangeDto {
+  pub fn new(from: i64, to: i64) -> Self {
+    Self {
+      from: time_utils::timestamp_to_date_string(
+        from,
+        time_utils::DateFormat::Standard
+      ),
+      to: time_utils::timestamp_to_date_string(
+        to,
+        time_utils::DateFormat::Standard
+      )
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsDto<T: Serialize> {
+  pub range: StatsRangeDto,
+  pub items: T
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeriodViewsDto {
+  pub period: String,
+  pub views: i64,
+  pub unique_visitors: i64
+}
+
+impl From<PeriodViews> for PeriodViewsDto {
+  fn from(views: PeriodViews) -> Self {
+    Self {
+      period: views.period,
+      views: views.views,
+      unique_visitors: views.unique_visitors
+    }
+  }
+}
+
+// Titles come from the main database, the article could
+// also have been deleted since.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArticleViewsDto {
+  pub article_id: i32,
+  pub title: Option<String>,
+  pub views: i64,
+  pub unique_visitors: i64
+}
+
+impl From<ArticleViews> for ArticleViewsDto {
+  fn from(views: ArticleViews) -> Self {
+    Self {
+      article_id: views.article_id,
+      title: None,
+      views: views.views,
+      unique_visitors: views.unique_visitors
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocationViewsDto {
+  pub country: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub region: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub city: Option<String>,
+  pub views: i64,
+  pub unique_visitors: i64
+}
+
+impl From<LocationViews> for LocationViewsDto {
+  fn from(views: LocationViews) -> Self {
+    Self {
+      country: views.country,
+      region: views.region,
+      city: views.city,
+      views: views.views,
+      unique_visitors: views.unique_visitors
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VisitorsSummaryDto {
+  pub views: i64,
+  pub unique_visitors: i64,
+  pub bot_views: i64
+}
+
+impl From<VisitorsSummary> for VisitorsSummaryDto {
+  fn from(summary: VisitorsSummary) -> Self {
+    Self {
+      views: summary.views,
+      unique_visitors: summary.unique_visitors,
+      bot_views: summary.bot_views
+    }
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -510,6 +978,75 @@ mod tests {
     assert_eq!(27, converted[1].id);
   }
 
+  #[test]
+  fn comment_to_dto_has_status() {
+    let sut = Comment {
+      id: 4,
+      article_id: 12,
+      author: "Someone".to_string(),
+      comment: "Some comment".to_string(),
+      date: 1615150740,
+      status: CommentStatus::Pending,
+      parent_id: None,
+      client_ip: None
+    };
+    let dto: CommentDto = sut.into();
+    assert_eq!(Some("pending".to_string()), dto.status);
+    assert_eq!(None, dto.remove_status().status);
+  }
+
+  fn comment_dto(id: i32, parent_id: Option<i32>) -> CommentDto {
+    CommentDto::from(Comment {
+      id,
+      article_id: 12,
+      author: "Someone".to_string(),
+      comment: "Some comment".to_string(),
+      date: 1615150740,
+      status: CommentStatus::Approved,
+      parent_id,
+      client_ip: None
+    })
+  }
+
+  #[test]
+  fn comments_to_tree_nests_replies() {
+    let sut = vec![
+      comment_dto(1, None),
+      comment_dto(2, None),
+      comment_dto(3, Some(1)),
+      comment_dto(4, Some(3)),
+      comment_dto(5, Some(2)),
+      comment_dto(6, Some(1))
+    ];
+    let tree = comments_to_tree(sut);
+    assert_eq!(2, tree.len());
+    let replies = tree[0].replies.as_ref().unwrap();
+    assert_eq!(vec![3, 6], replies.iter().map(|c| c.id).collect::<Vec<i32>>());
+    assert_eq!(4, replies[0].replies.as_ref().unwrap()[0].id);
+    assert_eq!(5, tree[1].replies.as_ref().unwrap()[0].id);
+  }
+
+  #[test]
+  fn trim_comment_page_removes_extra_thread() {
+    let page = || vec![
+      comment_dto(1, None),
+      comment_dto(2, None),
+      comment_dto(3, Some(1)),
+      comment_dto(4, Some(2)),
+      comment_dto(5, Some(4))
+    ];
+    let ids = |comments: &Vec<CommentDto>| comments
+      .iter()
+      .map(|c| c.id)
+      .collect::<Vec<i32>>();
+    let mut sut = page();
+    assert_eq!((false, true), trim_comment_page(&mut sut, 1, PageStart::Offset(0)));
+    assert_eq!(vec![1, 3], ids(&sut));
+    let mut sut = page();
+    assert_eq!((true, true), trim_comment_page(&mut sut, 1, PageStart::Before(6)));
+    assert_eq!(vec![2, 4, 5], ids(&sut));
+  }
+
   #[test]
   fn empty_string_is_none_for_article_thumb_image() {
     let sut = ImportedArticleDto {
@@ -517,6 +1054,8 @@ mod tests {
       article_url: None,
       article_url_bis: None,
       content: None,
+      create_tags: None,
+      publish_at: None,
       id: None,
       published: None,
       short: None,
@@ -1,29 +1,75 @@
-use actix_web::{dev::RequestHead, guard::Guard};
+use crate::db::entities::ApiToken;
+use crate::db::{self, Pool};
+use crate::utils::token_utils::hash_token;
+use actix_web::dev::{RequestHead, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{guard::Guard, http::header, HttpMessage};
+use derive_more::Display;
+use futures::future::{ok, LocalBoxFuture, Ready};
 use log::{warn, error};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+// Scopes an API token can be given. They're saved as
+// a comma separated list of their display values in
+// the api_tokens table.
+#[derive(Debug, Display, Clone, Copy, PartialEq)]
+pub enum Scope {
+  #[display(fmt = "rss")]
+  Rss,
+  #[display(fmt = "sitemap")]
+  Sitemap,
+  #[display(fmt = "publish")]
+  Publish,
+  #[display(fmt = "import")]
+  Import,
+  #[display(fmt = "reindex")]
+  Reindex,
+  #[display(fmt = "moderate")]
+  Moderate,
+  #[display(fmt = "stats")]
+  Stats,
+  #[display(fmt = "tags")]
+  Tags,
+  #[display(fmt = "authors")]
+  Authors
+}
 
 // A guard will just cause the router to not match the
-// route and thus probably show a 404. What I'd need 
+// route and thus probably show a 404. What I'd need
 // would be a middleware and not a guard. But it does
 // the trick anyway.
+// Expects the API token to have been resolved by the
+// ApiTokens middleware, guards can't do anything async
+// and they run for every route they're on until one
+// matches. The IP address allowlist is an optional
+// second factor.
 #[derive(Clone)]
-pub struct IPRestrictedGuard<T: 'static + AsRef<str>> {
-  allowed_ip_addresses: &'static [T]
+pub struct TokenGuard {
+  scope: Scope,
+  allowed_ip_addresses: Option<Vec<String>>
 }
 
-impl<T: AsRef<str>> IPRestrictedGuard<T> {
-  pub fn new(allowed_ips: &'static [T]) -> Self {
+impl TokenGuard {
+  pub fn new(
+    scope: Scope,
+    allowed_ip_addresses: Option<Vec<String>>
+  ) -> Self {
     Self {
-      allowed_ip_addresses: allowed_ips
+      scope,
+      allowed_ip_addresses
     }
   }
-}
 
-impl<T: AsRef<str>> Guard for IPRestrictedGuard<T> {
-  fn check(&self, req: &RequestHead) -> bool {
+  fn check_ip(&self, req: &RequestHead) -> bool {
+    let allowed_ips = match &self.allowed_ip_addresses {
+      Some(ips) => ips,
+      None => return true
+    };
     match req.peer_addr {
       Some(sock_addr) => {
         let addr = sock_addr.ip().to_string();
-        if self.allowed_ip_addresses.iter().any(|i| i.as_ref() == addr) {
+        if allowed_ips.contains(&addr) {
           true
         } else {
           warn!("IP address {} attempted to reach protected \
@@ -32,10 +78,203 @@ impl<T: AsRef<str>> Guard for IPRestrictedGuard<T> {
         }
       },
       None => {
-        error!("IPRestrictedGuard encountered a client with\
+        error!("TokenGuard encountered a client with\
          no IP address");
         false
       }
     }
   }
+
+  fn check_token(&self, req: &RequestHead) -> bool {
+    match req.extensions().get::<ApiToken>() {
+      Some(api_token) => {
+        if has_scope(&api_token.scopes, self.scope) {
+          true
+        } else {
+          warn!("API token '{}' is missing scope {} for endpoint at {}",
+            api_token.name, self.scope, req.uri);
+          false
+        }
+      },
+      None => {
+        warn!("Missing or invalid API token for protected endpoint at {}", req.uri);
+        false
+      }
+    }
+  }
+}
+
+impl Guard for TokenGuard {
+  fn check(&self, req: &RequestHead) -> bool {
+    self.check_ip(req) && self.check_token(req)
+  }
+}
+
+// Looks up the API token of the request, if it has one,
+// on the blocking thread pool, and puts it in the request
+// extensions for the guards and handlers. Requests
+// without a token don't touch the database.
+pub struct ApiTokens {
+  pool: Pool
+}
+
+impl ApiTokens {
+  pub fn new(pool: Pool) -> Self {
+    Self { pool }
+  }
+}
+
+impl<S, B> Transform<S> for ApiTokens
+where
+  S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>
+    + 'static,
+  S::Future: 'static,
+  B: 'static,
+{
+  type Request = ServiceRequest;
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type InitError = ();
+  type Transform = ApiTokensMiddleware<S>;
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ok(ApiTokensMiddleware {
+      // The service has to be called from the future.
+      service: Rc::new(RefCell::new(service)),
+      pool: self.pool.clone()
+    })
+  }
+}
+
+pub struct ApiTokensMiddleware<S> {
+  service: Rc<RefCell<S>>,
+  pool: Pool
+}
+
+impl<S, B> Service for ApiTokensMiddleware<S>
+where
+  S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>
+    + 'static,
+  S::Future: 'static,
+  B: 'static,
+{
+  type Request = ServiceRequest;
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.service.borrow_mut().poll_ready(cx)
+  }
+
+  fn call(&mut self, req: ServiceRequest) -> Self::Future {
+    let service = self.service.clone();
+    let pool = self.pool.clone();
+    Box::pin(async move {
+      if let Some(token) = token_from_headers(req.head()) {
+        let token_hash = hash_token(&token);
+        match db::run_blocking(&pool, move |pool| db::api_token_by_hash(pool, &token_hash)).await {
+          Ok(Some(api_token)) => {
+            req.extensions_mut().insert(api_token);
+          },
+          Ok(None) => warn!("Invalid API token used at {}", req.path()),
+          // The guards will act as if there was no token.
+          Err(e) => error!("Database error when checking API token - {}", e)
+        }
+      }
+      let fut = service.borrow_mut().call(req);
+      fut.await
+    })
+  }
+}
+
+fn token_from_headers(req: &RequestHead) -> Option<String> {
+  if let Some(auth) = req.headers.get(header::AUTHORIZATION) {
+    let auth = auth.to_str().unwrap_or("");
+    if auth.len() > 7 && auth[..7].eq_ignore_ascii_case("bearer ") {
+      return Some(auth[7..].trim().to_string());
+    }
+  }
+  req.headers
+    .get("x-api-key")
+    .and_then(|h| h.to_str().ok())
+    .map(|h| h.trim().to_string())
+    .filter(|h| !h.is_empty())
+}
+
+pub fn has_scope(scopes: &str, scope: Scope) -> bool {
+  let scope = scope.to_string();
+  scopes.split(',').any(|s| s.trim() == scope)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn has_scope_in_list() {
+    assert!(has_scope("rss, sitemap,publish", Scope::Sitemap));
+    assert!(has_scope("rss, sitemap,publish", Scope::Publish));
+    assert!(!has_scope("rss,sitemap", Scope::Import));
+    assert!(!has_scope("", Scope::Rss));
+  }
+
+  #[test]
+  fn guards_read_the_token_from_the_middleware() {
+    use actix_web::{test, web, App, HttpResponse};
+    use r2d2_sqlite::SqliteConnectionManager;
+
+    let pool = Pool::builder()
+      .max_size(1)
+      .build(SqliteConnectionManager::memory())
+      .unwrap();
+    db::migrations::migrate(&pool, db::migrations::Database::Main, false).unwrap();
+    let mut api_token = ApiToken {
+      id: -1,
+      name: String::from("cron"),
+      token_hash: hash_token("secret"),
+      scopes: String::from("rss"),
+      date: 0
+    };
+    db::insert_api_token(&pool, &mut api_token).unwrap();
+
+    actix_web::rt::System::new("guards").block_on(async move {
+      let mut app = test::init_service(
+        App::new()
+          .wrap(ApiTokens::new(pool))
+          .route("/rss", web::get()
+            .guard(TokenGuard::new(Scope::Rss, None))
+            .to(HttpResponse::Ok))
+          .route("/sitemap", web::get()
+            .guard(TokenGuard::new(Scope::Sitemap, None))
+            .to(HttpResponse::Ok))
+      ).await;
+      let request = |uri: &'static str, token: Option<&'static str>| {
+        let mut req = test::TestRequest::with_uri(uri);
+        if let Some(token) = token {
+          req = req.header("x-api-key", token);
+        }
+        req.to_request()
+      };
+      let resp = test::call_service(&mut app, request("/rss", Some("secret"))).await;
+      assert_eq!(200, resp.status().as_u16());
+      let resp = test::call_service(&mut app, request("/rss", Some("wrong"))).await;
+      assert_eq!(404, resp.status().as_u16());
+      let resp = test::call_service(&mut app, request("/rss", None)).await;
+      assert_eq!(404, resp.status().as_u16());
+      let resp = test::call_service(&mut app, request("/sitemap", Some("secret"))).await;
+      assert_eq!(404, resp.status().as_u16());
+    });
+  }
+
+  #[test]
+  fn scopes_match_token_scope_names() {
+    let scopes = [
+      Scope::Rss, Scope::Sitemap, Scope::Publish, Scope::Import, Scope::Reindex,
+      Scope::Moderate, Scope::Stats, Scope::Tags, Scope::Authors
+    ];
+    let names: Vec<String> = scopes.iter().map(Scope::to_string).collect();
+    assert_eq!(crate::db::entities::API_TOKEN_SCOPES, &names[..]);
+  }
 }
@@ -1,12 +1,13 @@
+use super::article_import::SaveError;
 use super::dtos::*;
-use super::error::{map_db_error, Error};
+use super::error::Error;
 use super::helpers;
+use super::related;
 use super::AppState;
-use crate::app::helpers::replace_start_in_pagination_path;
 use crate::db;
 use crate::db::entities::*;
 use crate::stats::{BaseArticleStat, StatsService};
-use crate::utils::{text_utils, time_utils};
+use crate::utils::{text_utils, time_utils, token_utils};
 use actix_web::{web, HttpRequest, HttpResponse, Result};
 use handlebars::Handlebars;
 use log::{debug, error};
@@ -28,20 +29,72 @@ const MAX_AUTHOR_LENGTH: usize = 70;
 const MAX_RSS_LENGTH: usize = 2500;
 // Max amount of search tersm to process:
 const MAX_SEARCH_TERMS: usize = 10;
+// Search results per page, it used to always be 15:
+const SEARCH_DEFAULT_MAX: usize = 15;
+const MAX_SEARCH_RESULTS: usize = 50;
+// Nobody reads that far, and big offsets make SQLite go
+// through all the results before it:
+const MAX_SEARCH_START: usize = 10000;
+// Search suggestions, words shorter than the min lengths
+// don't get completed or corrected:
+const SUGGEST_DEFAULT_MAX: usize = 10;
+const MAX_SUGGESTIONS: usize = 20;
+const MIN_COMPLETION_LENGTH: usize = 2;
+const MIN_CORRECTION_LENGTH: usize = 3;
+const MAX_CORRECTIONS: usize = 3;
+// Amount of related articles under an article:
+const RELATED_ARTICLES: usize = 4;
+// Stats reporting defaults to the last 30 days:
+const STATS_DEFAULT_RANGE: i64 = 30 * 24 * 3600;
+const MAX_TOP_ARTICLES: usize = 10;
+
+// Listings are browsed from an offset by default, or
+// from a cursor when one is given.
+fn page_start(cursor: &Option<String>, start: usize) -> Result<db::PageStart, Error> {
+    match cursor.as_deref() {
+        None => Ok(db::PageStart::Offset(start)),
+        Some("") => Ok(db::PageStart::Offset(0)),
+        Some(cursor) => helpers::decode_cursor(cursor)
+            .ok_or_else(|| Error::BadRequest(String::from("Invalid cursor"))),
+    }
+}
 
 /* --- Request body or query or form objects --- */
 // These have to be public.
 #[derive(Serialize, Deserialize)]
 pub struct ArticlesQuery {
     pub max: Option<usize>,
+    // Comma separated tag names and IDs:
     pub tags: Option<String>,
+    pub tag_ids: Option<String>,
+    // Either "all" or "any", defaults to "all".
+    #[serde(rename = "match")]
+    pub match_mode: Option<String>,
     pub order: Option<String>,
+    // Only for listings that don't have the start in their
+    // path.
+    pub start: Option<usize>,
+    // Opaque cursor from a previous page, the start in the
+    // path is ignored when present. An empty cursor means
+    // the first page.
+    pub cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PreviewQuery {
+    pub expires: i64,
+    pub signature: String,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct CommentsQuery {
     pub max: Option<usize>,
     pub start: Option<usize>,
+    // Either "tree" or "flat", defaults to "flat".
+    pub format: Option<String>,
+    // Same as for ArticlesQuery, start is ignored when
+    // present.
+    pub cursor: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -52,6 +105,22 @@ pub struct CommentForm {
     pub author: String,
     pub article_id: Option<i32>,
     pub articleurl: Option<String>,
+    // Set when replying to another comment.
+    pub parent_id: Option<i32>,
+}
+// Used by all the stats endpoints, though they don't
+// all use every field. Dates are YYYY-MM-DD, "to" is
+// inclusive.
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    // Either "day", "week" or "month".
+    pub period: Option<String>,
+    // Either "country", "region" or "city".
+    pub by: Option<String>,
+    pub article_id: Option<i32>,
+    pub max: Option<usize>,
 }
 /* --- End request body or query or form objects --- */
 
@@ -65,6 +134,18 @@ fn insert_stats(article_stat: BaseArticleStat, stats_service: &StatsService) {
     }
 }
 
+// The admin API sends the same JsonStatus objects as the
+// import service, we just have to pick a status code.
+fn save_error_response(e: SaveError) -> HttpResponse {
+    let mut response = match e {
+        SaveError::ArticleNotFound(_) => HttpResponse::NotFound(),
+        SaveError::Busy => HttpResponse::Forbidden(),
+        SaveError::Database(_) => HttpResponse::InternalServerError(),
+        _ => HttpResponse::BadRequest(),
+    };
+    response.json(JsonStatus::from(e))
+}
+
 pub async fn index() -> HttpResponse {
     HttpResponse::Ok()
         .content_type("text/plain")
@@ -73,12 +154,14 @@ pub async fn index() -> HttpResponse {
 
 // At this time robots.txt is just hardcoded here.
 // I could just disallow everything but I just do
-// for render-article.
+// for render-article and the previews.
 pub async fn robots() -> HttpResponse {
     HttpResponse::Ok().content_type("text/plain").body(
         "User-agent: *\n\
     Disallow: \n\
-    Disallow: /render-article/\n",
+    Disallow: /render-article/\n\
+    Disallow: /preview/\n\
+    Disallow: /render-preview/\n",
     )
 }
 
@@ -96,12 +179,16 @@ pub async fn not_found() -> Result<HttpResponse, Error> {
 // see my "error" module for the Error to response
 // conversions.
 pub async fn tags(app_state: web::Data<AppState>) -> Result<HttpResponse, Error> {
-    match db::all_tags(&app_state.pool) {
-        Ok(tags) => Ok(HttpResponse::Ok().json(Vec::<TagDto>::from(tags))),
+    match app_state.db(db::all_tags_with_counts).await {
+        Ok(tags) => Ok(HttpResponse::Ok().json(
+            tags.into_iter()
+                .map(TagWithCountDto::from)
+                .collect::<Vec<TagWithCountDto>>(),
+        )),
         // I could use something to log the error message
         // somewhere because it won't be shown in browsers
         // for security reasons (see "error" module).
-        Err(e) => Err(Error::DatabaseError(e.to_string())),
+        Err(e) => Err(e),
     }
 }
 
@@ -113,8 +200,9 @@ pub async fn article(
 ) -> Result<HttpResponse, Error> {
     let article_url = path.into_inner().0;
     // Check if we got an article ID:
-    let article: Option<Article> =
-        db::article_by_id_or_url(&app_state.pool, &article_url).map_err(map_db_error)?;
+    let article: Option<Article> = app_state
+        .db(move |pool| db::published_article_by_id_or_url(pool, &article_url))
+        .await?;
     // Send a 404 if there are no articles:
     match article {
         Some(a) => {
@@ -133,6 +221,43 @@ pub async fn article(
     }
 }
 
+// A few published articles and shorts that look like the
+// one in the path, without their content. See
+// related::related_article_ids for how they're picked.
+pub async fn related_articles(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let article_url = path.into_inner().0;
+    let cache = app_state.related_articles.clone();
+    let articles = app_state
+        .db(move |pool| {
+            let article = match db::published_article_by_id_or_url(pool, &article_url)? {
+                Some(article) => article,
+                None => return Ok(None),
+            };
+            let ids = match cache.get(article.id) {
+                Ok(ids) => ids,
+                Err(generation) => {
+                    let ids = related::related_article_ids(pool, &article, RELATED_ARTICLES)?;
+                    cache.insert(article.id, generation, ids.clone());
+                    ids
+                }
+            };
+            db::published_articles_by_ids(pool, &ids).map(Some)
+        })
+        .await?;
+    match articles {
+        Some(articles) => Ok(HttpResponse::Ok().json(
+            articles
+                .into_iter()
+                .map(ArticleDto::from)
+                .collect::<Vec<ArticleDto>>(),
+        )),
+        None => Err(Error::NotFound("Article does not exist".to_string())),
+    }
+}
+
 // Refreshes the date to the current date and time and publishes
 // the article specified by ID in the path.
 // The DB method takes an i32 so I left that as param.
@@ -140,10 +265,19 @@ pub async fn article(
 pub async fn refresh_date_and_publish(
     app_state: web::Data<AppState>,
     path: web::Path<(i32,)>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let article_id = path.into_inner().0;
-    let count = db::update_date_and_publish(&app_state.pool, article_id).map_err(map_db_error)?;
+    let author = token_name(&req);
+    let count = app_state
+        .db(move |pool| {
+            db::with_revision(pool, article_id, RevisionAction::Update, &author, |conn| {
+                db::update_date_and_publish_tx(conn, article_id)
+            })
+        })
+        .await?;
     if count > 0 {
+        app_state.related_articles.invalidate();
         let success = JsonStatus::new_with_id(
             JsonStatusType::Success,
             "Date refreshed and article published",
@@ -155,34 +289,53 @@ pub async fn refresh_date_and_publish(
     }
 }
 
-fn articles_or_shorts_starting_from(
-    api_root: &Option<String>,
-    pool: &db::Pool,
-    path: web::Path<(usize,)>,
+// Tags can be given by name and/or by id, articles have
+// to match all of them unless "match" is set to "any".
+fn tag_filter(query: &ArticlesQuery) -> Result<Option<db::TagFilter>, Error> {
+    let split = |value: &Option<String>| -> Vec<String> {
+        value
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect()
+    };
+    let ids = split(&query.tag_ids)
+        .iter()
+        .map(|id| id.parse::<i32>())
+        .collect::<std::result::Result<Vec<i32>, _>>()
+        .map_err(|_| Error::BadRequest(String::from("Tag IDs have to be numbers")))?;
+    let filter = db::TagFilter {
+        names: split(&query.tags),
+        ids,
+        match_mode: tag_match(query.match_mode.as_deref())?,
+    };
+    Ok(if filter.is_empty() { None } else { Some(filter) })
+}
+
+fn tag_match(match_mode: Option<&str>) -> Result<db::TagMatch, Error> {
+    match match_mode {
+        None | Some("all") => Ok(db::TagMatch::All),
+        Some("any") => Ok(db::TagMatch::Any),
+        Some(_) => Err(Error::BadRequest(String::from(
+            "Match has to be either all or any",
+        ))),
+    }
+}
+
+// Shared by all the article listings.
+async fn articles_or_shorts_starting_from(
+    app_state: web::Data<AppState>,
+    start: usize,
+    offset_param: helpers::OffsetParam,
     query: web::Query<ArticlesQuery>,
     article_selector: db::ArticleSelector,
+    filter: db::ArticleFilter,
     req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
-    let start = path.into_inner().0;
     let max = query.max.unwrap_or(MAX_ARTICLES);
-    let tags: Option<Vec<&str>> = query
-        .tags
-        .as_ref()
-        .map(|tags_str| {
-            tags_str
-                .split(",")
-                .map(|t| t.trim())
-                .filter(|t| !t.is_empty())
-                .collect()
-        })
-        .map(|tags_vec: Vec<&str>| {
-            if tags_vec.is_empty() {
-                None
-            } else {
-                Some(tags_vec)
-            }
-        })
-        .unwrap_or(None);
+    let start = page_start(&query.cursor, start)?;
     let order = match &query.order {
         Some(order) => {
             if order.to_lowercase() == "asc" {
@@ -194,8 +347,27 @@ fn articles_or_shorts_starting_from(
         None => db::Order::Desc,
     };
 
-    let count: usize = db::article_count(pool, &article_selector, &tags)
-        .map_err(map_db_error)?
+    let (count, articles) = app_state
+        .db(move |pool| {
+            let count = db::article_count(pool, &article_selector, &filter)?;
+            // No need to query the articles if we're past the end.
+            // Fetching one more article tells us if there's a
+            // next (or previous) page.
+            let articles = match start {
+                db::PageStart::Offset(offset) if offset as i64 >= count => Vec::new(),
+                _ => db::articles_from_to(
+                    pool,
+                    &article_selector,
+                    start,
+                    max.saturating_add(1),
+                    &filter,
+                    order,
+                )?,
+            };
+            Ok((count, articles))
+        })
+        .await?;
+    let count: usize = count
         // Convert the i64 to usize:
         .try_into()
         // Handle the case where it can't be converted - Should never happen.
@@ -205,38 +377,40 @@ fn articles_or_shorts_starting_from(
                 "Article count cannot be converted to usize - Should never happen",
             ))
         })?;
-    // If start is >= count, respond with 404.
-    if start >= count {
+    // If start is >= count (or the cursor is past the end),
+    // respond with 404.
+    if articles.is_empty() {
         Err(Error::NotFound(String::from("No articles found")))
     } else {
-        let articles = db::articles_from_to(pool, &article_selector, start, max, &tags, order)
-            .map_err(map_db_error)?;
-
-        // Generate a link header with the last page on it
-        // TODO: Might need to extract this to a helper for re-use and testing
-        let mut link_header = String::from("<");
-        if api_root.is_some() {
-            link_header.push_str(&api_root.clone().unwrap());
-        } else {
-            // We use https by default, might be wrong but whatever.
-            let host = req.headers().get("host").map(|h| h.to_str().unwrap_or(""));
-            link_header.push_str(&format!("https://{}", host.unwrap_or("localhost")));
-        }
-        // Processing the "last" one:
-        if max >= count {
-            link_header.push_str(req.path());
-        } else {
-            let factor = count / max;
-            link_header.push_str(&replace_start_in_pagination_path(req.path(), factor * max));
-        }
-        link_header.push_str(&format!("?max={}>; rel=\"last\"", max));
+        let mut articles = articles;
+        let more_pages = helpers::trim_page(&mut articles, max, start);
+        let bounds = (
+            articles.first().map(|a| a.id).unwrap_or_default(),
+            articles.last().map(|a| a.id).unwrap_or_default(),
+        );
+        let with_cursor = query.cursor.is_some();
+        let pagination =
+            helpers::Pagination::new(start, max, count, with_cursor, more_pages, bounds);
 
         // Might be another way to convert the whole Vec, but I don't know
         // about it.
         let article_dtos: Vec<ArticleDto> = articles.into_iter().map(|a| a.into()).collect();
-        Ok(HttpResponse::Ok()
-            .set_header("link", link_header)
-            .json(article_dtos))
+        let mut response = HttpResponse::Ok();
+        pagination.set_headers(
+            &mut response,
+            &req,
+            app_state.site_info.api_root.as_deref(),
+            offset_param,
+        );
+        if with_cursor {
+            Ok(response.json(CursorPageDto {
+                items: article_dtos,
+                next: pagination.next_cursor(),
+                prev: pagination.prev_cursor(),
+            }))
+        } else {
+            Ok(response.json(article_dtos))
+        }
     }
 }
 
@@ -246,14 +420,17 @@ pub async fn articles_starting_from(
     query: web::Query<ArticlesQuery>,
     req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
+    let filter = db::ArticleFilter::tags(tag_filter(&query)?);
     articles_or_shorts_starting_from(
-        &app_state.site_info.api_root,
-        &app_state.pool,
-        path,
+        app_state,
+        path.into_inner().0,
+        helpers::OffsetParam::Path,
         query,
         db::ArticleSelector::Article,
+        filter,
         req,
     )
+    .await
 }
 
 pub async fn shorts_starting_from(
@@ -262,14 +439,77 @@ pub async fn shorts_starting_from(
     query: web::Query<ArticlesQuery>,
     req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
+    let filter = db::ArticleFilter::tags(tag_filter(&query)?);
     articles_or_shorts_starting_from(
-        &app_state.site_info.api_root,
-        &app_state.pool,
-        path,
+        app_state,
+        path.into_inner().0,
+        helpers::OffsetParam::Path,
         query,
         db::ArticleSelector::Short,
+        filter,
         req,
     )
+    .await
+}
+
+// Ignores the tag related query params, the start comes
+// from the query string.
+pub async fn tag_articles(
+    app_state: web::Data<AppState>,
+    path: web::Path<(String,)>,
+    query: web::Query<ArticlesQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let tags = db::TagFilter {
+        names: vec![path.into_inner().0],
+        ids: Vec::new(),
+        match_mode: db::TagMatch::All,
+    };
+    articles_or_shorts_starting_from(
+        app_state,
+        query.start.unwrap_or_default(),
+        helpers::OffsetParam::Query("start"),
+        query,
+        db::ArticleSelector::Article,
+        db::ArticleFilter::tags(Some(tags)),
+        req,
+    )
+    .await
+}
+
+// Same as the tag articles, for a single author.
+pub async fn author_articles(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    query: web::Query<ArticlesQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    articles_or_shorts_starting_from(
+        app_state,
+        query.start.unwrap_or_default(),
+        helpers::OffsetParam::Query("start"),
+        query,
+        db::ArticleSelector::Article,
+        db::ArticleFilter::author(path.into_inner().0),
+        req,
+    )
+    .await
+}
+
+pub async fn authors(app_state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    let users = app_state.db(db::all_users).await?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+pub async fn author(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse, Error> {
+    let user_id = path.into_inner().0;
+    match app_state.db(move |pool| db::user_by_id(pool, user_id)).await? {
+        Some(user) => Ok(HttpResponse::Ok().json(user)),
+        None => Err(Error::NotFound("Author does not exist".to_string())),
+    }
 }
 
 pub async fn post_comment(
@@ -285,15 +525,22 @@ pub async fn post_comment(
         Some(article_id) => article_id,
         None => {
             // Do we have an articleurl?
-            match &comment_form.articleurl {
-                Some(url) => db::article_id_by_url(&app_state.pool, &url)
-                    .map_err(map_db_error)?
+            match comment_form.articleurl.clone() {
+                Some(url) => app_state
+                    .db(move |pool| db::article_id_by_url(pool, &url))
+                    .await?
                     .unwrap_or(-1),
                 None => -1,
             }
         }
     };
-    if article_id <= 0 {
+    // Unpublished and trashed articles can't be commented,
+    // their comments wouldn't show anyway.
+    if article_id <= 0
+        || !app_state
+            .db(move |pool| db::published_article_exists(pool, article_id))
+            .await?
+    {
         // Return a BadRequest immediately.
         return Err(Error::BadRequest(String::from(
             "Invalid article URL, ID, or no ID provided",
@@ -317,50 +564,477 @@ pub async fn post_comment(
         )));
     }
 
-    // This is where I decide to check with my really basic
-    // homemade rate limiter:
-    if app_state.check_rate_limit() {
-        return Err(Error::TooManyRequests);
+    // Replies have to be made to an approved comment of the
+    // same article, replies to anything else would never be
+    // shown. Replies to replies are fine, threads can be
+    // nested as deep as people want (format=tree shows them
+    // that way).
+    if let Some(parent_id) = comment_form.parent_id {
+        match app_state
+            .db(move |pool| db::comment_by_id(pool, parent_id))
+            .await?
+        {
+            Some(parent)
+                if parent.article_id == article_id
+                    && parent.status == CommentStatus::Approved => {}
+            _ => {
+                return Err(Error::BadRequest(String::from(
+                    "Parent comment does not exist, is not approved or belongs to another article",
+                )))
+            }
+        }
     }
 
-    // Note that if we provide an article_id that's >= 0, we don't
-    // actually check if it exists or not. We just update anyway.
+    // Comments are held for moderation if the config says so.
+    let status = if app_state.comment_moderation {
+        CommentStatus::Pending
+    } else {
+        CommentStatus::Approved
+    };
     let mut comment = Comment {
         article_id,
         id: -1,
         author,
         comment: text_utils::escape_html(&comment_form.comment),
         date: time_utils::current_timestamp(),
+        status,
+        parent_id: comment_form.parent_id,
         client_ip: helpers::real_ip_addr(&req).map(|ip| ip.to_string()),
     };
 
-    db::insert_comment(&app_state.pool, &mut comment).map_err(|e| {
-        error!("Could not insert a comment - {}", e);
-        Error::DatabaseError(format!("Failed to insert comment - {}", e))
-    })?;
+    let comment = app_state
+        .db(move |pool| {
+            db::insert_comment(pool, &mut comment)?;
+            Ok(comment)
+        })
+        .await?;
 
     Ok(HttpResponse::Ok().json(CommentDto::from(comment)))
 }
 
 pub async fn last_comment(app_state: web::Data<AppState>) -> Result<HttpResponse, Error> {
-    let comm: Option<Comment> = db::last_comment(&app_state.pool).map_err(map_db_error)?;
+    let comm: Option<Comment> = app_state.db(db::last_comment).await?;
     match comm {
         Some(comment) => Ok(HttpResponse::Ok().json(CommentDto::from(comment))),
         None => Err(Error::NotFound("No comment found".to_string())),
     }
 }
 
+// Moderation endpoints. Pending comments are listed oldest
+// first using the same start and max query params as the
+// public comments endpoint.
+pub async fn pending_comments(
+    app_state: web::Data<AppState>,
+    query: web::Query<CommentsQuery>,
+) -> Result<HttpResponse, Error> {
+    let start = query.start.unwrap_or_default();
+    let max = query
+        .max
+        .map(|m| if m > 50 { 50 } else { m })
+        .unwrap_or(MAX_COMMENTS);
+    let comments: Vec<CommentDto> = app_state
+        .db(move |pool| db::comments_by_status(pool, CommentStatus::Pending, start, max))
+        .await?
+        .into_iter()
+        .map(CommentDto::from)
+        .collect();
+    Ok(HttpResponse::Ok().json(comments))
+}
+
+async fn set_comment_status(
+    app_state: web::Data<AppState>,
+    comment_id: i32,
+    status: CommentStatus,
+) -> Result<HttpResponse, Error> {
+    let count = app_state
+        .db(move |pool| db::update_comment_status(pool, comment_id, status))
+        .await?;
+    if count > 0 {
+        Ok(HttpResponse::Ok().json(JsonStatus::new_with_id(
+            JsonStatusType::Success,
+            &format!("Comment status set to {}", status),
+            comment_id,
+        )))
+    } else {
+        Err(Error::NotFound("Comment does not exist".to_string()))
+    }
+}
+
+pub async fn approve_comment(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse, Error> {
+    set_comment_status(app_state, path.into_inner().0, CommentStatus::Approved).await
+}
+
+pub async fn reject_comment(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse, Error> {
+    set_comment_status(app_state, path.into_inner().0, CommentStatus::Spam).await
+}
+
+pub async fn delete_comment(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse, Error> {
+    let comment_id = path.into_inner().0;
+    let count = app_state
+        .db(move |pool| db::delete_comment(pool, comment_id))
+        .await?;
+    if count > 0 {
+        Ok(HttpResponse::Ok().json(JsonStatus::new_with_id(
+            JsonStatusType::Success,
+            "Comment deleted",
+            comment_id,
+        )))
+    } else {
+        Err(Error::NotFound("Comment does not exist".to_string()))
+    }
+}
+
+// Tag names are trimmed, can't be empty and have to be
+// unique (the database would refuse duplicates anyway).
+// The tag being renamed can keep its own name.
+async fn valid_tag_name(
+    app_state: &web::Data<AppState>,
+    name: &str,
+    tag_id: Option<i32>,
+) -> Result<String, Error> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(Error::BadRequest("Tag name cannot be empty".to_string()));
+    }
+    let lookup = name.clone();
+    let existing = app_state
+        .db(move |pool| db::tag_by_name(pool, &lookup))
+        .await?;
+    match existing {
+        Some(tag) if Some(tag.id) != tag_id => {
+            Err(Error::BadRequest(format!("Tag {} already exists", name)))
+        }
+        _ => Ok(name),
+    }
+}
+
+// main_tag is a boolean, I don't want anything else in there.
+fn valid_main_tag(main_tag: Option<i32>) -> Result<Option<i32>, Error> {
+    match main_tag {
+        None | Some(0) | Some(1) => Ok(main_tag),
+        _ => Err(Error::BadRequest("mainTag has to be 0 or 1".to_string())),
+    }
+}
+
+pub async fn admin_insert_tag(
+    app_state: web::Data<AppState>,
+    body: web::Json<TagBodyDto>,
+) -> Result<HttpResponse, Error> {
+    let body = body.into_inner();
+    let name = match &body.name {
+        Some(name) => valid_tag_name(&app_state, name, None).await?,
+        None => return Err(Error::BadRequest("Missing tag name".to_string())),
+    };
+    let mut tag = Tag {
+        id: -1,
+        name,
+        main_tag: valid_main_tag(body.main_tag)?.unwrap_or(1),
+    };
+    let tag = app_state
+        .db(move |pool| db::insert_tag(pool, &mut tag).map(|_| tag))
+        .await?;
+    Ok(HttpResponse::Ok().json(tag))
+}
+
+pub async fn admin_update_tag(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    body: web::Json<TagBodyDto>,
+) -> Result<HttpResponse, Error> {
+    let tag_id = path.into_inner().0;
+    let body = body.into_inner();
+    let name = match &body.name {
+        Some(name) => Some(valid_tag_name(&app_state, name, Some(tag_id)).await?),
+        None => None,
+    };
+    let main_tag = valid_main_tag(body.main_tag)?;
+    let tag = app_state
+        .db(move |pool| {
+            db::update_tag(pool, tag_id, name.as_deref(), main_tag)?;
+            db::tag_by_id(pool, tag_id)
+        })
+        .await?;
+    match tag {
+        Some(tag) => Ok(HttpResponse::Ok().json(tag)),
+        None => Err(Error::NotFound("Tag does not exist".to_string())),
+    }
+}
+
+pub async fn admin_delete_tag(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse, Error> {
+    let tag_id = path.into_inner().0;
+    let count = app_state
+        .db(move |pool| db::delete_tag(pool, tag_id))
+        .await?;
+    if count > 0 {
+        app_state.related_articles.invalidate();
+        Ok(HttpResponse::Ok().json(JsonStatus::new_with_id(
+            JsonStatusType::Success,
+            "Tag deleted",
+            tag_id,
+        )))
+    } else {
+        Err(Error::NotFound("Tag does not exist".to_string()))
+    }
+}
+
+// Merges the tag from the path into the one given in the
+// body, the tag from the path is deleted.
+pub async fn admin_merge_tag(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    body: web::Json<TagMergeDto>,
+) -> Result<HttpResponse, Error> {
+    let source_id = path.into_inner().0;
+    let target_id = body.into_inner().into;
+    if source_id == target_id {
+        return Err(Error::BadRequest(
+            "Cannot merge a tag into itself".to_string(),
+        ));
+    }
+    let moved = app_state
+        .db(move |pool| {
+            if !db::tag_exists(pool, source_id)? || !db::tag_exists(pool, target_id)? {
+                return Ok(None);
+            }
+            db::merge_tags(pool, source_id, target_id).map(Some)
+        })
+        .await?;
+    if moved.is_some() {
+        app_state.related_articles.invalidate();
+    }
+    match moved {
+        Some(moved) => Ok(HttpResponse::Ok().json(JsonStatus::new_with_id(
+            JsonStatusType::Success,
+            &format!("Tag merged, {} article(s) moved", moved),
+            target_id,
+        ))),
+        None => Err(Error::NotFound("Tag does not exist".to_string())),
+    }
+}
+
+// Same rules as tag names.
+async fn valid_author_name(
+    app_state: &web::Data<AppState>,
+    name: &str,
+    user_id: Option<i32>,
+) -> Result<String, Error> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(Error::BadRequest("Author name cannot be empty".to_string()));
+    }
+    let lookup = name.clone();
+    let existing = app_state
+        .db(move |pool| db::user_by_name(pool, &lookup))
+        .await?;
+    match existing {
+        Some(user) if Some(user.id) != user_id => {
+            Err(Error::BadRequest(format!("Author {} already exists", name)))
+        }
+        _ => Ok(name),
+    }
+}
+
+// The link ends up in an href on the blog, I don't want
+// anything else than a web link in there.
+fn valid_author_link(link: &Option<String>) -> Result<(), Error> {
+    match link {
+        Some(link) if !link.starts_with("https://") && !link.starts_with("http://") => Err(
+            Error::BadRequest("Author link has to be an http(s) URL".to_string()),
+        ),
+        _ => Ok(()),
+    }
+}
+
+pub async fn admin_insert_author(
+    app_state: web::Data<AppState>,
+    body: web::Json<AuthorBodyDto>,
+) -> Result<HttpResponse, Error> {
+    let body = body.into_inner().normalized();
+    let name = match &body.name {
+        Some(name) => valid_author_name(&app_state, name, None).await?,
+        None => return Err(Error::BadRequest("Missing author name".to_string())),
+    };
+    let mut user = User {
+        id: -1,
+        name,
+        bio: body.bio.flatten(),
+        avatar: body.avatar.flatten(),
+        link: body.link.flatten(),
+    };
+    valid_author_link(&user.link)?;
+    let user = app_state
+        .db(move |pool| db::insert_user(pool, &mut user).map(|_| user))
+        .await?;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+pub async fn admin_update_author(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    body: web::Json<AuthorBodyDto>,
+) -> Result<HttpResponse, Error> {
+    let user_id = path.into_inner().0;
+    let body = body.into_inner().normalized();
+    if let Some(link) = &body.link {
+        valid_author_link(link)?;
+    }
+    let name = match &body.name {
+        Some(name) => Some(valid_author_name(&app_state, name, Some(user_id)).await?),
+        None => None,
+    };
+    let update = UserUpdate {
+        id: user_id,
+        name,
+        bio: body.bio,
+        avatar: body.avatar,
+        link: body.link,
+    };
+    let user = app_state
+        .db(move |pool| {
+            db::update_user(pool, &update)?;
+            db::user_by_id(pool, user_id)
+        })
+        .await?;
+    match user {
+        Some(user) => Ok(HttpResponse::Ok().json(user)),
+        None => Err(Error::NotFound("Author does not exist".to_string())),
+    }
+}
+
+// Authors that still have articles can't be deleted, their
+// articles would end up being from "Anonymous".
+pub async fn admin_delete_author(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse, Error> {
+    let user_id = path.into_inner().0;
+    let (article_count, count) = app_state
+        .db(move |pool| {
+            let article_count = db::user_article_count(pool, user_id)?;
+            if article_count > 0 {
+                return Ok((article_count, 0));
+            }
+            Ok((0, db::delete_user(pool, user_id)?))
+        })
+        .await?;
+    if article_count > 0 {
+        Err(Error::BadRequest(format!(
+            "Author still has {} article(s)",
+            article_count
+        )))
+    } else if count > 0 {
+        Ok(HttpResponse::Ok().json(JsonStatus::new_with_id(
+            JsonStatusType::Success,
+            "Author deleted",
+            user_id,
+        )))
+    } else {
+        Err(Error::NotFound("Author does not exist".to_string()))
+    }
+}
+
+// Article revisions are credited to the name of the API
+// token used for the request, which the ApiTokens
+// middleware already looked up.
+fn token_name(req: &HttpRequest) -> String {
+    req.extensions()
+        .get::<ApiToken>()
+        .map(|t| t.name.clone())
+        .unwrap_or_default()
+}
+
 // We're using a lock present in app_state to make sure only one
 // import takes place at a given time.
 // I think it works. lol.
-pub async fn import_article(app_state: web::Data<AppState>) -> HttpResponse {
+pub async fn import_article(
+    app_state: web::Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let author = token_name(&req);
     match app_state
         .import_service
-        .import_articles(&app_state.pool)
+        .import_articles(&app_state.pool, &author)
         .await
     {
-        Ok(statuses) => HttpResponse::Ok().json(statuses),
-        Err(status) => HttpResponse::Forbidden().json(status),
+        Ok(statuses) => Ok(HttpResponse::Ok().json(statuses)),
+        Err(status) => Ok(HttpResponse::Forbidden().json(status)),
+    }
+}
+
+// Admin API for articles. Goes through the exact same
+// validation as the import service, the JSON body also
+// uses the import format.
+// The id and action fields are ignored, the path and
+// HTTP method decide what happens.
+pub async fn admin_insert_article(
+    app_state: web::Data<AppState>,
+    article: web::Json<ImportedArticleDto>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let author = token_name(&req);
+    let mut article = article.into_inner();
+    article.id = None;
+    article.action = None;
+    match app_state
+        .import_service
+        .save_single_article(&app_state.pool, article, &author)
+        .await
+    {
+        Ok(status) => Ok(HttpResponse::Ok().json(status)),
+        Err(e) => Ok(save_error_response(e)),
+    }
+}
+
+pub async fn admin_update_article(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    article: web::Json<ImportedArticleDto>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let author = token_name(&req);
+    let mut article = article.into_inner();
+    article.id = Some(path.into_inner().0);
+    article.action = None;
+    match app_state
+        .import_service
+        .save_single_article(&app_state.pool, article, &author)
+        .await
+    {
+        Ok(status) => Ok(HttpResponse::Ok().json(status)),
+        Err(e) => Ok(save_error_response(e)),
+    }
+}
+
+pub async fn admin_delete_article(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let author = token_name(&req);
+    let article = ImportedArticleDto {
+        id: Some(path.into_inner().0),
+        action: Some(1),
+        ..Default::default()
+    };
+    match app_state
+        .import_service
+        .save_single_article(&app_state.pool, article, &author)
+        .await
+    {
+        Ok(status) => Ok(HttpResponse::Ok().json(status)),
+        Err(e) => Ok(save_error_response(e)),
     }
 }
 
@@ -380,28 +1054,160 @@ pub async fn search_articles(
     // Weird invalid regex I was using for Java: [+*$%\\s]
     // I should probably allow "*" but remove "^".
 
-    // First, check rate limiting:
-    if app_state.check_rate_limit() {
-        return Err(Error::TooManyRequests);
-    }
-
-    let sanitized = text_utils::sanitize_search_terms(&search_body.include, MAX_SEARCH_TERMS);
+    let sanitized = text_utils::sanitize_search_terms(
+        &search_body.include,
+        MAX_SEARCH_TERMS,
+        app_state.search_stemming,
+    );
     // Test that we still got search terms after sanitization!
     if sanitized.is_empty() {
         // It's not actually an error, just return nothing:
-        Ok(HttpResponse::Ok().json(Vec::<String>::new()))
-    } else {
-        let articles =
-            db::search_published_articles(&app_state.pool, &sanitized[..]).map_err(map_db_error)?;
-        // There is a max number of results per query fixed
-        // in the DB function (supposedly at 15).
-        Ok(HttpResponse::Ok().json(
+        return Ok(HttpResponse::Ok()
+            .set_header("x-total-count", "0")
+            .json(Vec::<String>::new()));
+    }
+    let (article_selector, filter) = search_filter(&search_body)?;
+    let start = search_body.start.unwrap_or(0);
+    if start > MAX_SEARCH_START {
+        return Err(Error::BadRequest(format!(
+            "Start can't be more than {}",
+            MAX_SEARCH_START
+        )));
+    }
+    let max = search_body
+        .max
+        .unwrap_or(SEARCH_DEFAULT_MAX)
+        .clamp(1, MAX_SEARCH_RESULTS);
+    let (articles, total) = app_state
+        .db(move |pool| {
+            let total = db::search_count(pool, &sanitized, &article_selector, &filter)?;
+            let articles = db::search_published_articles(
+                pool,
+                &sanitized,
+                &article_selector,
+                &filter,
+                start,
+                max,
+            )?;
+            Ok((articles, total))
+        })
+        .await?;
+    // The total is in a header, same as the article
+    // listings, so that the body stays a plain list.
+    Ok(HttpResponse::Ok()
+        .set_header("x-total-count", total.to_string())
+        .json(
             articles
                 .into_iter()
                 .map(Into::into)
                 .collect::<Vec<SearchResult>>(),
         ))
+}
+
+// Completes the last word of the query from the fulltext
+// index vocabulary. When the query finds nothing, also
+// suggests terms that are close to the words that aren't
+// in any published article (typos, mostly).
+pub async fn search_suggestions(
+    app_state: web::Data<AppState>,
+    search_body: web::Json<SearchBody>,
+) -> Result<HttpResponse, Error> {
+    let words = text_utils::search_query_words(&search_body.include, MAX_SEARCH_TERMS);
+    let sanitized = text_utils::sanitize_search_terms(
+        &search_body.include,
+        MAX_SEARCH_TERMS,
+        app_state.search_stemming,
+    );
+    let (article_selector, filter) = search_filter(&search_body)?;
+    let max = search_body
+        .max
+        .unwrap_or(SUGGEST_DEFAULT_MAX)
+        .clamp(1, MAX_SUGGESTIONS);
+    let suggestions = app_state
+        .db(move |pool| {
+            let completions = match words.last() {
+                Some(last) if last.chars().count() >= MIN_COMPLETION_LENGTH => {
+                    db::vocabulary_completions(pool, last, max)?
+                }
+                _ => Vec::new(),
+            };
+            let mut corrections = Vec::new();
+            if !sanitized.is_empty()
+                && db::search_count(pool, &sanitized, &article_selector, &filter)? == 0
+            {
+                for word in words {
+                    let length = word.chars().count();
+                    if length < MIN_CORRECTION_LENGTH
+                        || db::published_term_count(pool, &word)? > 0
+                    {
+                        continue;
+                    }
+                    // Short words get way too many matches
+                    // at two edits.
+                    let distance = if length <= 4 { 1 } else { 2 };
+                    let suggestions =
+                        db::close_terms(pool, &word, distance, MAX_CORRECTIONS)?;
+                    if !suggestions.is_empty() {
+                        corrections.push(CorrectionDto {
+                            term: word,
+                            suggestions,
+                        });
+                    }
+                }
+            }
+            Ok(SuggestionsDto {
+                completions,
+                corrections,
+            })
+        })
+        .await?;
+    Ok(HttpResponse::Ok().json(suggestions))
+}
+
+fn search_filter(
+    search_body: &SearchBody,
+) -> Result<(db::ArticleSelector, db::ArticleFilter), Error> {
+    let article_selector = match search_body.article_type.as_deref() {
+        None | Some("all") => db::ArticleSelector::All,
+        Some("articles") => db::ArticleSelector::Article,
+        Some("shorts") => db::ArticleSelector::Short,
+        Some(_) => {
+            return Err(Error::BadRequest(String::from(
+                "Type has to be either articles, shorts or all",
+            )))
+        }
+    };
+    // Every tag adds a subquery.
+    let too_many = |count: Option<usize>| count.unwrap_or_default() > MAX_SEARCH_TERMS;
+    if too_many(search_body.tags.as_ref().map(Vec::len))
+        || too_many(search_body.tag_ids.as_ref().map(Vec::len))
+    {
+        return Err(Error::BadRequest(format!(
+            "Tags and tag IDs can't have more than {} items",
+            MAX_SEARCH_TERMS
+        )));
     }
+    let tags = db::TagFilter {
+        names: search_body.tags.clone().unwrap_or_default(),
+        ids: search_body.tag_ids.clone().unwrap_or_default(),
+        match_mode: tag_match(search_body.match_mode.as_deref())?,
+    };
+    let invalid_date = || Error::BadRequest(String::from("Dates have to be YYYY-MM-DD"));
+    let from_date = match &search_body.from {
+        Some(from) => Some(time_utils::day_start_timestamp(from).ok_or_else(invalid_date)?),
+        None => None,
+    };
+    let to_date = match &search_body.to {
+        Some(to) => Some(time_utils::day_end_timestamp(to).ok_or_else(invalid_date)?),
+        None => None,
+    };
+    let filter = db::ArticleFilter {
+        tags: if tags.is_empty() { None } else { Some(tags) },
+        from_date,
+        to_date,
+        ..Default::default()
+    };
+    Ok((article_selector, filter))
 }
 
 // Because the endpoint is beyond a guard that restricts
@@ -418,20 +1224,17 @@ pub async fn rss(
     // in the dtos module to serve as the full RSS data model.
     let mut data = RssFeed::new(&app_state.site_info, MAX_RSS_LENGTH);
 
-    // Get all the articles one by one by first fetching all
-    // of their IDs. I'm doing this hoping the articles will
-    // get dropped at each iteration, freeing "some" memory.
+    // Get all the articles in one go, tags included.
     // We ignore DB errors here and just output an empty RSS
     // file if an error happened.
     // I don't limit the amount of articles in the feed, this
     // could eventually get too big.
-    if let Ok(ids) = db::all_published_articles_and_shorts_ids(&app_state.pool, db::Order::Desc) {
-        for id in ids {
-            // Fetch the article:
-            if let Ok(Some(article)) = db::article_by_id(&app_state.pool, id) {
-                data.add_item(article);
-            }
-        }
+    let articles = app_state
+        .db(|pool| db::all_published_articles_and_shorts(pool, db::Order::Desc))
+        .await
+        .unwrap_or_default();
+    for article in articles {
+        data.add_item(article);
     }
 
     let body = hb.render("rss", &data).map_err(|e| {
@@ -448,9 +1251,10 @@ pub async fn comments_starting_from(
     app_state: web::Data<AppState>,
     path: web::Path<(String,)>,
     query: web::Query<CommentsQuery>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let article_url = path.into_inner().0;
-    let start = query.start.unwrap_or_default();
+    let start = page_start(&query.cursor, query.start.unwrap_or_default())?;
     let max = query
         .max
         .map(|m| if m > 50 { 50 } else { m })
@@ -462,7 +1266,10 @@ pub async fn comments_starting_from(
         Ok(article_id) => article_id,
         Err(_) => {
             // Try to find the ID in database:
-            match db::article_id_by_url(&app_state.pool, &article_url) {
+            match app_state
+                .db(move |pool| db::article_id_by_url(pool, &article_url))
+                .await
+            {
                 Ok(Some(id)) => id,
                 // I just don't care about errors here.
                 _ => -1,
@@ -470,9 +1277,21 @@ pub async fn comments_starting_from(
         }
     };
 
-    // Get the comment count for that article:
-    let count = db::comment_count(&app_state.pool, article_id)
-        .map_err(map_db_error)?
+    let as_tree = match query.format.as_deref() {
+        None | Some("flat") => false,
+        Some("tree") => true,
+        Some(_) => {
+            return Err(Error::BadRequest(String::from(
+                "Format has to be either tree or flat",
+            )))
+        }
+    };
+
+    // Get the top-level comment count for that article,
+    // pagination ignores replies:
+    let count = app_state
+        .db(move |pool| db::top_level_comment_count(pool, article_id))
+        .await?
         // Convert the i64 to usize:
         .try_into()
         // Handle the case where it can't be converted - Should never happen.
@@ -483,20 +1302,61 @@ pub async fn comments_starting_from(
             ))
         })?;
     // If start is >= count, respond with 404.
-    if start >= count {
-        Err(Error::NotFound(String::from("No comments found")))
-    } else {
-        let comments: Vec<CommentDto> =
-            db::comments_from_to(&app_state.pool, start, max, article_id)
-                .map_err(map_db_error)?
-                .into_iter()
-                // I'm removing the article ID from the comments because uh...
-                // I don't need it and that spares like 50 bytes for the response.
-                // I'm weird.
-                .map(|c| CommentDto::from(c).remove_article_id())
-                .collect();
+    let past_the_end = match start {
+        db::PageStart::Offset(offset) => offset >= count,
+        _ => count == 0,
+    };
+    if past_the_end {
+        return Err(Error::NotFound(String::from("No comments found")));
+    }
+    // Fetching one more top-level comment tells us if
+    // there's a next (or previous) page.
+    let mut comments: Vec<CommentDto> = app_state
+        .db(move |pool| db::comments_from_to(pool, start, max.saturating_add(1), article_id))
+        .await?
+        .into_iter()
+        // I'm removing the article ID from the comments because uh...
+        // I don't need it and that spares like 50 bytes for the response.
+        // I'm weird.
+        .map(|c| CommentDto::from(c).remove_article_id().remove_status())
+        .collect();
+    if comments.is_empty() {
+        return Err(Error::NotFound(String::from("No comments found")));
+    }
+    let more_pages = trim_comment_page(&mut comments, max, start);
+    // Cursors point to top-level comments:
+    let top_level: Vec<i32> = comments
+        .iter()
+        .filter(|c| c.parent_id.is_none())
+        .map(|c| c.id)
+        .collect();
+    let bounds = (
+        top_level.first().copied().unwrap_or_default(),
+        top_level.last().copied().unwrap_or_default(),
+    );
+    let with_cursor = query.cursor.is_some();
+    let pagination = helpers::Pagination::new(start, max, count, with_cursor, more_pages, bounds);
 
-        Ok(HttpResponse::Ok().json(comments))
+    let comments = if as_tree {
+        comments_to_tree(comments)
+    } else {
+        comments
+    };
+    let mut response = HttpResponse::Ok();
+    pagination.set_headers(
+        &mut response,
+        &req,
+        app_state.site_info.api_root.as_deref(),
+        helpers::OffsetParam::Query("start"),
+    );
+    if with_cursor {
+        Ok(response.json(CursorPageDto {
+            items: comments,
+            next: pagination.next_cursor(),
+            prev: pagination.prev_cursor(),
+        }))
+    } else {
+        Ok(response.json(comments))
     }
 }
 
@@ -506,8 +1366,10 @@ pub async fn sitemap(
 ) -> Result<HttpResponse, Error> {
     // This endpoint doesn't show an error on database
     // errors, it just displays an empty sitemap.
-    let urls: Vec<String> = db::all_published_articles_and_shorts_urls(&app_state.pool)
-        .unwrap_or(Vec::new())
+    let urls: Vec<String> = app_state
+        .db(db::all_published_articles_and_shorts_urls)
+        .await
+        .unwrap_or_default()
         .into_iter()
         .map(|(u, short)| {
             helpers::generate_article_url(
@@ -560,8 +1422,9 @@ pub async fn render_article(
     // enough to not warrant crazy refactoring.
     let article_url = path.into_inner().0;
     // Check if we got an article ID:
-    let article: Option<Article> =
-        db::article_by_id_or_url(&app_state.pool, &article_url).map_err(map_db_error)?;
+    let article: Option<Article> = app_state
+        .db(move |pool| db::published_article_by_id_or_url(pool, &article_url))
+        .await?;
     // Send a 404 if there are no articles:
     match article {
         Some(a) => {
@@ -574,17 +1437,7 @@ pub async fn render_article(
                 },
                 &app_state.stats_service,
             );
-            // Create the data for the template.
-            let data = RenderedArticle::new(a, &app_state.site_info);
-            let body = hb.render("article", &data).map_err(|e| {
-                error!(
-                    "A template engine error occued when rendering \
-            an article: {}",
-                    e
-                );
-                Error::InternalServerError("Template engine error".to_string())
-            })?;
-
+            let body = render_article_template(&hb, a, &app_state)?;
             Ok(HttpResponse::Ok()
                 .content_type("text/html; charset=UTF-8")
                 .body(body))
@@ -592,3 +1445,448 @@ pub async fn render_article(
         None => Err(Error::NotFound("Article does not exist".to_string())),
     }
 }
+
+fn render_article_template(
+    hb: &Handlebars<'_>,
+    article: Article,
+    app_state: &AppState,
+) -> Result<String, Error> {
+    // Create the data for the template.
+    let data = RenderedArticle::new(article, &app_state.site_info);
+    hb.render("article", &data).map_err(|e| {
+        error!(
+            "A template engine error occued when rendering \
+    an article: {}",
+            e
+        );
+        Error::InternalServerError("Template engine error".to_string())
+    })
+}
+
+// Preview links give access to a single article, published
+// or not, until they expire. They're never counted in the
+// stats.
+async fn previewed_article(
+    app_state: &web::Data<AppState>,
+    article_id: i32,
+    query: &PreviewQuery,
+) -> Result<Article, Error> {
+    let valid = query.expires >= time_utils::current_timestamp()
+        && token_utils::valid_preview_signature(
+            &app_state.preview_secret,
+            article_id,
+            query.expires,
+            &query.signature,
+        );
+    if !valid {
+        return Err(Error::Forbidden("Invalid or expired preview link".to_string()));
+    }
+    app_state
+        .db(move |pool| db::article_by_id(pool, article_id))
+        .await?
+        .ok_or_else(|| Error::NotFound("Article does not exist".to_string()))
+}
+
+pub async fn preview_article(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    query: web::Query<PreviewQuery>,
+) -> Result<HttpResponse, Error> {
+    let article = previewed_article(&app_state, path.into_inner().0, &query).await?;
+    Ok(HttpResponse::Ok()
+        .set_header("x-robots-tag", "noindex")
+        .json(ArticleDto::from(article)))
+}
+
+pub async fn render_preview(
+    app_state: web::Data<AppState>,
+    hb: web::Data<Handlebars<'_>>,
+    path: web::Path<(i32,)>,
+    query: web::Query<PreviewQuery>,
+) -> Result<HttpResponse, Error> {
+    let article = previewed_article(&app_state, path.into_inner().0, &query).await?;
+    let body = render_article_template(&hb, article, &app_state)?;
+    Ok(HttpResponse::Ok()
+        .set_header("x-robots-tag", "noindex")
+        .content_type("text/html; charset=UTF-8")
+        .body(body))
+}
+
+// Dates in the past get published right away, with
+// that date.
+pub async fn admin_schedule_article(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    body: web::Json<ScheduleDto>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let article_id = path.into_inner().0;
+    let author = token_name(&req);
+    let publish_at = time_utils::rfc3339_timestamp(&body.publish_at).ok_or_else(|| {
+        Error::BadRequest(String::from(
+            "publishAt has to be a date like 2021-03-07T21:59:00+01:00",
+        ))
+    })?;
+    let count = app_state
+        .db(move |pool| {
+            db::with_revision(pool, article_id, RevisionAction::Update, &author, |conn| {
+                db::schedule_article_tx(conn, article_id, Some(publish_at))
+            })
+        })
+        .await?;
+    if count > 0 {
+        app_state.publish_scheduler.wake_up();
+        app_state.related_articles.invalidate();
+        Ok(HttpResponse::Ok().json(JsonStatus::new_with_id(
+            JsonStatusType::Success,
+            &format!(
+                "Article will be published on {}",
+                time_utils::timestamp_to_date_string(publish_at, time_utils::DateFormat::Standard)
+            ),
+            article_id,
+        )))
+    } else {
+        Err(Error::NotFound("Article does not exist".to_string()))
+    }
+}
+
+// The article stays unpublished.
+pub async fn admin_unschedule_article(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let article_id = path.into_inner().0;
+    let author = token_name(&req);
+    let count = app_state
+        .db(move |pool| {
+            db::with_revision(pool, article_id, RevisionAction::Update, &author, |conn| {
+                db::schedule_article_tx(conn, article_id, None)
+            })
+        })
+        .await?;
+    if count > 0 {
+        app_state.publish_scheduler.wake_up();
+        app_state.related_articles.invalidate();
+        Ok(HttpResponse::Ok().json(JsonStatus::new_with_id(
+            JsonStatusType::Success,
+            "Article schedule cancelled",
+            article_id,
+        )))
+    } else {
+        Err(Error::NotFound("Article does not exist".to_string()))
+    }
+}
+
+// Creates a preview link that expires after PREVIEW_LINK_HOURS.
+pub async fn admin_preview_link(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let article_id = path.into_inner().0;
+    if !app_state
+        .db(move |pool| db::article_exists(pool, article_id))
+        .await?
+    {
+        return Err(Error::NotFound("Article does not exist".to_string()));
+    }
+    let expires = time_utils::current_timestamp() + app_state.preview_link_duration;
+    // The signature is URL-safe base64, no need to encode it.
+    let query = format!(
+        "expires={}&signature={}",
+        expires,
+        token_utils::sign_preview(&app_state.preview_secret, article_id, expires)
+    );
+    let base = helpers::api_base_url(&req, app_state.site_info.api_root.as_deref());
+    Ok(HttpResponse::Ok().json(PreviewLinkDto {
+        article_id,
+        expires: time_utils::timestamp_to_date_string(expires, time_utils::DateFormat::Standard),
+        url: format!("{}/preview/{}?{}", base, article_id, query),
+        render_url: format!("{}/render-preview/{}?{}", base, article_id, query),
+    }))
+}
+
+// Articles deleted through imports or the admin API.
+pub async fn admin_trash(app_state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    let articles = app_state.db(db::trashed_articles).await?;
+    Ok(HttpResponse::Ok().json(
+        articles
+            .into_iter()
+            .map(TrashedArticleDto::from)
+            .collect::<Vec<TrashedArticleDto>>(),
+    ))
+}
+
+pub async fn admin_restore_trashed_article(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let article_id = path.into_inner().0;
+    let author = token_name(&req);
+    let count = app_state
+        .db(move |pool| {
+            db::with_revision(pool, article_id, RevisionAction::Restore, &author, |conn| {
+                db::restore_trashed_article_tx(conn, article_id)
+            })
+        })
+        .await?;
+    if count > 0 {
+        // The article may have been scheduled.
+        app_state.publish_scheduler.wake_up();
+        app_state.related_articles.invalidate();
+        Ok(HttpResponse::Ok().json(JsonStatus::new_with_id(
+            JsonStatusType::Success,
+            "Article restored",
+            article_id,
+        )))
+    } else {
+        Err(Error::NotFound("Article is not in the trash".to_string()))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PurgeQuery {
+    pub days: Option<u32>,
+}
+
+// Uses TRASH_RETENTION_DAYS unless the query has a "days"
+// parameter, which can be 0 to empty the trash.
+pub async fn admin_purge_trash(
+    app_state: web::Data<AppState>,
+    query: web::Query<PurgeQuery>,
+) -> Result<HttpResponse, Error> {
+    let days = query.days.unwrap_or(app_state.trash_retention_days);
+    let before = time_utils::current_timestamp() - i64::from(days) * 24 * 3600;
+    let count = app_state
+        .db(move |pool| db::purge_trash(pool, before))
+        .await?;
+    Ok(HttpResponse::Ok().json(JsonStatus::new(
+        JsonStatusType::Success,
+        &format!("Deleted {} article(s) for good", count),
+    )))
+}
+
+// Revisions are kept for deleted articles too, so this
+// doesn't check that the article exists.
+pub async fn admin_article_revisions(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse, Error> {
+    let article_id = path.into_inner().0;
+    let revisions = app_state
+        .db(move |pool| db::article_revisions(pool, article_id))
+        .await?;
+    Ok(HttpResponse::Ok().json(
+        revisions
+            .into_iter()
+            .map(ArticleRevisionDto::from)
+            .collect::<Vec<ArticleRevisionDto>>(),
+    ))
+}
+
+async fn find_revision(
+    app_state: &AppState,
+    article_id: i32,
+    revision_id: i32,
+) -> Result<ArticleRevision, Error> {
+    app_state
+        .db(move |pool| db::article_revision(pool, article_id, revision_id))
+        .await?
+        .ok_or_else(|| Error::NotFound("Revision does not exist".to_string()))
+}
+
+pub async fn admin_article_revision(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, Error> {
+    let (article_id, revision_id) = path.into_inner();
+    let revision = find_revision(&app_state, article_id, revision_id).await?;
+    Ok(HttpResponse::Ok().json(ArticleRevisionDto::from(revision)))
+}
+
+#[derive(Deserialize)]
+pub struct RevisionDiffQuery {
+    pub to: Option<i32>,
+}
+
+// Compares to the current article when there's no "to"
+// revision.
+pub async fn admin_article_revision_diff(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32, i32)>,
+    query: web::Query<RevisionDiffQuery>,
+) -> Result<HttpResponse, Error> {
+    let (article_id, revision_id) = path.into_inner();
+    let from = find_revision(&app_state, article_id, revision_id).await?;
+    let to = match query.to {
+        Some(to_id) => find_revision(&app_state, article_id, to_id).await?,
+        None => app_state
+            .db(move |pool| db::current_article_revision(pool, article_id))
+            .await?
+            .ok_or_else(|| {
+                Error::NotFound(
+                    "Article does not exist anymore, compare with another revision".to_string(),
+                )
+            })?,
+    };
+    Ok(HttpResponse::Ok().json(RevisionDiffDto::new(&from, &to, query.to)))
+}
+
+// Also brings back deleted articles.
+pub async fn admin_restore_article_revision(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32, i32)>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let (article_id, revision_id) = path.into_inner();
+    let revision = find_revision(&app_state, article_id, revision_id).await?;
+    // Another article may have taken the URL since.
+    if let Some(article_url) = revision.article_url.clone() {
+        let id_for_url = app_state
+            .db(move |pool| db::article_id_by_url(pool, &article_url))
+            .await?;
+        if matches!(id_for_url, Some(id) if id != article_id) {
+            return Err(Error::BadRequest(format!(
+                "Article URL {} is used by another article",
+                revision.article_url.unwrap_or_default()
+            )));
+        }
+    }
+    let author = token_name(&req);
+    app_state
+        .db(move |pool| db::restore_article_revision(pool, &revision, &author))
+        .await?;
+    app_state.related_articles.invalidate();
+    Ok(HttpResponse::Ok().json(JsonStatus::new_with_id(
+        JsonStatusType::Success,
+        &format!("Article restored to revision {}", revision_id),
+        article_id,
+    )))
+}
+
+// Stats endpoints, these read from the stats database.
+// Returns the from (inclusive) and to (exclusive)
+// timestamps for the stats query.
+fn stats_date_range(query: &StatsQuery) -> Result<(i64, i64), Error> {
+    let invalid_date = || Error::BadRequest(String::from("Dates have to be YYYY-MM-DD"));
+    let to = match &query.to {
+        Some(to) => time_utils::day_end_timestamp(to).ok_or_else(invalid_date)?,
+        None => time_utils::current_timestamp(),
+    };
+    let from = match &query.from {
+        Some(from) => time_utils::day_start_timestamp(from).ok_or_else(invalid_date)?,
+        None => to - STATS_DEFAULT_RANGE,
+    };
+    if from >= to {
+        return Err(Error::BadRequest(String::from(
+            "The from date has to be before the to date",
+        )));
+    }
+    Ok((from, to))
+}
+
+pub async fn stats_article_views(
+    app_state: web::Data<AppState>,
+    path: web::Path<(i32,)>,
+    query: web::Query<StatsQuery>,
+) -> Result<HttpResponse, Error> {
+    let article_id = path.into_inner().0;
+    let (from, to) = stats_date_range(&query)?;
+    let period = match query.period.as_deref() {
+        None | Some("day") => db::StatsPeriod::Day,
+        Some("week") => db::StatsPeriod::Week,
+        Some("month") => db::StatsPeriod::Month,
+        Some(_) => {
+            return Err(Error::BadRequest(String::from(
+                "Period has to be day, week or month",
+            )))
+        }
+    };
+    let views: Vec<PeriodViewsDto> = app_state
+        .stats_db(move |pool| db::article_views_by_period(pool, article_id, period, from, to))
+        .await?
+        .into_iter()
+        .map(PeriodViewsDto::from)
+        .collect();
+    Ok(HttpResponse::Ok().json(StatsDto {
+        range: StatsRangeDto::new(from, to),
+        items: views,
+    }))
+}
+
+pub async fn stats_top_articles(
+    app_state: web::Data<AppState>,
+    query: web::Query<StatsQuery>,
+) -> Result<HttpResponse, Error> {
+    let (from, to) = stats_date_range(&query)?;
+    let max = query
+        .max
+        .map(|m| if m > 100 { 100 } else { m })
+        .unwrap_or(MAX_TOP_ARTICLES);
+    let mut articles: Vec<ArticleViewsDto> = app_state
+        .stats_db(move |pool| db::top_articles(pool, from, to, max))
+        .await?
+        .into_iter()
+        .map(ArticleViewsDto::from)
+        .collect();
+    // Titles are in the main database:
+    let ids: Vec<i32> = articles.iter().map(|a| a.article_id).collect();
+    let titles = app_state
+        .db(move |pool| db::article_titles(pool, &ids))
+        .await?;
+    for article in articles.iter_mut() {
+        article.title = titles
+            .iter()
+            .find(|(id, _)| *id == article.article_id)
+            .map(|(_, title)| title.clone());
+    }
+    Ok(HttpResponse::Ok().json(StatsDto {
+        range: StatsRangeDto::new(from, to),
+        items: articles,
+    }))
+}
+
+pub async fn stats_locations(
+    app_state: web::Data<AppState>,
+    query: web::Query<StatsQuery>,
+) -> Result<HttpResponse, Error> {
+    let (from, to) = stats_date_range(&query)?;
+    let level = match query.by.as_deref() {
+        None | Some("country") => db::LocationLevel::Country,
+        Some("region") => db::LocationLevel::Region,
+        Some("city") => db::LocationLevel::City,
+        Some(_) => {
+            return Err(Error::BadRequest(String::from(
+                "Breakdown has to be by country, region or city",
+            )))
+        }
+    };
+    let article_id = query.article_id;
+    let locations: Vec<LocationViewsDto> = app_state
+        .stats_db(move |pool| db::views_by_location(pool, level, from, to, article_id))
+        .await?
+        .into_iter()
+        .map(LocationViewsDto::from)
+        .collect();
+    Ok(HttpResponse::Ok().json(StatsDto {
+        range: StatsRangeDto::new(from, to),
+        items: locations,
+    }))
+}
+
+pub async fn stats_visitors(
+    app_state: web::Data<AppState>,
+    query: web::Query<StatsQuery>,
+) -> Result<HttpResponse, Error> {
+    let (from, to) = stats_date_range(&query)?;
+    let article_id = query.article_id;
+    let summary = app_state
+        .stats_db(move |pool| db::visitors_summary(pool, from, to, article_id))
+        .await?;
+    Ok(HttpResponse::Ok().json(StatsDto {
+        range: StatsRangeDto::new(from, to),
+        items: VisitorsSummaryDto::from(summary),
+    }))
+}
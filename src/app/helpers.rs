@@ -1,3 +1,5 @@
+use crate::db::PageStart;
+use actix_web::dev::{ConnectionInfo, HttpResponseBuilder};
 use actix_web::HttpRequest;
 use lazy_static::lazy_static;
 use regex::Regex;
@@ -20,6 +22,24 @@ pub fn header_value(req: &HttpRequest, header_name: &str) -> String {
 // the Actix ConnectionInfo, but I have made it so that
 // the stats service absolutely expects an IP address.
 pub fn real_ip_addr(req: &HttpRequest) -> Option<IpAddr> {
+    real_ip_addr_from_info(&req.connection_info())
+}
+
+// Absolute URLs in responses start with the configured
+// API root, or with whatever the request was sent to.
+pub fn api_base_url(req: &HttpRequest, api_root: Option<&str>) -> String {
+    match api_root {
+        Some(api_root) => api_root.trim_end_matches('/').to_string(),
+        None => {
+            let info = req.connection_info();
+            format!("{}://{}", info.scheme(), info.host())
+        }
+    }
+}
+
+// Middlewares don't have an HttpRequest yet, but they do
+// have the connection info.
+pub fn real_ip_addr_from_info(info: &ConnectionInfo) -> Option<IpAddr> {
     // Since there's no way to define a const that uses
     // the heap, we need that weird lazy_static crate.
     // Why isn't this built into the language? Probably
@@ -31,8 +51,7 @@ pub fn real_ip_addr(req: &HttpRequest) -> Option<IpAddr> {
         static ref PORT_REGEX: Regex = Regex::new(r"(.+):\d+$").unwrap();
     }
 
-    req.connection_info()
-        .realip_remote_addr()
+    info.realip_remote_addr()
         .map(|ip| {
             // Convert the result into an option:
             IpAddr::from_str(&PORT_REGEX.replace(ip, "$1")).ok()
@@ -63,6 +82,209 @@ pub fn replace_start_in_pagination_path(path: &str, start: usize) -> Cow<str> {
     REQ_REGEX.replace(path, &format!("$1/{}", start))
 }
 
+// Cursors are supposed to be opaque to clients, they're
+// just the page start encoded in URL safe base64.
+pub fn encode_cursor(start: PageStart) -> String {
+    let raw = match start {
+        PageStart::Offset(offset) => format!("o{}", offset),
+        PageStart::After(id) => format!("a{}", id),
+        PageStart::Before(id) => format!("b{}", id),
+    };
+    base64::encode_config(raw, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn decode_cursor(cursor: &str) -> Option<PageStart> {
+    let raw = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+    let raw = String::from_utf8(raw).ok()?;
+    // Can't slice at 1 if the first char is multibyte.
+    if !raw.is_char_boundary(1) {
+        return None;
+    }
+    let (kind, value) = raw.split_at(1);
+    match kind {
+        "o" => value.parse().ok().map(PageStart::Offset),
+        "a" => value.parse().ok().map(PageStart::After),
+        "b" => value.parse().ok().map(PageStart::Before),
+        _ => None,
+    }
+}
+
+// Pages are fetched with one extra item to find out if
+// there's anything after them (or before them, for Before
+// pages). Removes that item and returns whether there are
+// previous and next pages.
+pub fn trim_page<T>(items: &mut Vec<T>, max: usize, start: PageStart) -> (bool, bool) {
+    let more = items.len() > max;
+    match start {
+        PageStart::Before(_) => {
+            if more {
+                items.remove(0);
+            }
+            (more, true)
+        }
+        PageStart::After(_) => {
+            items.truncate(max);
+            (true, more)
+        }
+        PageStart::Offset(offset) => {
+            items.truncate(max);
+            (offset > 0, more)
+        }
+    }
+}
+
+// Where listings expect their offset.
+pub enum OffsetParam {
+    // Last segment of the path, e.g. /articles-starting-from/{start}
+    Path,
+    // Query string parameter with that name.
+    Query(&'static str),
+}
+
+/**
+ * Pagination headers for listings: an RFC 5988 "link"
+ * header with first, prev, next and last relations, and
+ * X-Total-Count. Links keep all of the query params of
+ * the request. They use cursors when the listing was
+ * requested with one, offsets otherwise.
+ */
+pub struct Pagination {
+    pub max: usize,
+    pub total: usize,
+    pub prev: Option<PageStart>,
+    pub next: Option<PageStart>,
+    pub with_cursor: bool,
+}
+
+impl Pagination {
+    // has_prev and has_next come from trim_page, first and
+    // last are the ids of the first and last items of the
+    // page (keyset pages start from these).
+    pub fn new(
+        start: PageStart,
+        max: usize,
+        total: usize,
+        with_cursor: bool,
+        (has_prev, has_next): (bool, bool),
+        (first, last): (i32, i32),
+    ) -> Self {
+        let (prev, next) = if with_cursor {
+            (PageStart::Before(first), PageStart::After(last))
+        } else {
+            let offset = match start {
+                PageStart::Offset(offset) => offset,
+                _ => 0,
+            };
+            (
+                PageStart::Offset(offset.saturating_sub(max)),
+                PageStart::Offset(offset + max),
+            )
+        };
+        Self {
+            max,
+            total,
+            prev: if has_prev { Some(prev) } else { None },
+            next: if has_next { Some(next) } else { None },
+            with_cursor,
+        }
+    }
+
+    pub fn prev_cursor(&self) -> Option<String> {
+        self.prev.map(encode_cursor)
+    }
+
+    pub fn next_cursor(&self) -> Option<String> {
+        self.next.map(encode_cursor)
+    }
+
+    fn last_offset(&self) -> usize {
+        if self.max == 0 || self.total == 0 {
+            0
+        } else {
+            (self.total - 1) / self.max * self.max
+        }
+    }
+
+    fn url(
+        &self,
+        req: &HttpRequest,
+        base: &str,
+        offset_param: &OffsetParam,
+        start: PageStart,
+    ) -> String {
+        // Original params minus the ones we're setting:
+        let mut params: Vec<(String, String)> =
+            serde_urlencoded::from_str(req.query_string()).unwrap_or_default();
+        params.retain(|(k, _)| {
+            k != "cursor"
+                && k != "max"
+                && match offset_param {
+                    OffsetParam::Query(name) => k != name,
+                    OffsetParam::Path => true,
+                }
+        });
+        params.push((String::from("max"), self.max.to_string()));
+        let mut path = Cow::Borrowed(req.path());
+        match (self.with_cursor, start, offset_param) {
+            (true, _, _) => params.push((String::from("cursor"), encode_cursor(start))),
+            (false, PageStart::Offset(offset), OffsetParam::Path) => {
+                path = replace_start_in_pagination_path(req.path(), offset)
+            }
+            (false, PageStart::Offset(offset), OffsetParam::Query(name)) => {
+                params.push((name.to_string(), offset.to_string()))
+            }
+            // Keyset pages always come with a cursor.
+            (false, _, _) => params.push((String::from("cursor"), encode_cursor(start))),
+        }
+        format!(
+            "{}{}?{}",
+            base,
+            path,
+            serde_urlencoded::to_string(&params).unwrap_or_default()
+        )
+    }
+
+    pub fn link_header(
+        &self,
+        req: &HttpRequest,
+        api_root: Option<&str>,
+        offset_param: OffsetParam,
+    ) -> String {
+        let base = api_base_url(req, api_root);
+        let mut links = vec![(PageStart::Offset(0), "first")];
+        if let Some(prev) = self.prev {
+            links.push((prev, "prev"));
+        }
+        if let Some(next) = self.next {
+            links.push((next, "next"));
+        }
+        links.push((PageStart::Offset(self.last_offset()), "last"));
+        links
+            .into_iter()
+            .map(|(start, rel)| {
+                format!(
+                    "<{}>; rel=\"{}\"",
+                    self.url(req, &base, &offset_param, start),
+                    rel
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    pub fn set_headers(
+        &self,
+        response: &mut HttpResponseBuilder,
+        req: &HttpRequest,
+        api_root: Option<&str>,
+        offset_param: OffsetParam,
+    ) {
+        response
+            .set_header("link", self.link_header(req, api_root, offset_param))
+            .set_header("x-total-count", self.total.to_string());
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -80,4 +302,61 @@ mod test {
         let expected = "/v2/articles-starting-from/100";
         assert_eq!(expected, replace_start_in_pagination_path(sut, 100));
     }
+
+    #[test]
+    fn cursors_can_be_decoded() {
+        for start in &[
+            PageStart::Offset(20),
+            PageStart::After(123),
+            PageStart::Before(-1),
+        ] {
+            assert_eq!(Some(*start), decode_cursor(&encode_cursor(*start)));
+        }
+        assert_eq!(None, decode_cursor("not a cursor"));
+        assert_eq!(None, decode_cursor(""));
+    }
+
+    #[test]
+    fn trim_page_removes_extra_item() {
+        let mut sut = vec![1, 2, 3];
+        assert_eq!((false, true), trim_page(&mut sut, 2, PageStart::Offset(0)));
+        assert_eq!(vec![1, 2], sut);
+        let mut sut = vec![1, 2, 3];
+        assert_eq!((true, true), trim_page(&mut sut, 2, PageStart::Before(4)));
+        assert_eq!(vec![2, 3], sut);
+        let mut sut = vec![1, 2];
+        assert_eq!((true, false), trim_page(&mut sut, 2, PageStart::After(0)));
+        assert_eq!(vec![1, 2], sut);
+    }
+
+    #[test]
+    fn pagination_links_keep_query_params() {
+        let req = actix_web::test::TestRequest::with_uri(
+            "/articles-starting-from/2?tags=a%20b,c&max=2&order=asc",
+        )
+        .header("host", "api.test")
+        .to_http_request();
+        let sut = Pagination::new(PageStart::Offset(2), 2, 5, false, (true, true), (3, 4));
+        assert_eq!(
+            "<http://api.test/articles-starting-from/0?tags=a+b%2Cc&order=asc&max=2>; rel=\"first\", \
+            <http://api.test/articles-starting-from/0?tags=a+b%2Cc&order=asc&max=2>; rel=\"prev\", \
+            <http://api.test/articles-starting-from/4?tags=a+b%2Cc&order=asc&max=2>; rel=\"next\", \
+            <http://api.test/articles-starting-from/4?tags=a+b%2Cc&order=asc&max=2>; rel=\"last\"",
+            sut.link_header(&req, None, OffsetParam::Path)
+        );
+    }
+
+    #[test]
+    fn pagination_links_use_cursors() {
+        let req = actix_web::test::TestRequest::with_uri("/comments-starting-from/12?start=3&cursor=")
+            .to_http_request();
+        let sut = Pagination::new(PageStart::Offset(0), 2, 3, true, (false, true), (5, 9));
+        assert_eq!(None, sut.prev_cursor());
+        assert_eq!(Some(PageStart::After(9)), sut.next);
+        let next_cursor = sut.next_cursor().unwrap();
+        let link = sut.link_header(&req, Some("https://api.site.tld/"), OffsetParam::Query("start"));
+        assert!(link.starts_with("<https://api.site.tld/comments-starting-from/12?max=2&cursor="));
+        assert!(link.contains(&format!("max=2&cursor={}>; rel=\"next\"", next_cursor)));
+        assert!(!link.contains("start="));
+    }
 }
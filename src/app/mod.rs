@@ -1,18 +1,26 @@
 use actix_cors::Cors;
-use actix_web::{middleware, web, App, HttpResponse, HttpServer};
+use actix_web::{middleware, web, App, HttpServer};
 use article_import::ImportService;
 use color_eyre::Result;
-use eyre::WrapErr;
+use eyre::{eyre, WrapErr};
+use guards::{ApiTokens, Scope, TokenGuard};
 use handlebars::Handlebars;
-use log::{debug, error};
-use r2d2_sqlite::{self, SqliteConnectionManager};
-use rate_limiter::BasicRateLimiter;
-use std::sync::RwLock;
+use log::{debug, info};
+use rand::{thread_rng, Rng};
+use rate_limiter::{IpRateLimiter, KeyedRateLimiter, RateLimit};
+use related::RelatedArticles;
+use scheduler::PublishScheduler;
+use std::sync::{Arc, Mutex};
 // I think we have to add crate here because
 // of the other crate named "config" that we
 // use as a dependency.
 use crate::config::{Config, SiteInfo};
-use crate::db::Pool;
+use crate::db::{self, Pool};
+use error::{map_db_error, Error};
+use crate::db::migrations::{migrate, Database};
+use crate::db::RawStatsAction;
+use crate::stats::retention::start_rollup_task;
+use crate::stats::visit_filter::VisitFilter;
 use crate::stats::StatsService;
 mod article_import;
 mod dtos;
@@ -21,63 +29,50 @@ mod guards;
 mod handlers;
 mod helpers;
 mod rate_limiter;
-
-// IP addresses allowed to make special calls (like the /rss one).
-// Should probably be in the config instead.
-pub const ALLOWED_IP_ADDRESSES: [&'static str; 2] = ["127.0.0.1", "::1"];
+mod related;
+mod scheduler;
 
 // Declare app state struct:
 pub struct AppState {
   pub pool: Pool,
   pub stats_service: StatsService,
-  pub rate_limiter: RwLock<BasicRateLimiter>,
+  // The stats reporting endpoints read from the stats
+  // database directly.
+  pub stats_pool: Pool,
   pub import_service: ImportService,
+  pub publish_scheduler: PublishScheduler,
+  pub related_articles: RelatedArticles,
   pub site_info: SiteInfo,
+  pub comment_moderation: bool,
+  pub preview_secret: Vec<u8>,
+  // In seconds.
+  pub preview_link_duration: i64,
+  pub trash_retention_days: u32,
+  pub search_stemming: bool,
 }
 
-// This shouldn't be that weird I'm sorry. These functions
-// could be moved elsewhere to not be directly in AppState.
 impl AppState {
-  pub fn check_rate_limit(&self) -> bool {
-    let (needs_update, is_locked) = self.rate_limiter_needs_update();
-    if needs_update {
-      // Get a lock on the rate limiter:
-      match self.rate_limiter.write() {
-        Ok(mut rl) => return rl.update(),
-        Err(e) => {
-          error!(
-            "Could not get a write handle on the \
-          rate limiter, SHOULD NEVER HAPPEN - {}",
-            e
-          );
-        }
-      }
-    }
-    return is_locked;
+  // Handlers must go through these to reach the databases,
+  // the queries run on the blocking thread pool.
+  pub async fn db<F, T>(&self, f: F) -> std::result::Result<T, Error>
+  where
+    F: FnOnce(&Pool) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+  {
+    db::run_blocking(&self.pool, f).await.map_err(map_db_error)
   }
 
-  // Returns tuple: "needs update" first, then the current
-  // is_locked value.
-  fn rate_limiter_needs_update(&self) -> (bool, bool) {
-    match self.rate_limiter.read() {
-      Ok(rl) => (
-        !rl.is_locked() || (rl.is_locked() && rl.is_expired()),
-        rl.is_locked(),
-      ),
-      Err(e) => {
-        // I decided to ignore possible weird rate limiter lock
-        // errors which should never happen.
-        error!(
-          "Could not get a read handle on the rate limiter - \
-          SHOULD NEVER HAPPEN - {}",
-          e
-        );
-        (false, false)
-      }
-    }
+  pub async fn stats_db<F, T>(&self, f: F) -> std::result::Result<T, Error>
+  where
+    F: FnOnce(&Pool) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+  {
+    db::run_blocking(&self.stats_pool, f).await.map_err(map_db_error)
   }
 }
 
+const MIN_DB_POOL_SIZE: u32 = 2;
+
 // Function to start the server.
 // Has to be async because there should be a .await at the end.
 // I'm not certain it's even allowed to put this all here as
@@ -86,24 +81,74 @@ impl AppState {
 pub async fn run() -> Result<()> {
   let config = Config::from_env().expect("Configuration (environment or .env file) is missing");
   debug!("Current config: {:?}", config);
-  let manager = SqliteConnectionManager::file(&config.db_path);
-  let pool = Pool::new(manager).expect("Database connection failed");
+  // The stats thread would leave no connection for the
+  // stats endpoints otherwise.
+  if config.db_pool_size < MIN_DB_POOL_SIZE {
+    return Err(eyre!(
+      "DB_POOL_SIZE has to be at least {}, the stats thread keeps one connection",
+      MIN_DB_POOL_SIZE
+    ));
+  }
+  let pool = db::open_pool(&config.db_path, config.db_pool_size, config.db_busy_timeout)
+    .expect("Database connection failed");
 
   // Declare the StatsService, start its thread
   // It has its own separate database.
-  let manager_stats = SqliteConnectionManager::file(&config.stats_db_path);
-  let pool_stats = Pool::new(manager_stats).expect("Could not connect to stats database");
+  // The stats thread holds on to one of these connections
+  // for good.
+  let pool_stats = db::open_pool(
+    &config.stats_db_path,
+    config.db_pool_size,
+    config.db_busy_timeout,
+  )
+  .expect("Could not connect to stats database");
+
+  // Databases that don't exist yet are created here too.
+  if config.db_auto_migrate {
+    migrate(&pool, Database::Main, false).context("Migrate main database")?;
+    migrate(&pool_stats, Database::Stats, false).context("Migrate stats database")?;
+  }
   let stats_service = StatsService::open(
     &pool_stats,
     &config.wordlist_path,
     &config.iploc_path,
     config.message_queue_size,
+    VisitFilter::new(
+      config.bot_patterns(),
+      config.stats_drop_bots,
+      config.excluded_ips(),
+    ),
   )?;
 
+  // The stats rollup only runs when a retention period
+  // is configured.
+  if let Some(retention_days) = config.stats_retention_days {
+    start_rollup_task(
+      pool_stats.clone(),
+      retention_days,
+      if config.stats_delete_raw {
+        RawStatsAction::Delete
+      } else {
+        RawStatsAction::Anonymize
+      },
+      config.stats_rollup_interval,
+    );
+  }
+
   // Declare the import service, crash immediately
   // if import directory is not writable:
-  let import_service =
-    ImportService::open(&config.import_path).expect("Fatal: import directory is not writable");
+  // Scheduled articles that were due while the server
+  // was down get published right away.
+  // Both can change articles, which has to reset the
+  // related articles cache.
+  let related_articles = RelatedArticles::default();
+  let publish_scheduler = PublishScheduler::start(pool.clone(), related_articles.clone());
+  let import_service = ImportService::open(
+    &config.import_path,
+    publish_scheduler.clone(),
+    related_articles.clone(),
+  )
+  .expect("Fatal: import directory is not writable");
 
   // Delcare the template system, currently using
   // handlebars:
@@ -120,17 +165,54 @@ pub async fn run() -> Result<()> {
   // we'll be destroying "config" by moving it into
   // app_state as another struct called SiteInfo.
   let bind_address = config.bind_address.clone();
+  let allowed_ips = config.allowed_ips();
+  let comment_moderation = config.comment_moderation;
+  let preview_secret = match &config.preview_secret {
+    Some(secret) => secret.as_bytes().to_vec(),
+    None => {
+      info!("PREVIEW_SECRET is not set, preview links won't survive a restart");
+      thread_rng().gen::<[u8; 32]>().to_vec()
+    }
+  };
+  let preview_link_duration = i64::from(config.preview_link_hours) * 3600;
+  let trash_retention_days = config.trash_retention_days;
+  let search_stemming = config.search_stemming;
+
+  // Rate limiters are per client IP address, with a
+  // separate budget for every class of endpoints.
+  // They're shared by all the workers.
+  let rate_limiters = RateLimiters {
+    comments: Arc::new(Mutex::new(KeyedRateLimiter::new(
+      config.rl_comments_max_requests,
+      config.rl_max_requests_time,
+      config.rl_block_duration,
+      config.rl_max_clients,
+    ))),
+    search: Arc::new(Mutex::new(KeyedRateLimiter::new(
+      config.rl_search_max_requests,
+      config.rl_max_requests_time,
+      config.rl_block_duration,
+      config.rl_max_clients,
+    ))),
+  };
+
+  // The API tokens middleware needs its own copy of the
+  // pool to look the tokens up.
+  let tokens_pool = pool.clone();
 
   let app_state = web::Data::new(AppState {
     pool,
     stats_service,
+    stats_pool: pool_stats,
     import_service,
-    rate_limiter: RwLock::new(BasicRateLimiter::new(
-      config.rl_max_requests,
-      config.rl_max_requests_time,
-      config.rl_block_duration,
-    )),
+    publish_scheduler,
+    related_articles,
     site_info: config.into(),
+    comment_moderation,
+    preview_secret,
+    preview_link_duration,
+    trash_retention_days,
+    search_stemming,
   });
 
   HttpServer::new(move || {
@@ -161,7 +243,10 @@ pub async fn run() -> Result<()> {
       // to have separate scopes (URL scopes) for the CORS-wrapped and
       // no-CORS endpoints.
       .wrap(cors)
-      .configure(base_endpoints_config)
+      // Like every middleware of the app, runs before the
+      // routing, which is where the guards are checked.
+      .wrap(ApiTokens::new(tokens_pool.clone()))
+      .configure(|cfg| base_endpoints_config(cfg, &allowed_ips, &rate_limiters))
       .default_service(web::route().to(handlers::not_found))
   })
   .bind(bind_address)?
@@ -170,16 +255,38 @@ pub async fn run() -> Result<()> {
   .context("Start Actix web server")
 }
 
+#[derive(Clone)]
+struct RateLimiters {
+  comments: IpRateLimiter,
+  search: IpRateLimiter,
+}
+
 // Route configuration:
-fn base_endpoints_config(cfg: &mut web::ServiceConfig) {
-  // Create the guard that cause protected endpoints to respond with a 404
-  // when the client IP address isn't allowed.
-  let ip_guard = guards::IPRestrictedGuard::new(&ALLOWED_IP_ADDRESSES);
+fn base_endpoints_config(
+  cfg: &mut web::ServiceConfig,
+  allowed_ips: &Option<Vec<String>>,
+  rate_limiters: &RateLimiters,
+) {
+  // Create the guards that cause protected endpoints to respond with a 404
+  // when the client doesn't have an API token with the right scope (or
+  // when the client IP address isn't allowed).
+  let token_guard = |scope: Scope| TokenGuard::new(scope, allowed_ips.clone());
 
   cfg
     .route("/", web::get().to(handlers::index))
     .route("/tags", web::get().to(handlers::tags))
+    .route("/tags/{name}/articles", web::get().to(handlers::tag_articles))
+    .route("/authors", web::get().to(handlers::authors))
+    .route("/authors/{author_id}", web::get().to(handlers::author))
+    .route(
+      "/authors/{author_id}/articles",
+      web::get().to(handlers::author_articles),
+    )
     .route("/article/{articleUrl}", web::get().to(handlers::article))
+    .route(
+      "/article/{articleUrl}/related",
+      web::get().to(handlers::related_articles),
+    )
     .route(
       "/articles-starting-from/{start}",
       web::get().to(handlers::articles_starting_from),
@@ -192,32 +299,234 @@ fn base_endpoints_config(cfg: &mut web::ServiceConfig) {
       "/comments-starting-from/{article_url}",
       web::get().to(handlers::comments_starting_from),
     )
-    .route("/comments", web::post().to(handlers::post_comment))
+    // Rate limited endpoints have to be resources, the
+    // middleware can't wrap a single route.
+    .service(
+      web::resource("/comments")
+        .wrap(RateLimit::new(rate_limiters.comments.clone()))
+        .route(web::post().to(handlers::post_comment)),
+    )
     .route("/last-comment", web::get().to(handlers::last_comment))
-    .route("/import-articles", web::get().to(handlers::import_article))
     .route(
-      "/articles/search",
-      web::post().to(handlers::search_articles),
+      "/import-articles",
+      web::get()
+        .guard(token_guard(Scope::Import))
+        .to(handlers::import_article),
+    )
+    .service(
+      web::resource("/articles/search")
+        .wrap(RateLimit::new(rate_limiters.search.clone()))
+        .route(web::post().to(handlers::search_articles)),
+    )
+    .service(
+      web::resource("/articles/search/suggest")
+        .wrap(RateLimit::new(rate_limiters.search.clone()))
+        .route(web::post().to(handlers::search_suggestions)),
+    )
+    .route(
+      "/rss",
+      web::get().guard(token_guard(Scope::Rss)).to(handlers::rss),
     )
-    .route("/rss", web::get().guard(ip_guard.clone()).to(handlers::rss))
     .route(
       "/gimme-sitemap",
-      web::get().guard(ip_guard.clone()).to(handlers::sitemap),
+      web::get()
+        .guard(token_guard(Scope::Sitemap))
+        .to(handlers::sitemap),
     )
     .route(
       "/rebuild-indexes",
       web::get()
-        .guard(ip_guard.clone())
+        .guard(token_guard(Scope::Reindex))
         .to(handlers::rebuild_indexes),
     )
     .route(
-      "/publish/{article_id}", 
-      web::get().guard(ip_guard.clone())
+      "/publish/{article_id}",
+      web::get()
+        .guard(token_guard(Scope::Publish))
         .to(handlers::refresh_date_and_publish),
     )
     .route(
       "/render-article/{articleUrl}",
       web::get().to(handlers::render_article),
     )
-    .route("/robots.txt", web::get().to(handlers::robots));
+    // Preview links are signed, no token required.
+    .route("/preview/{article_id}", web::get().to(handlers::preview_article))
+    .route(
+      "/render-preview/{article_id}",
+      web::get().to(handlers::render_preview),
+    )
+    .route("/robots.txt", web::get().to(handlers::robots))
+    .service(
+      // Guards have to be on every single route, a guard on the
+      // scope would make the other /admin routes unreachable.
+      web::scope("/admin")
+        .route(
+          "/articles",
+          web::post()
+            .guard(token_guard(Scope::Import))
+            .to(handlers::admin_insert_article),
+        )
+        .route(
+          "/articles/{article_id}",
+          web::patch()
+            .guard(token_guard(Scope::Import))
+            .to(handlers::admin_update_article),
+        )
+        .route(
+          "/articles/{article_id}",
+          web::delete()
+            .guard(token_guard(Scope::Import))
+            .to(handlers::admin_delete_article),
+        )
+        .route(
+          "/articles/{article_id}/revisions",
+          web::get()
+            .guard(token_guard(Scope::Import))
+            .to(handlers::admin_article_revisions),
+        )
+        .route(
+          "/articles/{article_id}/revisions/{revision_id}",
+          web::get()
+            .guard(token_guard(Scope::Import))
+            .to(handlers::admin_article_revision),
+        )
+        .route(
+          "/articles/{article_id}/revisions/{revision_id}/diff",
+          web::get()
+            .guard(token_guard(Scope::Import))
+            .to(handlers::admin_article_revision_diff),
+        )
+        .route(
+          "/articles/{article_id}/revisions/{revision_id}/restore",
+          web::post()
+            .guard(token_guard(Scope::Import))
+            .to(handlers::admin_restore_article_revision),
+        )
+        .route(
+          "/trash",
+          web::get()
+            .guard(token_guard(Scope::Import))
+            .to(handlers::admin_trash),
+        )
+        .route(
+          "/trash/purge",
+          web::post()
+            .guard(token_guard(Scope::Import))
+            .to(handlers::admin_purge_trash),
+        )
+        .route(
+          "/trash/{article_id}/restore",
+          web::post()
+            .guard(token_guard(Scope::Import))
+            .to(handlers::admin_restore_trashed_article),
+        )
+        .route(
+          "/tags",
+          web::post()
+            .guard(token_guard(Scope::Tags))
+            .to(handlers::admin_insert_tag),
+        )
+        .route(
+          "/tags/{tag_id}",
+          web::patch()
+            .guard(token_guard(Scope::Tags))
+            .to(handlers::admin_update_tag),
+        )
+        .route(
+          "/tags/{tag_id}",
+          web::delete()
+            .guard(token_guard(Scope::Tags))
+            .to(handlers::admin_delete_tag),
+        )
+        .route(
+          "/tags/{tag_id}/merge",
+          web::post()
+            .guard(token_guard(Scope::Tags))
+            .to(handlers::admin_merge_tag),
+        )
+        .route(
+          "/articles/{article_id}/schedule",
+          web::put()
+            .guard(token_guard(Scope::Publish))
+            .to(handlers::admin_schedule_article),
+        )
+        .route(
+          "/articles/{article_id}/schedule",
+          web::delete()
+            .guard(token_guard(Scope::Publish))
+            .to(handlers::admin_unschedule_article),
+        )
+        .route(
+          "/articles/{article_id}/preview-link",
+          web::get()
+            .guard(token_guard(Scope::Publish))
+            .to(handlers::admin_preview_link),
+        )
+        .route(
+          "/authors",
+          web::post()
+            .guard(token_guard(Scope::Authors))
+            .to(handlers::admin_insert_author),
+        )
+        .route(
+          "/authors/{author_id}",
+          web::patch()
+            .guard(token_guard(Scope::Authors))
+            .to(handlers::admin_update_author),
+        )
+        .route(
+          "/authors/{author_id}",
+          web::delete()
+            .guard(token_guard(Scope::Authors))
+            .to(handlers::admin_delete_author),
+        )
+        .route(
+          "/comments/pending",
+          web::get()
+            .guard(token_guard(Scope::Moderate))
+            .to(handlers::pending_comments),
+        )
+        .route(
+          "/comments/{comment_id}/approve",
+          web::post()
+            .guard(token_guard(Scope::Moderate))
+            .to(handlers::approve_comment),
+        )
+        .route(
+          "/comments/{comment_id}/reject",
+          web::post()
+            .guard(token_guard(Scope::Moderate))
+            .to(handlers::reject_comment),
+        )
+        .route(
+          "/comments/{comment_id}",
+          web::delete()
+            .guard(token_guard(Scope::Moderate))
+            .to(handlers::delete_comment),
+        )
+        .route(
+          "/stats/articles/{article_id}/views",
+          web::get()
+            .guard(token_guard(Scope::Stats))
+            .to(handlers::stats_article_views),
+        )
+        .route(
+          "/stats/top-articles",
+          web::get()
+            .guard(token_guard(Scope::Stats))
+            .to(handlers::stats_top_articles),
+        )
+        .route(
+          "/stats/locations",
+          web::get()
+            .guard(token_guard(Scope::Stats))
+            .to(handlers::stats_locations),
+        )
+        .route(
+          "/stats/visitors",
+          web::get()
+            .guard(token_guard(Scope::Stats))
+            .to(handlers::stats_visitors),
+        ),
+    );
 }
@@ -1,81 +1,233 @@
-use crate::utils::time_utils::current_timestamp;
-/*
-// Max value fot the counter before blocking:
-pub const MAX_REQUESTS: u32 = 150;
-// We rate limit if max requests is reached in that timeframe (seconds):
-pub const MAX_REQUESTS_TIME: u32 = 60;
-// Block duration in seconds:
-pub const BLOCK_TIME: u32 = 60;
-*/
+use super::error::Error;
+use super::helpers::real_ip_addr_from_info;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use futures::future::{ok, Either, Ready};
+use log::warn;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 /**
- * Just count the amount of times sensible endpoints are
- * being called per unit of time, supposed to block them
- * entirely for a specific "block time" when that happens.
+ * Token bucket for a single client. The bucket holds up
+ * to "max_requests" tokens and refills entirely over
+ * "max_requests_time". A client that runs out of tokens
+ * gets blocked for "block_duration".
  */
-pub struct BasicRateLimiter {
-  counter: u32,
-  last_update: i64,
-  is_limited: bool,
+#[derive(Debug)]
+struct Bucket {
+  tokens: f64,
+  last_update: Instant,
+  blocked_until: Option<Instant>
+}
+
+/**
+ * Rate limiter with a separate token bucket per key
+ * (the client IP address in practice). The amount of
+ * keys is bounded: idle buckets (full and not blocked)
+ * get evicted when we're out of room, and if there
+ * aren't any, the least recently used bucket goes.
+ */
+pub struct KeyedRateLimiter<K> {
+  buckets: HashMap<K, Bucket>,
   max_requests: u32,
-  // In seconds
-  max_requests_time: u32,
-  // In seconds
-  block_duration: u32
+  max_requests_time: Duration,
+  block_duration: Duration,
+  max_clients: usize
 }
 
-impl BasicRateLimiter {
+impl<K: Eq + Hash + Clone> KeyedRateLimiter<K> {
 
   pub fn new(
-    max_requests: u32, 
-    max_requests_time: u32, 
-    block_duration: u32
+    max_requests: u32,
+    // In seconds
+    max_requests_time: u32,
+    // In seconds
+    block_duration: u32,
+    max_clients: usize
   ) -> Self {
     Self {
-      counter: 0,
-      last_update: current_timestamp(),
-      is_limited: false,
+      buckets: HashMap::new(),
       max_requests,
-      max_requests_time,
-      block_duration
+      max_requests_time: Duration::from_secs(max_requests_time.into()),
+      block_duration: Duration::from_secs(block_duration.into()),
+      max_clients
     }
   }
- 
-  pub fn is_locked(&self) -> bool {
-    self.is_limited
+
+  // Returns true if the client is rate limited.
+  pub fn check(&mut self, key: &K) -> bool {
+    self.check_at(key, Instant::now())
   }
 
-  pub fn is_expired(&self) -> bool {
-    // If currently locked, check if past block_duration.
-    // Check if past max_request_time otherwise.
-    if self.is_locked() {
-      current_timestamp() - self.last_update >= self.block_duration.into()
+  // Split from check() so that tests can control time.
+  fn check_at(&mut self, key: &K, now: Instant) -> bool {
+    if !self.buckets.contains_key(key) {
+      if self.buckets.len() >= self.max_clients {
+        self.evict(now);
+      }
+      self.buckets.insert(key.clone(), Bucket {
+        tokens: self.max_requests.into(),
+        last_update: now,
+        blocked_until: None
+      });
+    }
+    let max_tokens: f64 = self.max_requests.into();
+    let refill_rate = self.refill_rate();
+    let block_duration = self.block_duration;
+    // Can't fail, we just made sure the key is there.
+    let bucket = self.buckets.get_mut(key).unwrap();
+
+    if let Some(blocked_until) = bucket.blocked_until {
+      if now < blocked_until {
+        return true;
+      }
+      // Block has expired, start over with a full bucket:
+      bucket.blocked_until = None;
+      bucket.tokens = max_tokens;
+      bucket.last_update = now;
+    }
+
+    let elapsed = now.saturating_duration_since(bucket.last_update);
+    bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * refill_rate)
+      .min(max_tokens);
+    bucket.last_update = now;
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      false
     } else {
-      current_timestamp() - self.last_update >= self.max_requests_time.into()
+      bucket.blocked_until = Some(now + block_duration);
+      true
     }
   }
 
-  // I'm trying to finely separate what is mutable and what isn't.
-  // Returns true if we're locked, false otherwise.
-  pub fn update(&mut self) -> bool {
-    // If we're locked, check if lock has expired:
-    if self.is_expired() {
-      // Reset:
-      self.counter = 0;
-      self.last_update = current_timestamp();
-      self.is_limited = false;
+  // Tokens per second.
+  fn refill_rate(&self) -> f64 {
+    let secs = self.max_requests_time.as_secs_f64();
+    if secs > 0.0 {
+      f64::from(self.max_requests) / secs
     } else {
-      self.counter += 1;
-      // Are we above the rate limit?
-      if self.counter >= self.max_requests {
-        self.is_limited = true;
-        // Reset last_update:
-        self.last_update = current_timestamp();
+      f64::INFINITY
+    }
+  }
+
+  fn is_idle(&self, bucket: &Bucket, now: Instant) -> bool {
+    match bucket.blocked_until {
+      Some(blocked_until) => now >= blocked_until,
+      None => {
+        let missing = f64::from(self.max_requests) - bucket.tokens;
+        now.saturating_duration_since(bucket.last_update).as_secs_f64()
+          * self.refill_rate() >= missing
+      }
+    }
+  }
+
+  fn evict(&mut self, now: Instant) {
+    let idle: Vec<K> = self.buckets
+      .iter()
+      .filter(|(_, b)| self.is_idle(b, now))
+      .map(|(k, _)| k.clone())
+      .collect();
+    for k in idle {
+      self.buckets.remove(&k);
+    }
+    if self.buckets.len() >= self.max_clients {
+      let oldest = self.buckets
+        .iter()
+        .min_by_key(|(_, b)| b.last_update)
+        .map(|(k, _)| k.clone());
+      if let Some(k) = oldest {
+        self.buckets.remove(&k);
+      }
+    }
+  }
+
+}
+
+pub type IpRateLimiter = Arc<Mutex<KeyedRateLimiter<IpAddr>>>;
+
+// Actix middleware applying one of the limiters to a
+// resource. Every endpoint class (comments, search...)
+// gets its own limiter, and thus its own budget.
+pub struct RateLimit {
+  limiter: IpRateLimiter
+}
+
+impl RateLimit {
+  pub fn new(limiter: IpRateLimiter) -> Self {
+    Self { limiter }
+  }
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+  S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+  S::Future: 'static,
+{
+  type Request = ServiceRequest;
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type InitError = ();
+  type Transform = RateLimitMiddleware<S>;
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ok(RateLimitMiddleware {
+      service,
+      limiter: self.limiter.clone()
+    })
+  }
+}
+
+pub struct RateLimitMiddleware<S> {
+  service: S,
+  limiter: IpRateLimiter
+}
+
+impl<S> RateLimitMiddleware<S> {
+  fn is_limited(&self, req: &ServiceRequest) -> bool {
+    // Clients without an IP address are let through, the
+    // stats service would complain about them anyway.
+    let ip = match real_ip_addr_from_info(&req.connection_info()) {
+      Some(ip) => ip,
+      None => return false
+    };
+    match self.limiter.lock() {
+      Ok(mut rl) => rl.check(&ip),
+      Err(e) => {
+        // I decided to ignore possible weird rate limiter lock
+        // errors which should never happen.
+        warn!("Could not lock the rate limiter - SHOULD NEVER HAPPEN - {}", e);
+        false
       }
     }
-    self.is_limited
+  }
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+  S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+  S::Future: 'static,
+{
+  type Request = ServiceRequest;
+  type Response = ServiceResponse<B>;
+  type Error = actix_web::Error;
+  type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.service.poll_ready(cx)
   }
 
+  fn call(&mut self, req: ServiceRequest) -> Self::Future {
+    if self.is_limited(&req) {
+      warn!("Rate limited client at {}", req.path());
+      Either::Right(futures::future::err(Error::TooManyRequests.into()))
+    } else {
+      Either::Left(self.service.call(req))
+    }
+  }
 }
 
 #[cfg(test)]
@@ -83,22 +235,54 @@ mod tests {
   use super::*;
 
   #[test]
-  fn basic_rate_limiter_initial_state() {
-    let sut = BasicRateLimiter::new(2, 60, 60);
-    assert_eq!(sut.is_expired(), false);
-    assert_eq!(sut.is_locked(), false);
+  fn keyed_rate_limiter_blocks_after_max_requests() {
+    let mut sut = KeyedRateLimiter::new(2, 60, 60, 10);
+    let now = Instant::now();
+    assert!(!sut.check_at(&1, now));
+    assert!(!sut.check_at(&1, now));
+    assert!(sut.check_at(&1, now));
+    // Other clients are not affected:
+    assert!(!sut.check_at(&2, now));
   }
 
   #[test]
-  fn basic_rate_limiter_can_unlock() {
-    // Block for 0 seconds:
-    let mut sut = BasicRateLimiter::new(2, u32::MAX, 0);
-    for _ in 0..2 {
-      sut.update();
+  fn keyed_rate_limiter_can_unlock() {
+    let mut sut = KeyedRateLimiter::new(2, 60, 10, 10);
+    let now = Instant::now();
+    for _ in 0..3 {
+      sut.check_at(&1, now);
     }
-    assert_eq!(sut.is_locked(), true);
-    sut.update();
-    // Should be unlocked now:
-    assert_eq!(sut.is_locked(), false);
+    assert!(sut.check_at(&1, now + Duration::from_secs(5)));
+    assert!(!sut.check_at(&1, now + Duration::from_secs(11)));
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn keyed_rate_limiter_refills_over_time() {
+    let mut sut = KeyedRateLimiter::new(2, 60, 60, 10);
+    let now = Instant::now();
+    sut.check_at(&1, now);
+    sut.check_at(&1, now);
+    // One token every 30 seconds:
+    assert!(!sut.check_at(&1, now + Duration::from_secs(30)));
+  }
+
+  #[test]
+  fn keyed_rate_limiter_memory_is_bounded() {
+    let mut sut = KeyedRateLimiter::new(2, 60, 60, 3);
+    let now = Instant::now();
+    for i in 0..3 {
+      sut.check_at(&i, now);
+    }
+    // Client 0 is idle after a minute and gets evicted
+    // first, the others are busy:
+    sut.check_at(&1, now + Duration::from_secs(50));
+    sut.check_at(&2, now + Duration::from_secs(55));
+    sut.check_at(&3, now + Duration::from_secs(60));
+    assert_eq!(sut.buckets.len(), 3);
+    assert!(!sut.buckets.contains_key(&0));
+    // Nothing is idle, least recently used goes:
+    sut.check_at(&4, now + Duration::from_secs(60));
+    assert_eq!(sut.buckets.len(), 3);
+    assert!(!sut.buckets.contains_key(&1));
+  }
+}
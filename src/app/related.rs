@@ -0,0 +1,131 @@
+use crate::db::entities::Article;
+use crate::db::{self, Pool};
+use crate::utils::text_utils;
+use color_eyre::Result;
+use log::warn;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// How many articles the fulltext query can bring in, the
+// best ones are the only ones that matter.
+const FULLTEXT_CANDIDATES: usize = 50;
+const MAX_SIMILARITY_TERMS: usize = 30;
+// The best fulltext match is worth that many shared tags.
+const FULLTEXT_WEIGHT: f64 = 2.0;
+// The blog doesn't have that many articles, but just in
+// case something is crawling all of them.
+const MAX_CACHED_ARTICLES: usize = 2000;
+
+// Finding related articles takes a fulltext query and a
+// tags query, and the result only changes when articles
+// do, so the IDs of the related articles are cached per
+// article. Any change to any article can move it in or
+// out of every list, so the whole cache gets dropped.
+// The articles themselves are fetched every time, the
+// comment counts would be stale otherwise.
+#[derive(Clone, Default)]
+pub struct RelatedArticles {
+  cache: Arc<Mutex<RelatedCache>>,
+}
+
+#[derive(Default)]
+struct RelatedCache {
+  // Incremented on every invalidation, so that lists that
+  // were computed before don't get in the cache after it.
+  generation: u64,
+  related: HashMap<i32, Vec<i32>>,
+}
+
+impl RelatedArticles {
+  // Returns the cached IDs, or the current generation to
+  // give back to insert() once they're computed.
+  pub fn get(&self, article_id: i32) -> std::result::Result<Vec<i32>, u64> {
+    match self.cache.lock() {
+      Ok(cache) => match cache.related.get(&article_id) {
+        Some(ids) => Ok(ids.clone()),
+        None => Err(cache.generation),
+      },
+      Err(e) => {
+        // Same as the rate limiter, this should never
+        // happen and isn't worth crashing for.
+        warn!("Could not lock the related articles cache - {}", e);
+        Err(0)
+      }
+    }
+  }
+
+  pub fn insert(&self, article_id: i32, generation: u64, ids: Vec<i32>) {
+    if let Ok(mut cache) = self.cache.lock() {
+      if cache.generation != generation {
+        return;
+      }
+      if cache.related.len() >= MAX_CACHED_ARTICLES {
+        cache.related.clear();
+      }
+      cache.related.insert(article_id, ids);
+    }
+  }
+
+  // Has to be called when articles (or their tags) may
+  // have changed.
+  pub fn invalidate(&self) {
+    match self.cache.lock() {
+      Ok(mut cache) => {
+        cache.generation += 1;
+        cache.related.clear();
+      }
+      Err(e) => warn!("Could not lock the related articles cache - {}", e),
+    }
+  }
+}
+
+// Scores the other published articles with the amount of
+// tags they share with the article, plus how well they
+// match the words of its title and summary (the best match
+// gets FULLTEXT_WEIGHT, the others a fraction of that).
+// Returns the IDs of the best ones, best first.
+pub fn related_article_ids(pool: &Pool, article: &Article, count: usize) -> Result<Vec<i32>> {
+  let mut scores: HashMap<i32, f64> = HashMap::new();
+  for (id, shared_tags) in db::articles_sharing_tags(pool, article.id)? {
+    *scores.entry(id).or_default() += shared_tags as f64;
+  }
+  let text = format!("{} {}", article.title, article.summary);
+  if let Some(query) = text_utils::similarity_query(&text, MAX_SIMILARITY_TERMS) {
+    let matches = db::fulltext_matches(pool, &query, article.id, FULLTEXT_CANDIDATES)?;
+    // bm25 ranks are negative, the lowest is the best.
+    let best = matches.iter().map(|(_, rank)| -rank).fold(0.0, f64::max);
+    if best > 0.0 {
+      for (id, rank) in matches {
+        *scores.entry(id).or_default() += FULLTEXT_WEIGHT * -rank / best;
+      }
+    }
+  }
+  let mut scores: Vec<(i32, f64)> = scores.into_iter().collect();
+  // Most recent articles first when scores are equal,
+  // which is the highest ID.
+  scores.sort_by(|a, b| {
+    b.1.partial_cmp(&a.1)
+      .unwrap_or(Ordering::Equal)
+      .then(b.0.cmp(&a.0))
+  });
+  Ok(scores.into_iter().take(count).map(|(id, _)| id).collect())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn related_cache_ignores_stale_lists() {
+    let related = RelatedArticles::default();
+    let generation = related.get(1).unwrap_err();
+    related.insert(1, generation, vec![2, 3]);
+    assert_eq!(Ok(vec![2, 3]), related.get(1));
+    let generation = related.get(2).unwrap_err();
+    related.invalidate();
+    assert!(related.get(1).is_err());
+    related.insert(2, generation, vec![1]);
+    assert!(related.get(2).is_err());
+  }
+}
@@ -0,0 +1,74 @@
+use super::related::RelatedArticles;
+use crate::db::{self, Pool};
+use crate::utils::time_utils::current_timestamp;
+use log::{error, info};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task;
+
+// Longest the scheduler sleeps without checking the
+// database, in case the clock changed or something
+// got scheduled without waking it up.
+const MAX_SLEEP_SECONDS: i64 = 3600;
+
+// Publishes scheduled articles at their publish_at time.
+// Schedules only live in the database, the scheduler just
+// sleeps until the next one, which means nothing gets
+// lost when the server restarts (articles that were due
+// in the meantime are published right away).
+#[derive(Clone)]
+pub struct PublishScheduler {
+  wake_up: Arc<Notify>,
+}
+
+impl PublishScheduler {
+  pub fn start(pool: Pool, related_articles: RelatedArticles) -> Self {
+    let wake_up = Arc::new(Notify::new());
+    let notified = wake_up.clone();
+    actix_web::rt::spawn(async move {
+      loop {
+        let sleep = match publish_due_articles(pool.clone(), &related_articles).await {
+          Some(next) => (next - current_timestamp()).clamp(0, MAX_SLEEP_SECONDS),
+          None => MAX_SLEEP_SECONDS,
+        };
+        tokio::select! {
+          _ = tokio::time::delay_for(Duration::from_secs(sleep as u64)) => {},
+          _ = notified.notified() => {},
+        }
+      }
+    });
+    Self { wake_up }
+  }
+
+  // Has to be called when schedules may have changed, so
+  // that the next sleep is recomputed.
+  pub fn wake_up(&self) {
+    self.wake_up.notify();
+  }
+}
+
+// Returns the next publish_at timestamp, if any.
+async fn publish_due_articles(pool: Pool, related_articles: &RelatedArticles) -> Option<i64> {
+  let related_articles = related_articles.clone();
+  let result = task::spawn_blocking(move || -> color_eyre::Result<Option<i64>> {
+    let published = db::publish_scheduled_articles(&pool, current_timestamp())?;
+    if published > 0 {
+      info!("Scheduler published {} article(s)", published);
+      related_articles.invalidate();
+    }
+    db::next_publish_at(&pool)
+  })
+  .await;
+  match result {
+    Ok(Ok(next)) => next,
+    Ok(Err(e)) => {
+      error!("Publishing scheduled articles failed - {}", e);
+      None
+    }
+    Err(e) => {
+      error!("Publish scheduler task panicked - {}", e);
+      None
+    }
+  }
+}
@@ -1,6 +1,7 @@
 // Adding the context method to errors:
 use color_eyre::Result;
 use eyre::WrapErr;
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::convert::From;
 
@@ -12,10 +13,15 @@ pub struct Config {
     pub wordlist_path: String,
     pub bind_address: String,
     pub message_queue_size: usize,
-    // Rate limiter settings:
-    pub rl_max_requests: u32,
+    // Rate limiter settings, the max requests are per
+    // client IP address:
+    pub rl_comments_max_requests: u32,
+    pub rl_search_max_requests: u32,
     pub rl_max_requests_time: u32,
     pub rl_block_duration: u32,
+    // Max amount of clients the rate limiters keep
+    // track of:
+    pub rl_max_clients: usize,
     pub import_path: String,
     pub template_dir: String,
     // Used to generate the RSS fields
@@ -30,6 +36,52 @@ pub struct Config {
     // Used in some response header generation,
     // optional
     pub api_root: Option<String>,
+    // Comma separated list of IP addresses allowed to
+    // reach protected endpoints, on top of requiring an
+    // API token. Leave it out when running behind a
+    // reverse proxy.
+    pub allowed_ip_addresses: Option<String>,
+    // When true, posted comments are held for moderation
+    // instead of being approved right away.
+    pub comment_moderation: bool,
+    // Comma separated list of case insensitive patterns,
+    // user agents containing any of them are bots.
+    pub stats_bot_patterns: String,
+    // Bot visits are flagged in the stats database, or
+    // not saved at all when this is true.
+    pub stats_drop_bots: bool,
+    // Comma separated list of IP addresses or CIDR ranges
+    // whose visits are never saved (e.g. internal IPs).
+    pub stats_excluded_ips: Option<String>,
+    // Raw stats older than that many days get rolled up into
+    // the daily tables. The rollup is disabled when absent.
+    pub stats_retention_days: Option<u32>,
+    // Rolled up raw stats are anonymized, or deleted when
+    // this is true.
+    pub stats_delete_raw: bool,
+    // How often the server runs the rollup, in hours.
+    pub stats_rollup_interval: u64,
+    // Apply pending migrations to both databases when the
+    // server starts.
+    pub db_auto_migrate: bool,
+    // Max connections per database pool, has to be at
+    // least 2 since the stats thread keeps one.
+    pub db_pool_size: u32,
+    // How long a connection waits for a locked database,
+    // in milliseconds.
+    pub db_busy_timeout: u64,
+    // Key used to sign the draft preview links. A random
+    // one is used when absent, meaning links stop working
+    // when the server restarts.
+    pub preview_secret: Option<String>,
+    // How long preview links are valid, in hours.
+    pub preview_link_hours: u32,
+    // Articles that have been in the trash for longer than
+    // that many days are deleted for good when purging.
+    pub trash_retention_days: u32,
+    // Search terms are reduced to their French stem and
+    // used as prefixes when true.
+    pub search_stemming: bool,
 }
 
 // Looks redundant but I thought having another
@@ -64,7 +116,38 @@ impl From<Config> for SiteInfo {
     }
 }
 
+// Matched against lowercase user agents. Also catches
+// link preview fetchers and feed readers (including the
+// RSS cron).
+const DEFAULT_BOT_PATTERNS: &str = "bot,crawl,spider,slurp,archiver,\
+    facebookexternalhit,embedly,preview,whatsapp,feed,rss,curl,wget,\
+    python-requests,go-http-client,java/,headless";
+
+// Splits comma separated config values.
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
 impl Config {
+    pub fn allowed_ips(&self) -> Option<Vec<String>> {
+        self.allowed_ip_addresses.as_deref().map(split_list)
+    }
+
+    pub fn bot_patterns(&self) -> Vec<String> {
+        split_list(&self.stats_bot_patterns)
+    }
+
+    pub fn excluded_ips(&self) -> Vec<String> {
+        self.stats_excluded_ips
+            .as_deref()
+            .map(split_list)
+            .unwrap_or_default()
+    }
+
     pub fn from_env() -> Result<Config> {
         let mut c = config::Config::new();
         // RUST_LOG is already set in main.rs if it
@@ -76,11 +159,25 @@ impl Config {
         // Used to set the queue size for sync_sender
         // (the Stats thread uses it):
         c.set_default("message_queue_size", 30)?;
-        // Settings for the basic rate limiter I'm
-        // using:
-        c.set_default("rl_max_requests", 120)?;
+        // Settings for the per client rate limiters:
+        c.set_default("rl_comments_max_requests", 10)?;
+        c.set_default("rl_search_max_requests", 60)?;
         c.set_default("rl_max_requests_time", 60)?;
         c.set_default("rl_block_duration", 60)?;
+        c.set_default("rl_max_clients", 10000)?;
+        // Comments are approved automatically by default:
+        c.set_default("comment_moderation", false)?;
+        // Bot visits are flagged but still saved by default:
+        c.set_default("stats_bot_patterns", DEFAULT_BOT_PATTERNS)?;
+        c.set_default("stats_drop_bots", false)?;
+        c.set_default("stats_delete_raw", false)?;
+        c.set_default("stats_rollup_interval", 24)?;
+        c.set_default("db_auto_migrate", true)?;
+        c.set_default("db_pool_size", 10)?;
+        c.set_default("db_busy_timeout", 5000)?;
+        c.set_default("preview_link_hours", 72)?;
+        c.set_default("trash_retention_days", 30)?;
+        c.set_default("search_stemming", true)?;
         // Default import path:
         c.set_default("import_path", "./import/")?;
         // Default template directory:
@@ -99,6 +196,19 @@ impl Config {
         )?;
 
         c.merge(config::Environment::default())?;
+        // RL_MAX_REQUESTS was the setting for the single rate
+        // limiter we used to have, it's still used as the
+        // default for both limiters so that old .env files
+        // keep working. Defaults can be set after merging,
+        // the environment still wins.
+        if let Ok(max_requests) = c.get_int("rl_max_requests") {
+            warn!(
+                "RL_MAX_REQUESTS is deprecated, use RL_COMMENTS_MAX_REQUESTS \
+                and RL_SEARCH_MAX_REQUESTS instead"
+            );
+            c.set_default("rl_comments_max_requests", max_requests)?;
+            c.set_default("rl_search_max_requests", max_requests)?;
+        }
         // The error has to be given a context for
         // color_eyre to work here:
         c.try_into().context("Loading configuration from env")
@@ -15,7 +15,12 @@ use lazy_static::lazy_static;
 use crate::db::Pool;
 use crate::db::entities::*;
 use crate::db::Order;
+use crate::db::migrations::{migrate, Database};
 use crate::config::Config;
+use crate::utils::time_utils::{current_timestamp, days_ago_timestamp};
+use crate::utils::token_utils::hash_token;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
 
 // The structure of ths file is horrible I'm so sorry
 
@@ -48,6 +53,112 @@ fn run_pre_tags_update(pool: &Pool) -> Result<()> {
   Ok(())
 }
 
+const TOKEN_LENGTH: usize = 40;
+
+fn generate_token() -> String {
+  thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(TOKEN_LENGTH)
+    .collect()
+}
+
+// Prints the token to stdout, it's the only time it's
+// ever visible since we only save the hash.
+fn run_create_token(pool: &Pool, name: Option<String>, scopes: Option<String>) -> Result<()> {
+  let name = name.ok_or_else(|| eyre!("A token name is required (--name)"))?;
+  let scopes: Vec<String> = scopes
+    .unwrap_or_default()
+    .split(',')
+    .map(|s| s.trim().to_lowercase())
+    .filter(|s| !s.is_empty())
+    .collect();
+  // A typo would make a token that can't reach anything.
+  if let Some(invalid) = scopes.iter().find(|s| !API_TOKEN_SCOPES.contains(&s.as_str())) {
+    return Err(eyre!(
+      "Unknown scope '{}', valid scopes are: {}",
+      invalid,
+      API_TOKEN_SCOPES.join(", ")
+    ));
+  }
+  let token = generate_token();
+  let mut api_token = ApiToken {
+    id: -1,
+    name,
+    token_hash: hash_token(&token),
+    scopes: scopes.join(","),
+    date: current_timestamp()
+  };
+  db::insert_api_token(pool, &mut api_token)?;
+  info!("Created API token {} with scopes '{}'", api_token.id, api_token.scopes);
+  println!("{}", token);
+  Ok(())
+}
+
+// Creates the database files if they don't exist.
+fn run_migrations(config: &Config, dry_run: bool) -> Result<()> {
+  let databases = [
+    (Database::Main, &config.db_path),
+    (Database::Stats, &config.stats_db_path)
+  ];
+  for (database, path) in databases.iter() {
+    let pool = Pool::new(SqliteConnectionManager::file(path))
+      .expect("Database connection failed");
+    let migrations = migrate(&pool, *database, dry_run)?;
+    if migrations.is_empty() {
+      println!("{:?} database ({}) is up to date", database, path);
+    }
+    for m in migrations.iter() {
+      println!(
+        "{:?} database ({}): {} migration {} - {}",
+        database,
+        path,
+        if dry_run { "pending" } else { "applied" },
+        m.version,
+        m.name
+      );
+    }
+  }
+  Ok(())
+}
+
+// Same as the periodic task in the server, except the
+// retention period has to be set.
+fn run_stats_rollup(config: &Config) -> Result<()> {
+  let retention_days = config.stats_retention_days
+    .ok_or_else(|| eyre!("STATS_RETENTION_DAYS has to be set for the stats rollup"))?;
+  let action = if config.stats_delete_raw {
+    db::RawStatsAction::Delete
+  } else {
+    db::RawStatsAction::Anonymize
+  };
+  let stats_pool = Pool::new(SqliteConnectionManager::file(&config.stats_db_path))
+    .expect("Stats database connection failed");
+  info!(
+    "Rolling up stats older than {} days ({:?} raw rows)...",
+    retention_days,
+    action
+  );
+  let rows = db::rollup_article_stats(
+    &stats_pool,
+    days_ago_timestamp(retention_days),
+    action
+  )?;
+  info!("Processed {} raw rows", rows);
+  Ok(())
+}
+
+// Same as the purge endpoint without the "days" parameter.
+fn run_purge_trash(pool: &Pool, config: &Config) -> Result<()> {
+  info!(
+    "Deleting articles that have been in the trash for more than {} days...",
+    config.trash_retention_days
+  );
+  let before = current_timestamp() - i64::from(config.trash_retention_days) * 24 * 3600;
+  let count = db::purge_trash(pool, before)?;
+  info!("Deleted {} article(s)", count);
+  Ok(())
+}
+
 fn transform_pre_code(content: String) -> String {
   // I have to use one of these cursed negative lookahead 
   // inside of a non-capturing group (?:()).
@@ -95,6 +206,15 @@ fn main() -> Result<()> {
   let program = args[0].clone();
   let mut opts = Options::new();
   opts.optopt("t", "transform", "Run desired data-transform", "OPERATION");
+  opts.optopt("n", "name", "Name of the API token to create", "NAME");
+  opts.optopt(
+    "s",
+    "scopes",
+    "Comma separated scopes for the API token (rss, sitemap, publish, import, reindex, moderate, stats, tags, authors)",
+    "SCOPES"
+  );
+  opts.optflag("m", "migrate", "Apply pending migrations to the main and stats databases");
+  opts.optflag("", "dry-run", "With --migrate, only list the pending migrations");
   opts.optflag("h", "help", "Program usage");
   let opt_matches = opts.parse(args)?;
   if opt_matches.opt_present("h") {
@@ -105,6 +225,10 @@ fn main() -> Result<()> {
   let config = Config::from_env()
     .expect("Configuration (environment or .env file) is missing");
 
+  if opt_matches.opt_present("m") {
+    return run_migrations(&config, opt_matches.opt_present("dry-run"));
+  }
+
   // Check operation to run:
   if let Some(operation) = opt_matches.opt_str("t") {
     let manager = SqliteConnectionManager::file(&config.db_path);
@@ -115,6 +239,19 @@ fn main() -> Result<()> {
         info!("Start <pre> to <pre><code> transform operation...");
         return run_pre_tags_update(&pool);
       },
+      "stats-rollup" => {
+        return run_stats_rollup(&config);
+      },
+      "purge-trash" => {
+        return run_purge_trash(&pool, &config);
+      },
+      "create-token" => {
+        return run_create_token(
+          &pool,
+          opt_matches.opt_str("n"),
+          opt_matches.opt_str("s")
+        );
+      },
       _ => {
         return Err(eyre!("Provided operation doesn't exist for data transform"));
       }
@@ -201,4 +338,12 @@ mod tests {
     assert_eq!(expect, result)
   }
 
+  #[test]
+  fn generated_tokens_are_different() {
+    let t1 = generate_token();
+    let t2 = generate_token();
+    assert_eq!(TOKEN_LENGTH, t1.len());
+    assert_ne!(t1, t2);
+  }
+
 }
\ No newline at end of file
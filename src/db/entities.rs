@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use derive_more::Display;
 
 // I'm starting with ultra simple datatypes,
 // which is something SQLite fits naturally into.
@@ -73,6 +74,79 @@ pub struct Tag {
   pub main_tag: i32
 }
 
+#[derive(Debug, Display, Clone, Copy, PartialEq)]
+pub enum RevisionAction {
+  #[display(fmt = "update")]
+  Update,
+  #[display(fmt = "delete")]
+  Delete,
+  #[display(fmt = "restore")]
+  Restore
+}
+
+// State of an article right before it got updated,
+// deleted or restored.
+#[derive(Debug, Clone)]
+pub struct ArticleRevision {
+  pub id: i32,
+  pub article_id: i32,
+  pub action: String,
+  // Name of the API token used for the change.
+  pub author: Option<String>,
+  pub date: i64,
+  pub title: String,
+  pub article_url: Option<String>,
+  pub thumb_image: Option<String>,
+  pub article_date: i64,
+  pub user_id: i32,
+  pub summary: String,
+  pub content: Option<String>,
+  pub published: i32,
+  pub short: i32,
+  pub tag_ids: Vec<i32>
+}
+
+#[derive(Debug, Clone)]
+pub struct TrashedArticle {
+  pub id: i32,
+  pub title: String,
+  pub article_url: Option<String>,
+  pub short: i32,
+  pub deleted_at: i64
+}
+
+// Users are the article authors, there's no login or
+// anything.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+  pub id: i32,
+  pub name: String,
+  pub bio: Option<String>,
+  pub avatar: Option<String>,
+  pub link: Option<String>
+}
+
+// Same as ArticleUpdate, only what's present gets updated.
+// The profile fields can be set to null.
+#[derive(Debug, Default)]
+pub struct UserUpdate {
+  pub id: i32,
+  pub name: Option<String>,
+  pub bio: Option<Option<String>>,
+  pub avatar: Option<Option<String>>,
+  pub link: Option<Option<String>>
+}
+
+// Tag with the amount of published articles and shorts
+// that have it.
+#[derive(Debug)]
+pub struct TagArticleCount {
+  pub tag: Tag,
+  pub articles: i64,
+  pub shorts: i64
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Comment {
   pub id: i32,
@@ -80,9 +154,45 @@ pub struct Comment {
   pub author: String,
   pub comment: String,
   pub date: i64,
+  pub status: CommentStatus,
+  // Set when the comment is a reply to another comment
+  // of the same article.
+  pub parent_id: Option<i32>,
   pub client_ip: Option<String>
 }
 
+// Saved as an integer in the comments table, only
+// approved comments are publicly visible.
+#[derive(Debug, Display, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum CommentStatus {
+  #[display(fmt = "pending")]
+  Pending,
+  #[display(fmt = "approved")]
+  Approved,
+  #[display(fmt = "spam")]
+  Spam
+}
+
+impl CommentStatus {
+  pub fn to_i32(self) -> i32 {
+    match self {
+      CommentStatus::Pending => 0,
+      CommentStatus::Approved => 1,
+      CommentStatus::Spam => 2
+    }
+  }
+
+  // Unknown values are considered pending, a bad value
+  // should never get a comment published.
+  pub fn from_i32(value: i32) -> Self {
+    match value {
+      1 => CommentStatus::Approved,
+      2 => CommentStatus::Spam,
+      _ => CommentStatus::Pending
+    }
+  }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ArticleStat {
   pub id: i64,
@@ -94,5 +204,64 @@ pub struct ArticleStat {
   pub country: String,
   pub region: String,
   pub city: String,
-  pub date: Option<i64>
+  pub date: Option<i64>,
+  // Flagged using the user agent.
+  pub is_bot: bool
+}
+
+// Aggregated views read back from the stats database.
+// Unique visitors are distinct pseudo_ip + pseudo_ua
+// pairs. Visits flagged as bots are not counted.
+#[derive(Debug)]
+pub struct PeriodViews {
+  // Either YYYY-MM-DD, YYYY-Www or YYYY-MM depending
+  // on the period.
+  pub period: String,
+  pub views: i64,
+  pub unique_visitors: i64
+}
+
+#[derive(Debug)]
+pub struct ArticleViews {
+  pub article_id: i32,
+  pub views: i64,
+  pub unique_visitors: i64
+}
+
+// Region and city are only present when the breakdown
+// is that detailed.
+#[derive(Debug)]
+pub struct LocationViews {
+  pub country: String,
+  pub region: Option<String>,
+  pub city: Option<String>,
+  pub views: i64,
+  pub unique_visitors: i64
+}
+
+#[derive(Debug)]
+pub struct VisitorsSummary {
+  pub views: i64,
+  pub unique_visitors: i64,
+  pub bot_views: i64
+}
+
+// Every scope name the API knows about, the guards
+// have a Scope variant for each of them.
+// Only used by data-transform to validate new tokens.
+#[allow(dead_code)]
+pub const API_TOKEN_SCOPES: &[&str] = &[
+  "rss", "sitemap", "publish", "import", "reindex", "moderate", "stats", "tags", "authors"
+];
+
+// API tokens are stored hashed, scopes are a comma
+// separated list of scope names.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct ApiToken {
+  pub id: i32,
+  pub name: String,
+  pub token_hash: String,
+  pub scopes: String,
+  pub date: i64
 }
@@ -1,15 +1,9 @@
 use html2text::from_read;
 //use super::entities::{Article};
 
-/**
- * Generate a certain amount of query placeholders
- */
-pub fn generate_where_placeholders(name: &str, count: usize) -> String {
-  let mut all_clauses: Vec<String> = Vec::with_capacity(count);
-  for _ in 0..count {
-    all_clauses.push(generate_field_equal_qmark(name));
-  }
-  all_clauses.join(" AND ")
+// For "IN (?,?,?)" clauses.
+pub fn generate_in_placeholders(count: usize) -> String {
+  vec!["?"; count].join(",")
 }
 
 pub fn generate_field_equal_qmark(name: &str) -> String {
@@ -32,10 +26,7 @@ mod tests {
   use super::*;
 
   #[test]
-  fn generate_4_query_placeholders() {
-    let name = "tags";
-    let count: usize = 4;
-    let expected = String::from("tags = ? AND tags = ? AND tags = ? AND tags = ?");     
-    assert_eq!(generate_where_placeholders(name, count), expected);
+  fn generate_3_in_placeholders() {
+    assert_eq!("?,?,?", generate_in_placeholders(3));
   }
 }
\ No newline at end of file
@@ -18,12 +18,12 @@ pub fn map_tag(row: &Row) -> Result<Tag, Error> {
   })
 }
 
+// Tags are fetched separately for a whole batch of
+// articles and added afterwards.
 pub fn map_article(
   row: &Row, 
-  tags: Vec<Tag>, 
   article_type: &ArticleSelector,
-  author: String,
-  comments_count: i64
+  anonymous_username: &str
 ) -> Result<Article, Error> {
   // Field order:
   /*
@@ -36,17 +36,25 @@ pub fn map_article(
   "articles.summary",
   "articles.published",
   "articles.short",
+  author (subquery),
+  comments_count (subquery),
   "articles.content"
   */
   let (content, article_url): (Option<String>, Option<String>) = 
     match article_type {
       ArticleSelector::All => 
-        (Some(row.get(9)?), Some(row.get(2)?)),
+        (Some(row.get(11)?), Some(row.get(2)?)),
       ArticleSelector::Short => 
-        (Some(row.get(9)?), None),
+        (Some(row.get(11)?), None),
+      // Shorts can end up in there too (related articles),
+      // they don't have a URL.
       ArticleSelector::Article => 
-        (None, Some(row.get(2)?)),
+        (None, row.get(2)?),
     };
+  // The old API was substituting "Anonymous" to possibly 
+  // invalid/unknown user IDs, the subquery gives NULL for
+  // these.
+  let author: Option<String> = row.get(9)?;
   Ok(
     Article {
       id: row.get(0)?,
@@ -59,16 +67,36 @@ pub fn map_article(
       published: row.get(7)?,
       content,
       short: row.get(8)?,
-      tags,
-      author,
-      comments_count
+      tags: Vec::new(),
+      author: author.unwrap_or_else(|| anonymous_username.to_string()),
+      comments_count: row.get(10)?
     }
   )
 }
 
+pub fn map_tag_article_count(row: &Row) -> Result<TagArticleCount, Error> {
+  Ok(TagArticleCount {
+    tag: map_tag(row)?,
+    articles: row.get(3)?,
+    shorts: row.get(4)?
+  })
+}
+
+// Tags with the ID of the article they belong to.
+pub fn map_article_tag(row: &Row) -> Result<(i32, Tag), Error> {
+  Ok((
+    row.get(0)?,
+    Tag {
+      id: row.get(1)?,
+      name: row.get(2)?,
+      main_tag: row.get(3)?
+    }
+  ))
+}
+
 // Some comment queries do not ask for client_ip.
 pub fn map_comment(row: &Row) -> Result<Comment, Error> {
-  let client_ip: Option<String> = match row.get(5) {
+  let client_ip: Option<String> = match row.get(7) {
     Ok(ip) => Some(ip),
     Err(_) => None
   };
@@ -79,6 +107,8 @@ pub fn map_comment(row: &Row) -> Result<Comment, Error> {
       author: row.get(2)?,
       comment: row.get(3)?,
       date: row.get(4)?,
+      status: CommentStatus::from_i32(row.get(5)?),
+      parent_id: row.get(6)?,
       client_ip
     }
   )
@@ -105,4 +135,101 @@ pub fn map_search_result(
       author: row.get(7)?
     }
   )
-}
\ No newline at end of file
+}
+
+// Tag IDs are saved as a comma separated list, IDs that
+// somehow aren't numbers are ignored.
+pub fn map_article_revision(row: &Row) -> Result<ArticleRevision, Error> {
+  let tag_ids: Option<String> = row.get(14)?;
+  let mut tag_ids: Vec<i32> = tag_ids
+    .unwrap_or_default()
+    .split(',')
+    .filter_map(|id| id.trim().parse().ok())
+    .collect();
+  tag_ids.sort_unstable();
+  Ok(ArticleRevision {
+    id: row.get(0)?,
+    article_id: row.get(1)?,
+    action: row.get(2)?,
+    author: row.get(3)?,
+    date: row.get(4)?,
+    title: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+    article_url: row.get(6)?,
+    thumb_image: row.get(7)?,
+    article_date: row.get::<_, Option<i64>>(8)?.unwrap_or_default(),
+    user_id: row.get::<_, Option<i32>>(9)?.unwrap_or_default(),
+    summary: row.get::<_, Option<String>>(10)?.unwrap_or_default(),
+    content: row.get(11)?,
+    published: row.get::<_, Option<i32>>(12)?.unwrap_or_default(),
+    short: row.get::<_, Option<i32>>(13)?.unwrap_or_default(),
+    tag_ids
+  })
+}
+
+pub fn map_trashed_article(row: &Row) -> Result<TrashedArticle, Error> {
+  Ok(TrashedArticle {
+    id: row.get(0)?,
+    title: row.get(1)?,
+    article_url: row.get(2)?,
+    short: row.get(3)?,
+    deleted_at: row.get(4)?
+  })
+}
+
+pub fn map_user(row: &Row) -> Result<User, Error> {
+  Ok(User {
+    id: row.get(0)?,
+    name: row.get(1)?,
+    bio: row.get(2)?,
+    avatar: row.get(3)?,
+    link: row.get(4)?
+  })
+}
+
+pub fn map_api_token(row: &Row) -> Result<ApiToken, Error> {
+  Ok(
+    ApiToken {
+      id: row.get(0)?,
+      name: row.get(1)?,
+      token_hash: row.get(2)?,
+      scopes: row.get(3)?,
+      date: row.get(4)?
+    }
+  )
+}
+
+pub fn map_period_views(row: &Row) -> Result<PeriodViews, Error> {
+  Ok(PeriodViews {
+    period: row.get(0)?,
+    views: row.get(1)?,
+    unique_visitors: row.get(2)?
+  })
+}
+
+pub fn map_article_views(row: &Row) -> Result<ArticleViews, Error> {
+  Ok(ArticleViews {
+    article_id: row.get(0)?,
+    views: row.get(1)?,
+    unique_visitors: row.get(2)?
+  })
+}
+
+// Region and city are selected as NULL when the
+// breakdown doesn't go that far.
+pub fn map_location_views(row: &Row) -> Result<LocationViews, Error> {
+  Ok(LocationViews {
+    country: row.get(0)?,
+    region: row.get(1)?,
+    city: row.get(2)?,
+    views: row.get(3)?,
+    unique_visitors: row.get(4)?
+  })
+}
+
+pub fn map_visitors_summary(row: &Row) -> Result<VisitorsSummary, Error> {
+  Ok(VisitorsSummary {
+    views: row.get(0)?,
+    unique_visitors: row.get(1)?,
+    bot_views: row.get(2)?
+  })
+}
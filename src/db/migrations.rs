@@ -0,0 +1,402 @@
+use super::Pool;
+use crate::utils::time_utils::current_timestamp;
+use color_eyre::Result;
+use eyre::{eyre, WrapErr};
+use log::info;
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+
+/**
+ * Embedded schema migrations for both databases. Applied
+ * migrations are recorded in the schema_version table.
+ *
+ * Databases created before migrations existed have no
+ * schema_version table, in which case the initial schema
+ * migration is marked as applied without running it. The
+ * following migrations check whether the changes are
+ * already there since they may have been applied by hand
+ * with the old data-transform operations.
+ *
+ * Never edit a migration that was released, add a new one.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Database {
+  Main,
+  Stats
+}
+
+enum MigrationKind {
+  Sql(&'static str),
+  Rust(fn(&Connection) -> Result<()>)
+}
+
+pub struct Migration {
+  pub version: u32,
+  pub name: &'static str,
+  kind: MigrationKind
+}
+
+const MAIN_MIGRATIONS: &[Migration] = &[
+  Migration {
+    version: 1,
+    name: "initial_schema",
+    kind: MigrationKind::Sql(include_str!("../../db/migrations/main/0001_initial_schema.sql"))
+  },
+  Migration {
+    version: 2,
+    name: "api_tokens",
+    kind: MigrationKind::Sql(include_str!("../../db/migrations/main/0002_api_tokens.sql"))
+  },
+  Migration {
+    version: 3,
+    name: "comments_status",
+    kind: MigrationKind::Rust(comments_status)
+  },
+  Migration {
+    version: 4,
+    name: "comments_parent_id",
+    kind: MigrationKind::Rust(comments_parent_id)
+  },
+  Migration {
+    version: 5,
+    name: "users_profile",
+    kind: MigrationKind::Rust(users_profile)
+  },
+  Migration {
+    version: 6,
+    name: "articles_publish_at",
+    kind: MigrationKind::Rust(articles_publish_at)
+  },
+  Migration {
+    version: 7,
+    name: "article_revisions",
+    kind: MigrationKind::Sql(include_str!("../../db/migrations/main/0007_article_revisions.sql"))
+  },
+  Migration {
+    version: 8,
+    name: "articles_trash",
+    kind: MigrationKind::Rust(articles_trash)
+  },
+  Migration {
+    version: 9,
+    name: "articles_ft_remove_diacritics",
+    kind: MigrationKind::Rust(articles_ft_remove_diacritics)
+  },
+  Migration {
+    version: 10,
+    name: "articles_ft_vocab",
+    kind: MigrationKind::Sql(include_str!("../../db/migrations/main/0010_articles_ft_vocab.sql"))
+  },
+];
+
+const STATS_MIGRATIONS: &[Migration] = &[
+  Migration {
+    version: 1,
+    name: "initial_schema",
+    kind: MigrationKind::Sql(include_str!("../../db/migrations/stats/0001_initial_schema.sql"))
+  },
+  Migration {
+    version: 2,
+    name: "article_stats_is_bot",
+    kind: MigrationKind::Rust(article_stats_is_bot)
+  },
+  Migration {
+    version: 3,
+    name: "article_stats_rolled_up",
+    kind: MigrationKind::Rust(article_stats_rolled_up)
+  },
+  Migration {
+    version: 4,
+    name: "daily_rollup_tables",
+    kind: MigrationKind::Sql(include_str!("../../db/migrations/stats/0004_daily_rollup_tables.sql"))
+  },
+  Migration {
+    version: 5,
+    name: "country_stats_daily_locations",
+    kind: MigrationKind::Sql(include_str!("../../db/migrations/stats/0005_country_stats_daily_locations.sql"))
+  },
+];
+
+impl Database {
+  fn migrations(&self) -> &'static [Migration] {
+    match self {
+      Database::Main => MAIN_MIGRATIONS,
+      Database::Stats => STATS_MIGRATIONS
+    }
+  }
+
+  // Table that tells us the database existed before
+  // migrations did.
+  fn marker_table(&self) -> &'static str {
+    match self {
+      Database::Main => "articles",
+      Database::Stats => "article_stats"
+    }
+  }
+}
+
+// Applies all the pending migrations, each in its own
+// transaction, or just lists them when dry_run is true.
+// Returns the migrations that were (or would be) applied.
+pub fn migrate(pool: &Pool, database: Database, dry_run: bool) -> Result<Vec<&'static Migration>> {
+  let mut conn = pool.clone().get()?;
+  let is_legacy = !table_exists(&conn, "schema_version")?
+    && table_exists(&conn, database.marker_table())?;
+  if !dry_run {
+    create_schema_version_table(&conn)?;
+    if is_legacy {
+      info!("Database predates migrations, recording the initial schema as applied");
+      record_version(&conn, &database.migrations()[0])?;
+    }
+  }
+  let current = if is_legacy {
+    database.migrations()[0].version
+  } else {
+    current_version(&conn)?
+  };
+  let pending: Vec<&'static Migration> = database
+    .migrations()
+    .iter()
+    .filter(|m| m.version > current)
+    .collect();
+  if dry_run {
+    return Ok(pending);
+  }
+  for migration in pending.iter() {
+    info!("Applying migration {} - {}", migration.version, migration.name);
+    let tx = conn.transaction()?;
+    match migration.kind {
+      MigrationKind::Sql(sql) => tx.execute_batch(sql)?,
+      MigrationKind::Rust(run) => run(&tx)?
+    };
+    record_version(&tx, migration)?;
+    tx.commit()
+      .wrap_err_with(|| format!("Apply migration {}", migration.version))?;
+  }
+  Ok(pending)
+}
+
+// Latest version, 0 for an empty database.
+pub fn current_version(conn: &Connection) -> Result<u32> {
+  if !table_exists(conn, "schema_version")? {
+    return Ok(0);
+  }
+  let version: Option<u32> = conn
+    .query_row("SELECT max(version) FROM schema_version", NO_PARAMS, |row| row.get(0))
+    .context("Get current schema version")?;
+  Ok(version.unwrap_or(0))
+}
+
+fn create_schema_version_table(conn: &Connection) -> Result<()> {
+  conn.execute_batch(
+    "CREATE TABLE IF NOT EXISTS \"schema_version\" (
+      \"version\" INTEGER PRIMARY KEY,
+      \"name\" TEXT NOT NULL,
+      \"applied_at\" INTEGER NOT NULL
+    );"
+  ).context("Create schema_version table")
+}
+
+fn record_version(conn: &Connection, migration: &Migration) -> Result<()> {
+  conn.execute(
+    "INSERT INTO schema_version (version, name, applied_at) VALUES (?, ?, ?)",
+    params![migration.version, migration.name, current_timestamp()]
+  )?;
+  Ok(())
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+  conn.query_row(
+    "SELECT 1 FROM sqlite_master WHERE type IN ('table', 'view') AND name = ?",
+    params![table],
+    |_| Ok(())
+  )
+  .optional()
+  .map(|r| r.is_some())
+  .context("Check if table exists")
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+  // PRAGMA doesn't take bound parameters for the table
+  // name, it's never user input anyway.
+  let mut stmt = conn.prepare(&format!("PRAGMA table_info(\"{}\")", table))?;
+  let columns = stmt
+    .query_map(NO_PARAMS, |row| row.get::<_, String>(1))?
+    .collect::<Result<Vec<String>, _>>()?;
+  if columns.is_empty() {
+    return Err(eyre!("Table {} does not exist", table));
+  }
+  Ok(columns.iter().any(|c| c == column))
+}
+
+fn add_column_if_missing(
+  conn: &Connection,
+  table: &str,
+  column: &str,
+  definition: &str
+) -> Result<()> {
+  if !column_exists(conn, table, column)? {
+    conn.execute_batch(&format!(
+      "ALTER TABLE \"{}\" ADD COLUMN \"{}\" {};",
+      table, column, definition
+    ))?;
+  }
+  Ok(())
+}
+
+/* --- Rust migrations --- */
+
+// Existing comments are all considered approved.
+fn comments_status(conn: &Connection) -> Result<()> {
+  add_column_if_missing(conn, "comments", "status", "INTEGER NOT NULL DEFAULT 1")
+}
+
+fn comments_parent_id(conn: &Connection) -> Result<()> {
+  add_column_if_missing(conn, "comments", "parent_id", "INTEGER DEFAULT NULL")
+}
+
+// Optional author profile for the author pages.
+fn users_profile(conn: &Connection) -> Result<()> {
+  add_column_if_missing(conn, "users", "bio", "TEXT DEFAULT NULL")?;
+  add_column_if_missing(conn, "users", "avatar", "TEXT DEFAULT NULL")?;
+  add_column_if_missing(conn, "users", "link", "TEXT DEFAULT NULL")
+}
+
+// Timestamp at which an unpublished article gets
+// published by the scheduler.
+fn articles_publish_at(conn: &Connection) -> Result<()> {
+  add_column_if_missing(conn, "articles", "publish_at", "INTEGER DEFAULT NULL")
+}
+
+// Trashed articles have a deleted_at timestamp, their
+// published status is kept aside until they're restored.
+fn articles_trash(conn: &Connection) -> Result<()> {
+  add_column_if_missing(conn, "articles", "deleted_at", "INTEGER DEFAULT NULL")?;
+  add_column_if_missing(conn, "articles", "deleted_published", "INTEGER DEFAULT NULL")
+}
+
+// The blog is in French, searching for "ete" has to find
+// "été". FTS5 tables can't change their tokenizer, the
+// table is created again and everything is reindexed.
+fn articles_ft_remove_diacritics(conn: &Connection) -> Result<()> {
+  conn.execute_batch(
+    "DROP TABLE IF EXISTS articles_ft;
+    CREATE VIRTUAL TABLE articles_ft USING FTS5(
+      id, title, content,
+      tokenize = 'unicode61 remove_diacritics 2'
+    );"
+  )?;
+  let count = super::reindex_fulltext(conn)?;
+  info!("Reindexed {} articles", count);
+  Ok(())
+}
+
+// Existing visits are all considered human.
+fn article_stats_is_bot(conn: &Connection) -> Result<()> {
+  add_column_if_missing(conn, "article_stats", "is_bot", "INTEGER NOT NULL DEFAULT 0")
+}
+
+fn article_stats_rolled_up(conn: &Connection) -> Result<()> {
+  add_column_if_missing(conn, "article_stats", "rolled_up", "INTEGER NOT NULL DEFAULT 0")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::db;
+  use r2d2_sqlite::SqliteConnectionManager;
+
+  // Every connection to an in-memory database is its own
+  // database, so the pool can only have one.
+  fn memory_pool() -> Pool {
+    Pool::builder()
+      .max_size(1)
+      .build(SqliteConnectionManager::memory())
+      .unwrap()
+  }
+
+  #[test]
+  fn migrations_are_numbered_in_order() {
+    for db in &[Database::Main, Database::Stats] {
+      for (i, m) in db.migrations().iter().enumerate() {
+        assert_eq!(i as u32 + 1, m.version);
+      }
+    }
+  }
+
+  #[test]
+  fn creates_fresh_main_database() {
+    let pool = memory_pool();
+    let applied = migrate(&pool, Database::Main, false).unwrap();
+    assert_eq!(MAIN_MIGRATIONS.len(), applied.len());
+    let conn = pool.get().unwrap();
+    assert!(table_exists(&conn, "articles_ft").unwrap());
+    assert!(column_exists(&conn, "comments", "parent_id").unwrap());
+    assert_eq!(MAIN_MIGRATIONS.len() as u32, current_version(&conn).unwrap());
+    drop(conn);
+    // Running it again does nothing:
+    assert!(migrate(&pool, Database::Main, false).unwrap().is_empty());
+  }
+
+  #[test]
+  fn dry_run_changes_nothing() {
+    let pool = memory_pool();
+    let pending = migrate(&pool, Database::Stats, true).unwrap();
+    assert_eq!(STATS_MIGRATIONS.len(), pending.len());
+    let conn = pool.get().unwrap();
+    assert!(!table_exists(&conn, "schema_version").unwrap());
+    assert!(!table_exists(&conn, "article_stats").unwrap());
+  }
+
+  #[test]
+  fn upgrades_database_that_predates_migrations() {
+    let pool = memory_pool();
+    {
+      let conn = pool.get().unwrap();
+      conn.execute_batch(include_str!("../../db/migrations/stats/0001_initial_schema.sql"))
+        .unwrap();
+      // Applied by hand with the old data-transform operation:
+      article_stats_is_bot(&conn).unwrap();
+    }
+    let applied = migrate(&pool, Database::Stats, false).unwrap();
+    assert_eq!(
+      vec![2, 3, 4, 5],
+      applied.iter().map(|m| m.version).collect::<Vec<u32>>()
+    );
+    let conn = pool.get().unwrap();
+    assert!(column_exists(&conn, "article_stats", "rolled_up").unwrap());
+    assert!(table_exists(&conn, "country_stats_daily").unwrap());
+  }
+
+  #[test]
+  fn fulltext_reindex_keeps_unpublished_articles() {
+    let pool = memory_pool();
+    migrate(&pool, Database::Main, false).unwrap();
+    {
+      // Back to before the tokenizer change, with an
+      // article scheduled for later.
+      let conn = pool.get().unwrap();
+      conn.execute_batch(
+        "DELETE FROM schema_version WHERE version >= 9;
+        DROP TABLE articles_ft_vocab;
+        DROP TABLE articles_ft_instance;
+        INSERT INTO users (id, name) VALUES (1, 'DkVZ');
+        INSERT INTO articles (id, title, article_url, date, user_id, summary, content, \
+        published, publish_at) VALUES (1, 'Title', 'article', 0, 1, 'Summary', 'Été', 0, 1000);"
+      )
+      .unwrap();
+    }
+    assert_eq!(2, migrate(&pool, Database::Main, false).unwrap().len());
+    assert_eq!(1, db::publish_scheduled_articles(&pool, 1000).unwrap());
+    let found = db::search_published_articles(
+      &pool,
+      &["ete"],
+      &db::ArticleSelector::All,
+      &db::ArticleFilter::default(),
+      0,
+      10
+    )
+    .unwrap();
+    assert_eq!(1, found.len());
+  }
+}
@@ -2,17 +2,26 @@ use rusqlite::{params, OptionalExtension, Row, ToSql, NO_PARAMS};
 pub mod entities;
 mod helpers;
 mod mappers;
+pub mod migrations;
 mod queries;
 use color_eyre::Result;
 use entities::*;
-use eyre::WrapErr;
+use eyre::{eyre, WrapErr};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 // Re-exporting the query building enums and structs:
+use crate::utils::text_utils::edit_distance;
 use crate::utils::time_utils::current_timestamp;
 use helpers::{
-    generate_field_equal_qmark, generate_where_placeholders, strip_html, stripped_article_content,
+    generate_field_equal_qmark, generate_in_placeholders, strip_html,
+    stripped_article_content,
+};
+use mappers::{
+    map_api_token, map_article, map_article_revision, map_article_tag, map_article_views, map_comment, map_count, map_location_views,
+    map_period_views, map_search_result, map_tag, map_tag_article_count, map_trashed_article, map_user,
+    map_visitors_summary,
 };
-use mappers::{map_article, map_comment, map_count, map_search_result, map_tag};
 pub use queries::{Order, OrderBy};
 use queries::{Query, QueryType};
 
@@ -26,6 +35,7 @@ use queries::{Query, QueryType};
  */
 
 const ANONYMOUS_USERNAME: &'static str = "Anonymous";
+const TAGS_BATCH_SIZE: usize = 500;
 
 // Type alias to make function signatures much clearer:
 pub type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
@@ -38,6 +48,184 @@ pub enum ArticleSelector {
     All,
 }
 
+// Where a page of results starts. After and Before are
+// keyset pages that start right after (or end right
+// before) the item with the given id, in the order the
+// results are requested. They don't get slower on deep
+// pages and don't shift when something gets published
+// in between.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageStart {
+    Offset(usize),
+    After(i32),
+    Before(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagMatch {
+    All,
+    Any,
+}
+
+// Tag filter for the article listings. Tags can be given
+// by name or by id, articles need to have all of them or
+// any of them.
+#[derive(Debug)]
+pub struct TagFilter {
+    pub names: Vec<String>,
+    pub ids: Vec<i32>,
+    pub match_mode: TagMatch,
+}
+
+impl TagFilter {
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.ids.is_empty()
+    }
+
+    // Condition on articles.id, parameters come from params().
+    // Tags used to be joined in the FROM clause, which meant
+    // asking for two tags could never match anything.
+    fn where_clause(&self) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+        let clause = match self.match_mode {
+            TagMatch::All => {
+                let mut clauses: Vec<&str> = vec![
+                    "EXISTS (SELECT 1 FROM article_tags, tags \
+                    WHERE article_tags.article_id = articles.id \
+                    AND article_tags.tag_id = tags.id AND tags.name = ?)";
+                    self.names.len()
+                ];
+                clauses.append(&mut vec![
+                    "EXISTS (SELECT 1 FROM article_tags \
+                    WHERE article_tags.article_id = articles.id \
+                    AND article_tags.tag_id = ?)";
+                    self.ids.len()
+                ]);
+                format!("({})", clauses.join(" AND "))
+            }
+            TagMatch::Any => format!(
+                "articles.id IN (SELECT article_tags.article_id \
+                FROM article_tags, tags WHERE article_tags.tag_id = tags.id \
+                AND (tags.name IN ({}) OR tags.id IN ({})))",
+                generate_in_placeholders(self.names.len()),
+                generate_in_placeholders(self.ids.len())
+            ),
+        };
+        Some(clause)
+    }
+
+    fn params(&self) -> Vec<&dyn ToSql> {
+        self.names
+            .iter()
+            .map(|n| n as &dyn ToSql)
+            .chain(self.ids.iter().map(|id| id as &dyn ToSql))
+            .collect()
+    }
+}
+
+// Everything the article listings can be filtered on, on
+// top of the article type.
+// Dates are timestamps, from is inclusive and to is
+// exclusive.
+#[derive(Debug, Default)]
+pub struct ArticleFilter {
+    pub tags: Option<TagFilter>,
+    pub author_id: Option<i32>,
+    pub from_date: Option<i64>,
+    pub to_date: Option<i64>,
+}
+
+impl ArticleFilter {
+    pub fn tags(tags: Option<TagFilter>) -> Self {
+        Self {
+            tags,
+            ..Default::default()
+        }
+    }
+
+    pub fn author(author_id: i32) -> Self {
+        Self {
+            author_id: Some(author_id),
+            ..Default::default()
+        }
+    }
+
+    // Conditions to add to the where clause, in the same
+    // order as the params.
+    fn where_clauses(&self) -> Vec<String> {
+        let mut clauses = Vec::new();
+        if self.author_id.is_some() {
+            clauses.push(String::from("articles.user_id = ?"));
+        }
+        if let Some(tag_clause) = self.tags.as_ref().and_then(TagFilter::where_clause) {
+            clauses.push(tag_clause);
+        }
+        if self.from_date.is_some() {
+            clauses.push(String::from("articles.date >= ?"));
+        }
+        if self.to_date.is_some() {
+            clauses.push(String::from("articles.date < ?"));
+        }
+        clauses
+    }
+
+    fn params(&self) -> Vec<&dyn ToSql> {
+        let mut params: Vec<&dyn ToSql> = Vec::new();
+        if let Some(author_id) = &self.author_id {
+            params.push(author_id);
+        }
+        if let Some(tags) = &self.tags {
+            params.append(&mut tags.params());
+        }
+        if let Some(from_date) = &self.from_date {
+            params.push(from_date);
+        }
+        if let Some(to_date) = &self.to_date {
+            params.push(to_date);
+        }
+        params
+    }
+}
+
+// What a PageStart means for a query on an id column.
+struct PageQuery {
+    // Id to compare to and whether the results have to
+    // be greater than it.
+    keyset: Option<(i32, bool)>,
+    offset: usize,
+    order: Order,
+    // Before pages are fetched backwards from the id, the
+    // results then have to be reversed.
+    reverse: bool,
+}
+
+impl PageStart {
+    fn to_query(self, order: Order) -> PageQuery {
+        match self {
+            PageStart::Offset(offset) => PageQuery {
+                keyset: None,
+                offset,
+                order,
+                reverse: false,
+            },
+            PageStart::After(id) => PageQuery {
+                keyset: Some((id, order == Order::Asc)),
+                offset: 0,
+                order,
+                reverse: false,
+            },
+            PageStart::Before(id) => PageQuery {
+                keyset: Some((id, order == Order::Desc)),
+                offset: 0,
+                order: order.reversed(),
+                reverse: true,
+            },
+        }
+    }
+}
+
 // Stole most of the signature from the rustqlite doc.
 // Careful to use a later version of the crate,
 // Google takes you to old versions of the doc.
@@ -71,6 +259,33 @@ where
         .context("Generic select_one query")
 }
 
+// Runs database code on tokio's blocking thread pool so
+// that async callers (the actix handlers) never wait on
+// SQLite themselves. The closure gets its own clone of
+// the pool.
+pub async fn run_blocking<F, T>(pool: &Pool, f: F) -> Result<T>
+where
+    F: FnOnce(&Pool) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || f(&pool))
+        .await
+        .context("Blocking database task failed")?
+}
+
+// Connections wait up to busy_timeout (in milliseconds) for
+// locks held by other connections instead of failing right
+// away with SQLITE_BUSY.
+pub fn open_pool(path: &str, max_size: u32, busy_timeout: u64) -> Result<Pool> {
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(path)
+        .with_init(move |c| c.busy_timeout(std::time::Duration::from_millis(busy_timeout)));
+    Pool::builder()
+        .max_size(max_size)
+        .build(manager)
+        .context("Open database connection pool")
+}
+
 fn select_count<P>(pool: &Pool, query: &str, params: P) -> Result<i64>
 where
     P: IntoIterator,
@@ -86,14 +301,49 @@ Reusable mappers and query functions
 ------------------------------------------------------
 */
 
+// Fields for the full article queries. The author name and
+// comment count are subqueries so that a page of articles
+// doesn't cost three extra queries per article anymore.
+// The comment count comes after these, then the content,
+// which has to stay last since it's not always selected.
+const ARTICLE_FIELDS: &[&str] = &[
+    "articles.id",
+    "articles.title",
+    "articles.article_url",
+    "articles.thumb_image",
+    "articles.date",
+    "articles.user_id",
+    "articles.summary",
+    "articles.published",
+    "articles.short",
+    "(SELECT name FROM users WHERE users.id = articles.user_id) AS author",
+];
+
+lazy_static! {
+    // Built from the enum so that it counts the same
+    // comments as the comment queries do.
+    static ref COMMENTS_COUNT_FIELD: String = format!(
+        "(SELECT count(*) FROM comments WHERE comments.article_id = articles.id \
+        AND comments.status = {}) AS comments_count",
+        CommentStatus::Approved.to_i32()
+    );
+}
+
+fn article_fields(with_content: bool) -> Vec<&'static str> {
+    let mut fields = ARTICLE_FIELDS.to_vec();
+    fields.push(COMMENTS_COUNT_FIELD.as_str());
+    if with_content {
+        fields.push("articles.content");
+    }
+    fields
+}
+
+// Tags have to be added with add_tags afterwards.
 fn full_article_mapper(
-    pool: &Pool,
     row: &Row,
     article_type: Option<&ArticleSelector>,
 ) -> Result<Article, rusqlite::Error> {
-    let article_id = row.get(0)?;
     let short: i32 = row.get(8)?;
-    let user_id: i32 = row.get(5)?;
     // Due to how I wrote the mapper function,
     // I have to use the "selector" All for articles
     // or the content is ignored.
@@ -110,14 +360,35 @@ fn full_article_mapper(
             }
         }
     };
-    // Get the tags, username, and comment count:
-    map_article(
-        row,
-        tags_for_article(pool, article_id).map_err(|_| rusqlite::Error::InvalidQuery)?,
-        &article_selector,
-        username_for_id(pool, user_id).map_err(|_| rusqlite::Error::InvalidQuery)?,
-        comment_count(pool, article_id).map_err(|_| rusqlite::Error::InvalidQuery)?,
-    )
+    map_article(row, article_selector, ANONYMOUS_USERNAME)
+}
+
+// Fetches the tags for all the given articles in one go
+// (well, one query per batch of TAGS_BATCH_SIZE articles,
+// SQLite has a limit on the amount of parameters).
+fn add_tags(pool: &Pool, articles: &mut [Article]) -> Result<()> {
+    let conn = pool.clone().get()?;
+    let mut tags: HashMap<i32, Vec<Tag>> = HashMap::new();
+    for batch in articles.chunks(TAGS_BATCH_SIZE) {
+        let query = format!(
+            "SELECT article_tags.article_id, tags.id, tags.name, tags.main_tag \
+        FROM article_tags, tags WHERE article_tags.tag_id = tags.id \
+        AND article_tags.article_id IN ({})",
+            generate_in_placeholders(batch.len())
+        );
+        let mut stmt = conn.prepare(&query)?;
+        let rows = stmt
+            .query_map(batch.iter().map(|a| a.id), map_article_tag)?
+            .collect::<Result<Vec<(i32, Tag)>, _>>()
+            .context("Fetch tags for articles")?;
+        for (article_id, tag) in rows {
+            tags.entry(article_id).or_default().push(tag);
+        }
+    }
+    for article in articles.iter_mut() {
+        article.tags = tags.remove(&article.id).unwrap_or_default();
+    }
+    Ok(())
 }
 
 // Check if something exists by ID. I chose
@@ -128,7 +399,7 @@ fn entry_exists(pool: &Pool, query: &str, id: i32) -> Result<bool> {
 }
 
 // Trying to reuse connections here.
-fn insert_article_tag(connection: &Connection, tag_id: i32, article_id: i32) -> Result<usize> {
+fn insert_article_tag(connection: &rusqlite::Connection, tag_id: i32, article_id: i32) -> Result<usize> {
     let query = Query::new(QueryType::Insert {
         table: "article_tags",
         fields: &["article_id", "tag_id"],
@@ -144,7 +415,7 @@ fn insert_article_tag(connection: &Connection, tag_id: i32, article_id: i32) ->
         .context("Insert tag for article")
 }
 
-fn delete_all_tags_for_article(connection: &Connection, article_id: i32) -> Result<usize> {
+fn delete_all_tags_for_article(connection: &rusqlite::Connection, article_id: i32) -> Result<usize> {
     let query = Query::new(QueryType::Delete {
         table: "article_tags",
     })
@@ -163,7 +434,7 @@ fn insert_article_fulltext(connection: &Connection, article: &Article) -> Result
 // some weird trait to work as a generic for
 // "partial" articles.
 fn insert_article_fulltext_by_values(
-    connection: &Connection,
+    connection: &rusqlite::Connection,
     title: &String,
     content: &Option<String>,
     article_id: i32,
@@ -188,7 +459,7 @@ fn insert_article_fulltext_by_values(
 // the update method uses a different struct
 // entirely, I'd have to implement From and
 // that's useless memory allocation so yeah.
-fn update_article_fulltext(connection: &Connection, article: &ArticleUpdate) -> Result<usize> {
+fn update_article_fulltext(connection: &rusqlite::Connection, article: &ArticleUpdate) -> Result<usize> {
     // We just return Ok(0) immediately if there's
     // nothing to update, we don't error for that
     // case.
@@ -244,49 +515,38 @@ Data access functions
 ------------------------------------------------------
 */
 
-pub fn all_tags(pool: &Pool) -> Result<Vec<Tag>> {
-    select_many(
-        pool,
-        "SELECT id, name, main_tag FROM tags ORDER BY name ASC",
-        NO_PARAMS,
-        map_tag,
-    )
+// Only published articles and shorts are counted.
+pub fn all_tags_with_counts(pool: &Pool) -> Result<Vec<TagArticleCount>> {
+    let count_query = |short: i32| {
+        format!(
+            "(SELECT count(*) FROM article_tags, articles \
+        WHERE article_tags.tag_id = tags.id \
+        AND articles.id = article_tags.article_id \
+        AND articles.published = 1 AND articles.short = {})",
+            short
+        )
+    };
+    let query = format!(
+        "SELECT id, name, main_tag, {}, {} FROM tags ORDER BY name ASC",
+        count_query(0),
+        count_query(1)
+    );
+    select_many(pool, &query, NO_PARAMS, map_tag_article_count)
 }
 
-pub fn comment_count(pool: &Pool, article_id: i32) -> Result<i64> {
+// Pagination of comments is based on top-level comments only.
+// Comments of unpublished or trashed articles aren't
+// counted, same as in comments_from_to.
+pub fn top_level_comment_count(pool: &Pool, article_id: i32) -> Result<i64> {
     select_count(
         pool,
-        "SELECT count(*) FROM comments WHERE article_id = ?",
-        params![article_id],
-    )
-}
-
-pub fn tags_for_article(pool: &Pool, article_id: i32) -> Result<Vec<Tag>> {
-    select_many(
-        pool,
-        "SELECT tags.id, tags.name, tags.main_tag 
-    FROM article_tags, tags WHERE 
-    article_tags.article_id = ? 
-    AND article_tags.tag_id = tags.id",
-        params![article_id],
-        map_tag,
+        "SELECT count(*) FROM comments WHERE article_id = ? \
+    AND status = ? AND parent_id IS NULL \
+    AND article_id IN (SELECT id FROM articles WHERE published = 1 AND deleted_at IS NULL)",
+        params![article_id, CommentStatus::Approved.to_i32()],
     )
 }
 
-pub fn username_for_id(pool: &Pool, user_id: i32) -> Result<String> {
-    let conn = pool.clone().get()?;
-    // The old API was substituting "Anonymous" to possibly invalid/unknown
-    // user IDs during database row processing, doing the same here.
-    let mut stmt = conn.prepare("SELECT name FROM users WHERE id = ?")?;
-    stmt.query_row(params![user_id], |row| -> Result<String, rusqlite::Error> {
-        Ok(row.get(0)?)
-    })
-    .optional()
-    .context(format!("Fetch usename for user_id {}", user_id))?
-    // Need to recreate a Result after we unwrapped the Option:
-    .map_or(Ok(ANONYMOUS_USERNAME.to_string()), |username| Ok(username))
-}
-
 // Trying to upgrade from the horrible mess I had in the Java app
 // for article retrieval.
 // The same function has to be able to retrieve ALL articles too.
@@ -298,29 +558,18 @@ pub fn username_for_id(pool: &Pool, user_id: i32) -> Result<String> {
 pub fn articles_from_to(
     pool: &Pool,
     article_selector: &ArticleSelector,
-    start: usize,
+    start: PageStart,
     count: usize,
-    tags: &Option<Vec<&str>>,
+    filter: &ArticleFilter,
     order: Order,
 ) -> Result<Vec<Article>> {
-    let mut from = vec!["articles"];
-    let mut fields = vec![
-        "articles.id",
-        "articles.title",
-        "articles.article_url",
-        "articles.thumb_image",
-        "articles.date",
-        "articles.user_id",
-        "articles.summary",
-        "articles.published",
-        "articles.short",
-    ];
     // Add the article content to the fields list when
     // ArticleSelector is ALL or SHORT (we don't add it
     // to ARTICLES because these have huge content):
-    if let ArticleSelector::All | ArticleSelector::Short = article_selector {
-        fields.push("articles.content");
-    }
+    let fields = article_fields(match article_selector {
+        ArticleSelector::All | ArticleSelector::Short => true,
+        ArticleSelector::Article => false,
+    });
     let mut q_where = vec!["articles.published = 1"];
     // Kinda redundant, "if let" above is almost the same check
     match article_selector {
@@ -330,51 +579,50 @@ pub fn articles_from_to(
     }
     // Have to declare this here as it has to live as long as the
     // q_where vector does.
-    // I could just use a copy and fix this but uh... Yeah.
-    let placeholders: String;
-    if let Some(tag_list) = &tags {
-        if tag_list.len() > 0 {
-            // Append actually drains ("move" is more accurate) the
-            // provided vector, so it needs a mutable one.
-            from.append(&mut vec!["article_tags", "tags"]);
-            q_where.push(
-                "(tags.id = article_tags.tag_id AND \
-        article_tags.article_id = articles.id)",
-            );
-            placeholders = generate_where_placeholders("tags.name", tag_list.len());
-            q_where.push(placeholders.as_str());
-        }
+    let filter_clauses = filter.where_clauses();
+    q_where.extend(filter_clauses.iter().map(String::as_str));
+    let page = start.to_query(order);
+    if let Some((_, greater)) = page.keyset {
+        q_where.push(if greater {
+            "articles.id > ?"
+        } else {
+            "articles.id < ?"
+        });
     }
     // Build the query. I order by id and not by date for
     // performance reasons. I don't know, it's historical.
     let query = Query::new(QueryType::Select {
-        from: &from,
+        from: &["articles"],
         fields: &fields,
     })
     .where_and(&q_where)
-    .order(OrderBy::new(order, "articles.id"))
+    .order(OrderBy::new(page.order, "articles.id"))
     .limit(count)
-    .offset(start)
+    .offset(page.offset)
     .to_string();
 
     // haven't thought of something more "optimal" than
     // providing an empty vector.
-    let params: Vec<&str> = match tags {
-        Some(ts) => ts.clone(),
-        None => Vec::new(),
-    };
+    let mut params = filter.params();
+    if let Some((id, _)) = &page.keyset {
+        params.push(id);
+    }
 
-    select_many(pool, query.as_str(), params, |row| {
-        full_article_mapper(pool, row, Some(&article_selector))
-    })
+    let mut articles = select_many(pool, query.as_str(), params, |row| {
+        full_article_mapper(row, Some(&article_selector))
+    })?;
+    if page.reverse {
+        articles.reverse();
+    }
+    add_tags(pool, &mut articles)?;
+    Ok(articles)
 }
 
 pub fn article_count(
     pool: &Pool,
     article_selector: &ArticleSelector,
-    tags: &Option<Vec<&str>>,
+    filter: &ArticleFilter,
 ) -> Result<i64> {
-    let mut from = vec!["articles"];
     let mut q_where = vec!["articles.published = 1"];
     // Yes the following lines are a huge copy paste from the function
     // above.
@@ -385,31 +633,12 @@ pub fn article_count(
     }
     // Have to declare this here as it has to live as long as the
     // q_where vector does.
-    // I could just use a copy and fix this but uh... Yeah.
-    let placeholders: String;
-    if let Some(tag_list) = &tags {
-        if tag_list.len() > 0 {
-            // Append actually drains ("move" is more accurate) the
-            // provided vector, so it needs a mutable one.
-            from.append(&mut vec!["article_tags", "tags"]);
-            q_where.push(
-                "(tags.id = article_tags.tag_id AND \
-        article_tags.article_id = articles.id)",
-            );
-            placeholders = generate_where_placeholders("tags.name", tag_list.len());
-            q_where.push(placeholders.as_str());
-        }
-    }
-
-    // haven't thought of something more "optimal" than
-    // providing an empty vector.
-    let params: Vec<&str> = match tags {
-        Some(ts) => ts.clone(),
-        None => Vec::new(),
-    };
+    let filter_clauses = filter.where_clauses();
+    q_where.extend(filter_clauses.iter().map(String::as_str));
+    let params = filter.params();
 
     let query = Query::new(QueryType::Select {
-        from: &from,
+        from: &["articles"],
         fields: &["count(*)"],
     })
     .where_and(&q_where)
@@ -421,6 +650,8 @@ pub fn article_count(
 // I use this to check for article existence
 // because fetching the whole article + tags
 // etc is more costly.
+// Finds unpublished and trashed articles too, since their
+// URL can't be taken by another article.
 pub fn article_id_by_url(pool: &Pool, url: &str) -> Result<Option<i32>> {
     select_one(
         pool,
@@ -430,40 +661,67 @@ pub fn article_id_by_url(pool: &Pool, url: &str) -> Result<Option<i32>> {
     )
 }
 
+// Returns unpublished articles too, but not the ones
+// in the trash.
 pub fn article_by_id(pool: &Pool, id: i32) -> Result<Option<Article>> {
-    select_one(
-        pool,
-        "SELECT id, title, article_url, thumb_image, date, user_id, \
-    summary, published, short, content FROM articles WHERE id = ?",
-        params![id],
-        |row| full_article_mapper(&pool, &row, None),
-    )
+    single_article(pool, "articles.id = ? AND articles.deleted_at IS NULL", id, false)
 }
 
-pub fn article_by_url(pool: &Pool, url: &str) -> Result<Option<Article>> {
-    select_one(
-        pool,
-        "SELECT id, title, article_url, thumb_image, date, user_id, \
-    summary, published, short, content FROM articles \
-    WHERE article_url = ?",
-        params![url],
-        |row| full_article_mapper(&pool, &row, None),
-    )
+fn single_article<T: ToSql>(
+    pool: &Pool,
+    q_where: &'static str,
+    value: T,
+    published_only: bool,
+) -> Result<Option<Article>> {
+    let mut q_where = vec![q_where];
+    if published_only {
+        q_where.push("articles.published = 1");
+    }
+    let query = Query::new(QueryType::Select {
+        from: &["articles"],
+        fields: &article_fields(true),
+    })
+    .where_and(&q_where)
+    .to_string();
+    match select_one(pool, &query, params![value], |row| {
+        full_article_mapper(row, None)
+    })? {
+        Some(article) => {
+            let mut articles = [article];
+            add_tags(pool, &mut articles)?;
+            let [article] = articles;
+            Ok(Some(article))
+        }
+        None => Ok(None),
+    }
 }
 
-pub fn article_by_id_or_url(pool: &Pool, article_url: &str) -> Result<Option<Article>> {
+// What the public endpoints use, drafts are only visible
+// through preview links.
+pub fn published_article_by_id_or_url(pool: &Pool, article_url: &str) -> Result<Option<Article>> {
     match article_url.parse::<i32>() {
         // Fetch article by id:
-        Ok(article_id) => article_by_id(pool, article_id),
+        Ok(article_id) => single_article(pool, "articles.id = ?", article_id, true),
         // Fetch article by URL:
-        Err(_) => article_by_url(pool, &article_url),
+        Err(_) => single_article(pool, "articles.article_url = ?", article_url, true),
     }
 }
 
+// Trashed articles don't exist anymore as far as
+// updating them is concerned.
 pub fn article_exists(pool: &Pool, id: i32) -> Result<bool> {
     entry_exists(
         pool,
-        "SELECT count(*) FROM articles WHERE id = ? LIMIT 1",
+        "SELECT count(*) FROM articles WHERE id = ? AND deleted_at IS NULL LIMIT 1",
+        id,
+    )
+}
+
+pub fn published_article_exists(pool: &Pool, id: i32) -> Result<bool> {
+    entry_exists(
+        pool,
+        "SELECT count(*) FROM articles WHERE id = ? AND published = 1 \
+    AND deleted_at IS NULL LIMIT 1",
         id,
     )
 }
@@ -476,6 +734,229 @@ pub fn user_exists(pool: &Pool, id: i32) -> Result<bool> {
     entry_exists(pool, "SELECT count(*) FROM users WHERE id = ? LIMIT 1", id)
 }
 
+pub fn tag_by_id(pool: &Pool, id: i32) -> Result<Option<Tag>> {
+    select_one(
+        pool,
+        "SELECT id, name, main_tag FROM tags WHERE id = ?",
+        params![id],
+        map_tag,
+    )
+}
+
+pub fn tag_by_name(pool: &Pool, name: &str) -> Result<Option<Tag>> {
+    select_one(
+        pool,
+        "SELECT id, name, main_tag FROM tags WHERE name = ?",
+        params![name],
+        map_tag,
+    )
+}
+
+// main_tag is really a boolean, I'm making sure nothing
+// else than 0 or 1 ends up in there.
+pub fn insert_tag(pool: &Pool, tag: &mut Tag) -> Result<i32> {
+    let query = Query::new(QueryType::Insert {
+        table: "tags",
+        fields: &["name", "main_tag"],
+        values: None,
+    })
+    .to_string();
+    tag.main_tag = main_tag_value(tag.main_tag);
+    let conn = pool.clone().get()?;
+    let mut stmt = conn.prepare(&query)?;
+    stmt.execute(params![tag.name, tag.main_tag])
+        .context("Insert tag")?;
+    let id: i32 = i32::try_from(conn.last_insert_rowid())?;
+    tag.id = id;
+    Ok(id)
+}
+
+// Returns Ok(0) when there's nothing to update or when
+// the tag doesn't exist, same as udpate_article.
+pub fn update_tag(
+    pool: &Pool,
+    id: i32,
+    name: Option<&str>,
+    main_tag: Option<i32>,
+) -> Result<usize> {
+    let mut fields: Vec<String> = Vec::new();
+    let mut values: Vec<&dyn ToSql> = Vec::new();
+    if let Some(name) = &name {
+        fields.push(generate_field_equal_qmark("name"));
+        values.push(name);
+    }
+    let main_tag = main_tag.map(main_tag_value);
+    if let Some(main_tag) = &main_tag {
+        fields.push(generate_field_equal_qmark("main_tag"));
+        values.push(main_tag);
+    }
+    if fields.is_empty() {
+        return Ok(0);
+    }
+    values.push(&id);
+    let query = Query::new(QueryType::Update {
+        table: "tags",
+        fields: &fields.iter().map(|s| s as &str).collect::<Vec<&str>>(),
+    })
+    .where_clause("id = ?")
+    .to_string();
+    let conn = pool.clone().get()?;
+    let mut stmt = conn.prepare(&query)?;
+    stmt.execute(values).context("Update tag")
+}
+
+// Removes the tag from every article it was on too.
+// Returns the amount of tags deleted (so 0 or 1).
+pub fn delete_tag(pool: &Pool, id: i32) -> Result<usize> {
+    let mut conn = pool.clone().get()?;
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM article_tags WHERE tag_id = ?", params![id])
+        .context("Delete tag from articles")?;
+    let count = tx
+        .execute("DELETE FROM tags WHERE id = ?", params![id])
+        .context("Delete tag")?;
+    tx.commit()?;
+    Ok(count)
+}
+
+// Moves every article from the source tag to the target
+// tag and deletes the source tag. Articles that had both
+// tags just keep the target one. The target becomes a
+// main tag if any of the two was one.
+// Returns the amount of articles that were moved to the
+// target tag.
+// Doesn't check if the tags exist, that's up to the caller.
+pub fn merge_tags(pool: &Pool, source_id: i32, target_id: i32) -> Result<usize> {
+    let mut conn = pool.clone().get()?;
+    let tx = conn.transaction()?;
+    let moved = tx
+        .execute(
+            "INSERT OR IGNORE INTO article_tags (article_id, tag_id) \
+        SELECT article_id, ? FROM article_tags WHERE tag_id = ?",
+            params![target_id, source_id],
+        )
+        .context("Move articles to merged tag")?;
+    tx.execute(
+        "UPDATE tags SET main_tag = max(main_tag, \
+        (SELECT main_tag FROM tags WHERE id = ?)) WHERE id = ?",
+        params![source_id, target_id],
+    )
+    .context("Update main_tag of merged tag")?;
+    tx.execute(
+        "DELETE FROM article_tags WHERE tag_id = ?",
+        params![source_id],
+    )
+    .context("Delete tag from articles")?;
+    tx.execute("DELETE FROM tags WHERE id = ?", params![source_id])
+        .context("Delete merged tag")?;
+    tx.commit()?;
+    Ok(moved)
+}
+
+fn main_tag_value(main_tag: i32) -> i32 {
+    if main_tag == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+// The name column was created with an INTEGER type in the
+// original schema (oops), the cast makes sure names that
+// look like numbers still come out as text.
+const USER_FIELDS: &str = "id, CAST(name AS TEXT), bio, avatar, link";
+
+pub fn all_users(pool: &Pool) -> Result<Vec<User>> {
+    select_many(
+        pool,
+        &format!("SELECT {} FROM users ORDER BY name ASC", USER_FIELDS),
+        NO_PARAMS,
+        map_user,
+    )
+}
+
+pub fn user_by_id(pool: &Pool, id: i32) -> Result<Option<User>> {
+    select_one(
+        pool,
+        &format!("SELECT {} FROM users WHERE id = ?", USER_FIELDS),
+        params![id],
+        map_user,
+    )
+}
+
+pub fn user_by_name(pool: &Pool, name: &str) -> Result<Option<User>> {
+    select_one(
+        pool,
+        &format!("SELECT {} FROM users WHERE name = ?", USER_FIELDS),
+        params![name],
+        map_user,
+    )
+}
+
+pub fn insert_user(pool: &Pool, user: &mut User) -> Result<i32> {
+    let query = Query::new(QueryType::Insert {
+        table: "users",
+        fields: &["name", "bio", "avatar", "link"],
+        values: None,
+    })
+    .to_string();
+    let conn = pool.clone().get()?;
+    let mut stmt = conn.prepare(&query)?;
+    stmt.execute(params![user.name, user.bio, user.avatar, user.link])
+        .context("Insert user")?;
+    let id: i32 = i32::try_from(conn.last_insert_rowid())?;
+    user.id = id;
+    Ok(id)
+}
+
+// Returns Ok(0) when there's nothing to update or when
+// the user doesn't exist.
+pub fn update_user(pool: &Pool, user: &UserUpdate) -> Result<usize> {
+    let mut fields: Vec<String> = Vec::new();
+    let mut values: Vec<&dyn ToSql> = Vec::new();
+    if let Some(name) = &user.name {
+        fields.push(generate_field_equal_qmark("name"));
+        values.push(name);
+    }
+    // The inner Option is NULL when it's None.
+    for (field, value) in [("bio", &user.bio), ("avatar", &user.avatar), ("link", &user.link)].iter() {
+        if let Some(value) = value {
+            fields.push(generate_field_equal_qmark(field));
+            values.push(value);
+        }
+    }
+    if fields.is_empty() {
+        return Ok(0);
+    }
+    values.push(&user.id);
+    let query = Query::new(QueryType::Update {
+        table: "users",
+        fields: &fields.iter().map(|s| s as &str).collect::<Vec<&str>>(),
+    })
+    .where_clause("id = ?")
+    .to_string();
+    let conn = pool.clone().get()?;
+    let mut stmt = conn.prepare(&query)?;
+    stmt.execute(values).context("Update user")
+}
+
+// Doesn't check for articles, the API doesn't allow deleting
+// users that still have some.
+pub fn delete_user(pool: &Pool, id: i32) -> Result<usize> {
+    let conn = pool.clone().get()?;
+    conn.execute("DELETE FROM users WHERE id = ?", params![id])
+        .context("Delete user")
+}
+
+// Counts all the articles and shorts, published or not.
+pub fn user_article_count(pool: &Pool, id: i32) -> Result<i64> {
+    select_count(
+        pool,
+        "SELECT count(*) FROM articles WHERE user_id = ?",
+        params![id],
+    )
+}
+
 // Returns a result with the ID of the inserted article when
 // successful.
 pub fn insert_article(pool: &Pool, article: &mut Article) -> Result<i32> {
@@ -557,30 +1038,318 @@ pub fn delete_article(pool: &Pool, article_id: i32) -> Result<usize> {
     stmt.execute(parms).context("Delete article")
 }
 
-pub fn update_date_and_publish(pool: &Pool, article_id: i32) -> Result<usize> {
-    let conn = pool.clone().get()?;
+// Moves the article to the trash. Trashed articles are
+// unpublished so that every query that checks "published"
+// leaves them out, same as scheduled articles. Their
+// schedule is kept but the scheduler ignores them.
+// Returns Ok(0) if the article doesn't exist or is
+// already in the trash.
+pub fn trash_article_tx(conn: &rusqlite::Connection, article_id: i32, now: i64) -> Result<usize> {
+    conn.execute(
+        "UPDATE articles SET deleted_at = ?, deleted_published = published, published = 0 \
+    WHERE id = ? AND deleted_at IS NULL",
+        params![now, article_id],
+    )
+    .context("Trash article")
+}
+
+// Puts the article back the way it was when it got
+// trashed.
+pub fn restore_trashed_article_tx(conn: &rusqlite::Connection, article_id: i32) -> Result<usize> {
+    conn.execute(
+        "UPDATE articles SET published = coalesce(deleted_published, 0), \
+    deleted_at = NULL, deleted_published = NULL \
+    WHERE id = ? AND deleted_at IS NOT NULL",
+        params![article_id],
+    )
+    .context("Restore trashed article")
+}
+
+// Most recently trashed first.
+pub fn trashed_articles(pool: &Pool) -> Result<Vec<TrashedArticle>> {
+    select_many(
+        pool,
+        "SELECT id, title, article_url, short, deleted_at FROM articles \
+    WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC",
+        NO_PARAMS,
+        map_trashed_article,
+    )
+}
+
+// Deletes the articles that were trashed before the given
+// timestamp for good, with their tags, comments and
+// fulltext entry. Revisions are kept.
+// Returns how many articles were deleted.
+pub fn purge_trash(pool: &Pool, before: i64) -> Result<usize> {
+    let ids: Vec<i32> = select_many(
+        pool,
+        "SELECT id FROM articles WHERE deleted_at IS NOT NULL AND deleted_at < ?",
+        params![before],
+        |row| row.get(0),
+    )?;
+    for id in ids.iter() {
+        delete_article(pool, *id)?;
+    }
+    Ok(ids.len())
+}
+
+// Cancels the publication schedule if there was one.
+pub fn update_date_and_publish_tx(conn: &rusqlite::Connection, article_id: i32) -> Result<usize> {
     let d_f: &str = &generate_field_equal_qmark("date");
     let d_p: &str = &generate_field_equal_qmark("published");
-    let fields = [d_f, d_p];
+    let fields = [d_f, d_p, "publish_at = NULL"];
     let query = Query::new(QueryType::Update {
         table: "articles",
         fields: &fields,
     })
-    .where_clause("id = ?");
+    .where_and(&["id = ?", "deleted_at IS NULL"]);
     let mut stmt = conn.prepare(&query.to_string())?;
     let parms = params![current_timestamp(), 1, article_id];
     let result = stmt.execute(parms)?;
     Ok(result)
 }
 
-// Updating articles is weird in that we check for
-// the presence of fields to update or we don't touch
-// them (because the API expects this behavior).
-// We don't check if the article exists here, will just
-// return Ok(0) if nothing happened.
-// Also, was complaining about code repetition before,
+const REVISION_FIELDS: &str = "id, article_id, action, author, date, title, article_url, \
+    thumb_image, article_date, user_id, summary, content, published, short, tag_ids";
+
+// Saves the current state of the article as a revision,
+// then applies the change in the same transaction. The
+// change is given a plain connection, meant for the
+// *_tx functions. When the change fails or doesn't
+// affect anything, the revision isn't kept either.
+// Returns what the change returned.
+pub fn with_revision<F>(
+    pool: &Pool,
+    article_id: i32,
+    action: RevisionAction,
+    author: &str,
+    change: F,
+) -> Result<usize>
+where
+    F: FnOnce(&rusqlite::Connection) -> Result<usize>,
+{
+    let mut conn = pool.clone().get()?;
+    let tx = conn.transaction()?;
+    insert_revision(&tx, article_id, action, author)?;
+    let count = change(&tx)?;
+    // Dropping the transaction rolls it back.
+    if count > 0 {
+        tx.commit()?;
+    }
+    Ok(count)
+}
+
+// Takes a plain connection so that it also works inside
+// a transaction.
+fn insert_revision(
+    conn: &rusqlite::Connection,
+    article_id: i32,
+    action: RevisionAction,
+    author: &str,
+) -> Result<usize> {
+    conn.execute(
+        "INSERT INTO article_revisions (article_id, action, author, date, title, \
+    article_url, thumb_image, article_date, user_id, summary, content, published, \
+    short, tag_ids) \
+    SELECT id, ?, ?, ?, title, article_url, thumb_image, date, user_id, summary, \
+    content, published, short, \
+    (SELECT group_concat(tag_id) FROM article_tags \
+    WHERE article_tags.article_id = articles.id) \
+    FROM articles WHERE id = ?",
+        params![action.to_string(), author, current_timestamp(), article_id],
+    )
+    .context("Save article revision")
+}
+
+// Newest first, without the content. Works for deleted
+// articles too.
+pub fn article_revisions(pool: &Pool, article_id: i32) -> Result<Vec<ArticleRevision>> {
+    select_many(
+        pool,
+        &format!(
+            "SELECT {} FROM article_revisions WHERE article_id = ? ORDER BY id DESC",
+            REVISION_FIELDS.replace("content", "NULL")
+        ),
+        params![article_id],
+        map_article_revision,
+    )
+}
+
+pub fn article_revision(
+    pool: &Pool,
+    article_id: i32,
+    revision_id: i32,
+) -> Result<Option<ArticleRevision>> {
+    select_one(
+        pool,
+        &format!(
+            "SELECT {} FROM article_revisions WHERE id = ? AND article_id = ?",
+            REVISION_FIELDS
+        ),
+        params![revision_id, article_id],
+        map_article_revision,
+    )
+}
+
+// Current state of an article in the same shape as a
+// revision, so that revisions can be compared to it.
+pub fn current_article_revision(pool: &Pool, article_id: i32) -> Result<Option<ArticleRevision>> {
+    select_one(
+        pool,
+        "SELECT 0, id, 'current', NULL, ?, title, article_url, thumb_image, date, \
+    user_id, summary, content, published, short, \
+    (SELECT group_concat(tag_id) FROM article_tags \
+    WHERE article_tags.article_id = articles.id) \
+    FROM articles WHERE id = ?",
+        params![current_timestamp(), article_id],
+        map_article_revision,
+    )
+}
+
+// Puts the article back the way it was in the revision,
+// the current state is saved as a "restore" revision
+// first. The published status and schedule are left
+// alone, except for purged articles which are created
+// again with their old ID (their comments are gone for
+// good though). Tags that don't exist anymore are
+// skipped. The fulltext index is updated too.
+// Doesn't check for duplicate article URLs.
+pub fn restore_article_revision(pool: &Pool, revision: &ArticleRevision, author: &str) -> Result<()> {
+    let mut conn = pool.clone().get()?;
+    let tx = conn.transaction()?;
+    let article_id = revision.article_id;
+    let exists = insert_revision(&tx, article_id, RevisionAction::Restore, author)? > 0;
+    if exists {
+        tx.execute(
+            "UPDATE articles SET title = ?, article_url = ?, thumb_image = ?, \
+        date = ?, user_id = ?, summary = ?, content = ? WHERE id = ?",
+            params![
+                revision.title,
+                revision.article_url,
+                revision.thumb_image,
+                revision.article_date,
+                revision.user_id,
+                revision.summary,
+                revision.content,
+                article_id
+            ],
+        )
+        .context("Restore article")?;
+    } else {
+        tx.execute(
+            "INSERT INTO articles (id, title, article_url, thumb_image, date, \
+        user_id, summary, content, published, short) \
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                article_id,
+                revision.title,
+                revision.article_url,
+                revision.thumb_image,
+                revision.article_date,
+                revision.user_id,
+                revision.summary,
+                revision.content,
+                revision.published,
+                revision.short
+            ],
+        )
+        .context("Restore deleted article")?;
+    }
+    tx.execute(
+        "DELETE FROM article_tags WHERE article_id = ?",
+        params![article_id],
+    )?;
+    for tag_id in revision.tag_ids.iter() {
+        tx.execute(
+            "INSERT INTO article_tags (article_id, tag_id) SELECT ?, id FROM tags WHERE id = ?",
+            params![article_id, tag_id],
+        )
+        .context("Restore article tags")?;
+    }
+    tx.execute("DELETE FROM articles_ft WHERE id = ?", params![article_id])?;
+    tx.execute(
+        "INSERT INTO articles_ft (id, title, content) VALUES (?, ?, ?)",
+        params![
+            article_id,
+            revision.title,
+            stripped_article_content(&revision.content)
+        ],
+    )
+    .context("Reindex restored article")?;
+    tx.commit()?;
+    Ok(())
+}
+
+// Scheduled articles are always unpublished until the
+// scheduler publishes them, so that every query that
+// checks "published" keeps working as is.
+// Giving None cancels the schedule but leaves the
+// article unpublished.
+pub fn schedule_article(pool: &Pool, article_id: i32, publish_at: Option<i64>) -> Result<usize> {
+    let conn = pool.clone().get()?;
+    schedule_article_tx(&conn, article_id, publish_at)
+}
+
+pub fn schedule_article_tx(
+    conn: &rusqlite::Connection,
+    article_id: i32,
+    publish_at: Option<i64>,
+) -> Result<usize> {
+    match publish_at {
+        Some(publish_at) => conn.execute(
+            "UPDATE articles SET publish_at = ?, published = 0 \
+        WHERE id = ? AND deleted_at IS NULL",
+            params![publish_at, article_id],
+        ),
+        None => conn.execute(
+            "UPDATE articles SET publish_at = NULL WHERE id = ? AND deleted_at IS NULL",
+            params![article_id],
+        ),
+    }
+    .context("Schedule article")
+}
+
+// Publishes the articles whose time has come, their date
+// becomes the scheduled date. Their fulltext row is already
+// there, unpublished articles are indexed too.
+// Returns the amount of articles that got published.
+pub fn publish_scheduled_articles(pool: &Pool, now: i64) -> Result<usize> {
+    let conn = pool.clone().get()?;
+    conn.execute(
+        "UPDATE articles SET published = 1, date = publish_at, publish_at = NULL \
+    WHERE publish_at IS NOT NULL AND publish_at <= ? AND deleted_at IS NULL",
+        params![now],
+    )
+    .context("Publish scheduled articles")
+}
+
+pub fn next_publish_at(pool: &Pool) -> Result<Option<i64>> {
+    select_one(
+        pool,
+        "SELECT min(publish_at) FROM articles \
+    WHERE publish_at IS NOT NULL AND deleted_at IS NULL",
+        NO_PARAMS,
+        |row| row.get(0),
+    )
+    .map(Option::flatten)
+}
+
+// Updating articles is weird in that we check for
+// the presence of fields to update or we don't touch
+// them (because the API expects this behavior).
+// We don't check if the article exists here, will just
+// return Ok(0) if nothing happened.
+// Also, was complaining about code repetition before,
 // this function put it in a different perspective.
+// Only data-transform uses this one, the API goes
+// through with_revision.
+#[allow(dead_code)]
 pub fn udpate_article(pool: &Pool, article: &ArticleUpdate) -> Result<usize> {
+    let conn = pool.clone().get()?;
+    update_article_tx(&conn, article)
+}
+
+pub fn update_article_tx(conn: &rusqlite::Connection, article: &ArticleUpdate) -> Result<usize> {
     // Gotta use Strings or I get a whole bunch of
     // temporary values dropped in my evil "if let"
     // mania below.
@@ -627,6 +1396,10 @@ pub fn udpate_article(pool: &Pool, article: &ArticleUpdate) -> Result<usize> {
     if let Some(published) = &article.published {
         fields.push(generate_field_equal_qmark("published"));
         values.push(published);
+        // Articles that get published aren't scheduled anymore.
+        if *published == 1 {
+            fields.push(String::from("publish_at = NULL"));
+        }
     }
     // Check that there's at least one field OR that tags are present.
     // If not, we return Ok(0) immediately.
@@ -638,7 +1411,6 @@ pub fn udpate_article(pool: &Pool, article: &ArticleUpdate) -> Result<usize> {
     match (got_fields, got_tags) {
         (false, false) => Ok(0), // Return immediately, no error
         _ => {
-            let conn = pool.clone().get()?;
             let mut result = 0;
             if got_fields {
                 // update the article ; Need to transform the Vec of Strings to
@@ -656,14 +1428,14 @@ pub fn udpate_article(pool: &Pool, article: &ArticleUpdate) -> Result<usize> {
                 let mut stmt = conn.prepare(&query)?;
                 result = stmt.execute(values)?;
                 // Update the fulltext data:
-                update_article_fulltext(&conn, &article)?;
+                update_article_fulltext(conn, article)?;
             }
             if let Some(tags) = &article.tags {
                 // Delete all tags and re-add them all.
                 // This is easier than checking what's there or not.
-                delete_all_tags_for_article(&conn, article.id)?;
+                delete_all_tags_for_article(conn, article.id)?;
                 for tag in tags.iter() {
-                    insert_article_tag(&conn, tag.id, article.id)?;
+                    insert_article_tag(conn, tag.id, article.id)?;
                     result += 1;
                 }
             }
@@ -674,8 +1446,17 @@ pub fn udpate_article(pool: &Pool, article: &ArticleUpdate) -> Result<usize> {
 
 // Rebuilds the entire fulltext index from the articles table.
 pub fn rebuild_fulltext(pool: &Pool) -> Result<usize> {
-    // SELECT id, title, content FROM articles WHERE published = 1 ORDER BY id ASC
     let conn = pool.clone().get()?;
+    reindex_fulltext(&conn)
+}
+
+// Also used by the migration that changed the tokenizer,
+// hence the plain connection.
+// Unpublished articles (drafts, scheduled or trashed) are
+// indexed too, same as insert_article does, otherwise
+// they'd be missing once published. The queries on the
+// index all check articles.published anyway.
+fn reindex_fulltext(conn: &rusqlite::Connection) -> Result<usize> {
     // Delete all the current fulltext info.
     // Doesn't need to be a prepared statement but I use them everywhere
     // anyway for convenience and future-proofing.
@@ -683,16 +1464,15 @@ pub fn rebuild_fulltext(pool: &Pool) -> Result<usize> {
     stmt.execute(NO_PARAMS)?;
 
     let mut stmt = conn.prepare(
-        "SELECT id, title, content FROM articles \
-    WHERE published = 1 ORDER BY id ASC",
+        "SELECT id, title, content FROM articles ORDER BY id ASC",
     )?;
     let mut rows = stmt.query(NO_PARAMS)?;
     let mut i = 0;
     while let Some(row) = rows.next()? {
         let id: i32 = row.get(0)?;
         let title: String = row.get(1)?;
-        let content: String = row.get(2)?;
-        insert_article_fulltext_by_values(&conn, &title, &Some(content), id)?;
+        let content: Option<String> = row.get(2)?;
+        insert_article_fulltext_by_values(conn, &title, &content, id)?;
         i += 1;
     }
     Ok(i)
@@ -702,7 +1482,15 @@ pub fn rebuild_fulltext(pool: &Pool) -> Result<usize> {
 pub fn insert_comment(pool: &Pool, comment: &mut Comment) -> Result<i32> {
     let query = Query::new(QueryType::Insert {
         table: "comments",
-        fields: &["article_id", "author", "comment", "date", "client_ip"],
+        fields: &[
+            "article_id",
+            "author",
+            "comment",
+            "date",
+            "status",
+            "parent_id",
+            "client_ip",
+        ],
         values: None,
     })
     .to_string();
@@ -713,6 +1501,8 @@ pub fn insert_comment(pool: &Pool, comment: &mut Comment) -> Result<i32> {
         comment.author,
         comment.comment,
         comment.date,
+        comment.status.to_i32(),
+        comment.parent_id,
         comment.client_ip
     ])?;
     // Could be an error if the id is too large to fit inside i32.
@@ -724,22 +1514,58 @@ pub fn insert_comment(pool: &Pool, comment: &mut Comment) -> Result<i32> {
     Ok(id)
 }
 
+// Leaves out comments of unpublished or trashed articles,
+// same as top_level_comment_count.
 pub fn last_comment(pool: &Pool) -> Result<Option<Comment>> {
     select_one(
         &pool,
-        "SELECT id, article_id, author, comment, date \
-     FROM comments ORDER BY id DESC LIMIT 1",
-        NO_PARAMS,
+        "SELECT id, article_id, author, comment, date, status, parent_id \
+     FROM comments WHERE status = ? \
+     AND article_id IN (SELECT id FROM articles WHERE published = 1 AND deleted_at IS NULL) \
+     ORDER BY id DESC LIMIT 1",
+        params![CommentStatus::Approved.to_i32()],
         map_comment,
     )
 }
 
+pub fn comment_by_id(pool: &Pool, comment_id: i32) -> Result<Option<Comment>> {
+    select_one(
+        pool,
+        "SELECT id, article_id, author, comment, date, status, parent_id \
+     FROM comments WHERE id = ?",
+        params![comment_id],
+        map_comment,
+    )
+}
+
+// Gets a page of top-level comments and all of their
+// (approved) replies, however deep they go, ordered by id.
+// Replies to comments that aren't approved are never
+// returned.
+// Pages go through top-level comments, oldest first, and
+// come with all of their approved replies.
 pub fn comments_from_to(
     pool: &Pool,
-    start: usize,
+    start: PageStart,
     count: usize,
     article_id: i32,
 ) -> Result<Vec<Comment>> {
+    let page = start.to_query(Order::Asc);
+    let mut q_where = vec![
+        "articles.id = ?",
+        "articles.id = comments.article_id",
+        "articles.published = 1",
+        "articles.deleted_at IS NULL",
+        "comments.status = ?",
+        "comments.parent_id IS NULL",
+    ];
+    if let Some((_, greater)) = page.keyset {
+        q_where.push(if greater {
+            "comments.id > ?"
+        } else {
+            "comments.id < ?"
+        });
+    }
     let query = Query::new(QueryType::Select {
         from: &["comments", "articles"],
         fields: &[
@@ -748,42 +1574,308 @@ pub fn comments_from_to(
             "comments.author",
             "comments.comment",
             "comments.date",
+            "comments.status",
+            "comments.parent_id",
+        ],
+    })
+    .where_and(&q_where)
+    .order(OrderBy::new(page.order, "comments.id"))
+    .limit(count)
+    .offset(page.offset)
+    .to_string();
+
+    let approved = CommentStatus::Approved.to_i32();
+    let mut params: Vec<&dyn ToSql> = vec![&article_id, &approved];
+    if let Some((id, _)) = &page.keyset {
+        params.push(id);
+    }
+    // Comments get sorted by id at the end, no need to
+    // reverse Before pages.
+    let mut comments = select_many(pool, query.as_str(), params, map_comment)?;
+    if comments.is_empty() {
+        return Ok(comments);
+    }
+    // Recursive CTEs are apparently fine in SQLite.
+    let root_ids: Vec<i32> = comments.iter().map(|c| c.id).collect();
+    let replies_query = format!(
+        "WITH RECURSIVE thread(id) AS (\
+    SELECT id FROM comments WHERE parent_id IN ({}) AND status = ? \
+    UNION ALL \
+    SELECT comments.id FROM comments, thread \
+    WHERE comments.parent_id = thread.id AND comments.status = ?) \
+    SELECT id, article_id, author, comment, date, status, parent_id \
+    FROM comments WHERE id IN (SELECT id FROM thread)",
+        generate_in_placeholders(root_ids.len())
+    );
+    let mut values: Vec<&dyn ToSql> = root_ids.iter().map(|id| id as &dyn ToSql).collect();
+    values.push(&approved);
+    values.push(&approved);
+    comments.append(&mut select_many(
+        pool,
+        &replies_query,
+        values,
+        map_comment,
+    )?);
+    comments.sort_by_key(|c| c.id);
+    Ok(comments)
+}
+
+// Used by the moderation endpoints, which also need to
+// see the client IP address.
+pub fn comments_by_status(
+    pool: &Pool,
+    status: CommentStatus,
+    start: usize,
+    count: usize,
+) -> Result<Vec<Comment>> {
+    let query = Query::new(QueryType::Select {
+        from: &["comments"],
+        fields: &[
+            "id",
+            "article_id",
+            "author",
+            "comment",
+            "date",
+            "status",
+            "parent_id",
+            "client_ip",
         ],
     })
-    .where_and(&["articles.id = ?", "articles.id = comments.article_id"])
-    .order(OrderBy::new(Order::Asc, "comments.id"))
+    .where_clause("status = ?")
+    .order(OrderBy::new(Order::Asc, "id"))
     .limit(count)
     .offset(start)
     .to_string();
 
-    select_many(pool, query.as_str(), params![article_id], map_comment)
+    select_many(pool, query.as_str(), params![status.to_i32()], map_comment)
+}
+
+pub fn update_comment_status(pool: &Pool, comment_id: i32, status: CommentStatus) -> Result<usize> {
+    let conn = pool.clone().get()?;
+    let query = Query::new(QueryType::Update {
+        table: "comments",
+        fields: &["status = ?"],
+    })
+    .where_clause("id = ?")
+    .to_string();
+    let mut stmt = conn.prepare(&query)?;
+    stmt.execute(params![status.to_i32(), comment_id])
+        .context("Update comment status")
+}
+
+// Also deletes all the replies to that comment.
+pub fn delete_comment(pool: &Pool, comment_id: i32) -> Result<usize> {
+    let conn = pool.clone().get()?;
+    let mut stmt = conn.prepare(
+        "WITH RECURSIVE thread(id) AS (\
+    SELECT id FROM comments WHERE id = ? \
+    UNION ALL \
+    SELECT comments.id FROM comments, thread \
+    WHERE comments.parent_id = thread.id) \
+    DELETE FROM comments WHERE id IN (SELECT id FROM thread)",
+    )?;
+    stmt.execute(params![comment_id]).context("Delete comment")
 }
 
 // Uses SQLite fulltext search.
 // WARNING: The API endpoint or whatever is using the DB
 // lib will have to clean the search terms up itself first.
-pub fn search_published_articles<T: AsRef<str>>(pool: &Pool, terms: &[T]) -> Result<Vec<Article>> {
+// Where clause shared by the search and the search count.
+// The MATCH parameter comes first, then the filter ones.
+fn search_where_clause(article_selector: &ArticleSelector, filter: &ArticleFilter) -> String {
+    let mut q_where = vec![
+        String::from("articles_ft MATCH ?"),
+        String::from("articles.id = articles_ft.id"),
+        String::from("articles.published = 1"),
+        String::from("articles.user_id = users.id"),
+    ];
+    match article_selector {
+        ArticleSelector::Article => q_where.push(String::from("articles.short = 0")),
+        ArticleSelector::Short => q_where.push(String::from("articles.short = 1")),
+        _ => (),
+    }
+    q_where.append(&mut filter.where_clauses());
+    q_where.join(" AND ")
+}
+
+fn search_match_terms<T: AsRef<str>>(terms: &[T]) -> String {
+    terms
+        .iter()
+        .map(AsRef::as_ref)
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+// Results are sorted by relevance, which means offsets are
+// the only way to paginate.
+pub fn search_published_articles<T: AsRef<str>>(
+    pool: &Pool,
+    terms: &[T],
+    article_selector: &ArticleSelector,
+    filter: &ArticleFilter,
+    start: usize,
+    count: usize,
+) -> Result<Vec<Article>> {
     // Copy pasted the query from the old backend. It's probably suboptimal.
     // As other things are in here.
-    let query = "SELECT articles_ft.id, articles_ft.title, \
+    let query = format!(
+        "SELECT articles_ft.id, articles_ft.title, \
     articles.article_url, articles.short, articles.date, articles.user_id, \
     snippet(articles_ft, 2, '<b>', '</b>', ' [...] ', 50) AS snippet, users.name \
-    FROM articles_ft, articles, users WHERE articles_ft MATCH ? \
+    FROM articles_ft, articles, users WHERE {} \
+    ORDER BY rank LIMIT ? OFFSET ?",
+        search_where_clause(article_selector, filter)
+    );
+    let match_terms = search_match_terms(terms);
+    let (count, start) = (count as i64, start as i64);
+    let mut params: Vec<&dyn ToSql> = vec![&match_terms];
+    params.append(&mut filter.params());
+    params.push(&count);
+    params.push(&start);
+    select_many(pool, &query, params, map_search_result)
+}
+
+pub fn search_count<T: AsRef<str>>(
+    pool: &Pool,
+    terms: &[T],
+    article_selector: &ArticleSelector,
+    filter: &ArticleFilter,
+) -> Result<i64> {
+    let query = format!(
+        "SELECT count(*) FROM articles_ft, articles, users WHERE {}",
+        search_where_clause(article_selector, filter)
+    );
+    let match_terms = search_match_terms(terms);
+    let mut params: Vec<&dyn ToSql> = vec![&match_terms];
+    params.append(&mut filter.params());
+    select_count(pool, &query, params)
+}
+
+// Terms of the fulltext index starting with the prefix,
+// most used first. Only terms that are in published
+// articles count.
+pub fn vocabulary_completions(pool: &Pool, prefix: &str, count: usize) -> Result<Vec<String>> {
+    // The highest code point sorts after anything that
+    // starts with the prefix.
+    let upper = format!("{}\u{10FFFF}", prefix);
+    select_many(
+        pool,
+        "SELECT v.term FROM articles_ft_instance v, articles_ft, articles \
+    WHERE v.term >= ? AND v.term < ? AND articles_ft.rowid = v.doc \
     AND articles.id = articles_ft.id AND articles.published = 1 \
-    AND articles.user_id = users.id \
-    ORDER BY rank LIMIT 15";
+    GROUP BY v.term ORDER BY count(DISTINCT articles.id) DESC, v.term LIMIT ?",
+        params![prefix, upper, count as i64],
+        |row| row.get(0),
+    )
+}
+
+// Amount of published articles the term is in.
+pub fn published_term_count(pool: &Pool, term: &str) -> Result<i64> {
+    select_count(
+        pool,
+        "SELECT count(DISTINCT articles.id) FROM articles_ft_instance v, articles_ft, articles \
+    WHERE v.term = ? AND articles_ft.rowid = v.doc \
+    AND articles.id = articles_ft.id AND articles.published = 1",
+        params![term],
+    )
+}
+
+// Terms of the fulltext index that are at most
+// max_distance edits away from the word, closest then
+// most used first. Only terms that are in published
+// articles are returned.
+pub fn close_terms(pool: &Pool, word: &str, max_distance: usize, count: usize) -> Result<Vec<String>> {
+    let length = word.chars().count();
+    // Each edit changes the length by one at most.
+    let candidates: Vec<(String, i64)> = select_many(
+        pool,
+        "SELECT term, doc FROM articles_ft_vocab WHERE length(term) BETWEEN ? AND ?",
+        params![
+            length.saturating_sub(max_distance) as i64,
+            (length + max_distance) as i64
+        ],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let mut close: Vec<(usize, i64, String)> = candidates
+        .into_iter()
+        .filter(|(term, _)| term != word)
+        .filter_map(|(term, docs)| {
+            let distance = edit_distance(word, &term);
+            if distance <= max_distance {
+                Some((distance, -docs, term))
+            } else {
+                None
+            }
+        })
+        .collect();
+    close.sort();
+    let mut terms = Vec::new();
+    for (_, _, term) in close {
+        if terms.len() == count {
+            break;
+        }
+        if published_term_count(pool, &term)? > 0 {
+            terms.push(term);
+        }
+    }
+    Ok(terms)
+}
+
+// Published articles that have tags in common with the
+// given one, with the amount of tags they share.
+pub fn articles_sharing_tags(pool: &Pool, article_id: i32) -> Result<Vec<(i32, i64)>> {
     select_many(
         pool,
-        query,
-        params![terms
-            .iter()
-            .map(AsRef::as_ref)
-            .collect::<Vec<&str>>()
-            .join(" ")],
-        map_search_result,
+        "SELECT article_tags.article_id, count(*) FROM article_tags, articles \
+    WHERE article_tags.tag_id IN (SELECT tag_id FROM article_tags WHERE article_id = ?) \
+    AND article_tags.article_id != ? AND articles.id = article_tags.article_id \
+    AND articles.published = 1 GROUP BY article_tags.article_id",
+        params![article_id, article_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
     )
 }
 
+// Best fulltext matches for the MATCH expression among the
+// published articles, except the given one, with their
+// rank (bm25, lower is better).
+pub fn fulltext_matches(
+    pool: &Pool,
+    match_expression: &str,
+    except_id: i32,
+    count: usize,
+) -> Result<Vec<(i32, f64)>> {
+    select_many(
+        pool,
+        "SELECT articles_ft.id, rank FROM articles_ft, articles \
+    WHERE articles_ft MATCH ? AND articles.id = articles_ft.id \
+    AND articles.published = 1 AND articles.id != ? ORDER BY rank LIMIT ?",
+        params![match_expression, except_id, count as i64],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+}
+
+// Published articles and shorts without their content, in
+// the same order as the IDs. IDs that aren't published
+// anymore are skipped.
+pub fn published_articles_by_ids(pool: &Pool, article_ids: &[i32]) -> Result<Vec<Article>> {
+    if article_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let in_clause = format!("articles.id IN ({})", generate_in_placeholders(article_ids.len()));
+    let query = Query::new(QueryType::Select {
+        from: &["articles"],
+        fields: &article_fields(false),
+    })
+    .where_and(&[in_clause.as_str(), "articles.published = 1"])
+    .to_string();
+    let mut articles = select_many(pool, &query, article_ids, |row| {
+        full_article_mapper(row, Some(&ArticleSelector::Article))
+    })?;
+    articles.sort_by_key(|a| article_ids.iter().position(|id| *id == a.id));
+    add_tags(pool, &mut articles)?;
+    Ok(articles)
+}
+
 // Since my stats are in another DB file, they should
 // receive a completely different "pool".
 // The data functions just do the data things, no
@@ -805,6 +1897,7 @@ pub fn insert_article_stat(
             "client_ua",
             "client_ip",
             "date",
+            "is_bot",
         ],
         values: None,
     })
@@ -823,7 +1916,8 @@ pub fn insert_article_stat(
         article_stat.city,
         article_stat.client_ua,
         article_stat.client_ip,
-        article_stat.date.unwrap_or(current_timestamp())
+        article_stat.date.unwrap_or(current_timestamp()),
+        article_stat.is_bot
     ])
     .context("Insert article stats")
     // This is unsed in a multithreaded context, I'd rather
@@ -833,13 +1927,309 @@ pub fn insert_article_stat(
     Ok(id)*/
 }
 
-// Created this to not have to load every single article ever
-// in memory when browsing all articles to create the RSS feed.
-// Yeah I don't know why I bother but that's me.
-pub fn all_published_articles_and_shorts_ids(pool: &Pool, order: Order) -> Result<Vec<i32>> {
-    all_articles_and_shorts_ids(pool, order, true)
+/*
+------------------------------------------------------
+Stats reporting, these functions expect a pool to the
+stats database. Date ranges are timestamps, "from" is
+inclusive and "to" is exclusive. Bots are left out.
+Raw stats that have been rolled up are read from the
+daily tables instead (whether they were deleted or
+anonymized), which only have whole days: the range is
+rounded to days for them. Unique visitors can't be
+told apart across days and articles in these tables,
+so they're the sum of the daily ones there.
+------------------------------------------------------
+*/
+
+const UNIQUE_VISITORS: &str = "count(DISTINCT pseudo_ip || '|' || pseudo_ua)";
+// Conditions for the raw rows and for the daily rows,
+// with ?1 and ?2 being "from" and "to".
+const RAW_STATS_RANGE: &str = "date >= ?1 AND date < ?2 AND rolled_up = 0";
+const DAILY_STATS_RANGE: &str = "day >= strftime('%Y-%m-%d', ?1, 'unixepoch', 'localtime') \
+    AND day < strftime('%Y-%m-%d', ?2, 'unixepoch', 'localtime')";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatsPeriod {
+    Day,
+    Week,
+    Month,
+}
+
+impl StatsPeriod {
+    // SQL expression giving the period of the time value,
+    // which can be followed by modifiers.
+    fn sql_expression(&self, time: &str) -> String {
+        match self {
+            StatsPeriod::Day => format!("strftime('%Y-%m-%d', {})", time),
+            // SQLite's %W isn't the ISO week, which is the
+            // week of its Thursday (weeks start on Monday).
+            StatsPeriod::Week => format!(
+                "strftime('%Y', {thursday}) || '-W' || \
+    printf('%02d', (strftime('%j', {thursday}) - 1) / 7 + 1)",
+                thursday = format!("date({}, '-3 days', 'weekday 4')", time)
+            ),
+            StatsPeriod::Month => format!("strftime('%Y-%m', {})", time),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LocationLevel {
+    Country,
+    Region,
+    City,
+}
+
+pub fn article_views_by_period(
+    pool: &Pool,
+    article_id: i32,
+    period: StatsPeriod,
+    from: i64,
+    to: i64,
+) -> Result<Vec<PeriodViews>> {
+    // The days of the daily table are already local time.
+    let query = format!(
+        "SELECT period, sum(views), sum(visitors) FROM (\
+    SELECT {raw_period} AS period, \
+    count(*) AS views, {unique} AS visitors FROM article_stats \
+    WHERE article_id = ?3 AND {raw} AND is_bot = 0 GROUP BY period \
+    UNION ALL \
+    SELECT {daily_period} AS period, sum(views), sum(unique_visitors) \
+    FROM article_stats_daily WHERE article_id = ?3 AND {daily} GROUP BY period) \
+    GROUP BY period ORDER BY period ASC",
+        raw_period = period.sql_expression("date, 'unixepoch', 'localtime'"),
+        daily_period = period.sql_expression("day"),
+        unique = UNIQUE_VISITORS,
+        raw = RAW_STATS_RANGE,
+        daily = DAILY_STATS_RANGE
+    );
+    select_many(
+        pool,
+        &query,
+        params![from, to, article_id],
+        map_period_views,
+    )
+}
+
+pub fn top_articles(pool: &Pool, from: i64, to: i64, max: usize) -> Result<Vec<ArticleViews>> {
+    let query = format!(
+        "SELECT article_id, sum(views) AS total_views, sum(visitors) FROM (\
+    SELECT article_id, count(*) AS views, {unique} AS visitors FROM article_stats \
+    WHERE {raw} AND is_bot = 0 GROUP BY article_id \
+    UNION ALL \
+    SELECT article_id, sum(views), sum(unique_visitors) FROM article_stats_daily \
+    WHERE {daily} GROUP BY article_id) \
+    GROUP BY article_id ORDER BY total_views DESC, article_id ASC LIMIT ?3",
+        unique = UNIQUE_VISITORS,
+        raw = RAW_STATS_RANGE,
+        daily = DAILY_STATS_RANGE
+    );
+    // rusqlite can't bind an usize.
+    let max = max as i64;
+    select_many(pool, &query, params![from, to, max], map_article_views)
+}
+
+pub fn views_by_location(
+    pool: &Pool,
+    level: LocationLevel,
+    from: i64,
+    to: i64,
+    article_id: Option<i32>,
+) -> Result<Vec<LocationViews>> {
+    // Raw rows can have NULL locations, the daily table
+    // has empty strings instead.
+    let fields = match level {
+        LocationLevel::Country => "ifnull(country, '') AS country, NULL AS region, NULL AS city",
+        LocationLevel::Region => {
+            "ifnull(country, '') AS country, ifnull(region, '') AS region, NULL AS city"
+        }
+        LocationLevel::City => {
+            "ifnull(country, '') AS country, ifnull(region, '') AS region, \
+            ifnull(city, '') AS city"
+        }
+    };
+    let mut values: Vec<&dyn ToSql> = vec![&from, &to];
+    let article_clause = match &article_id {
+        Some(id) => {
+            values.push(id);
+            "AND article_id = ?3"
+        }
+        None => "",
+    };
+    // Region and city are NULL when the level doesn't go
+    // that far, grouping on them doesn't change anything.
+    let query = format!(
+        "SELECT country, region, city, sum(views) AS total_views, sum(visitors) FROM (\
+    SELECT {fields}, count(*) AS views, {unique} AS visitors FROM article_stats \
+    WHERE {raw} AND is_bot = 0 {article} GROUP BY 1, 2, 3 \
+    UNION ALL \
+    SELECT {fields}, sum(views), sum(unique_visitors) FROM country_stats_daily \
+    WHERE {daily} {article} GROUP BY 1, 2, 3) \
+    GROUP BY country, region, city ORDER BY total_views DESC",
+        fields = fields,
+        unique = UNIQUE_VISITORS,
+        raw = RAW_STATS_RANGE,
+        daily = DAILY_STATS_RANGE,
+        article = article_clause
+    );
+    select_many(pool, &query, values, map_location_views)
+}
+
+pub fn visitors_summary(
+    pool: &Pool,
+    from: i64,
+    to: i64,
+    article_id: Option<i32>,
+) -> Result<VisitorsSummary> {
+    let mut values: Vec<&dyn ToSql> = vec![&from, &to];
+    let article_clause = match &article_id {
+        Some(id) => {
+            values.push(id);
+            "AND article_id = ?3"
+        }
+        None => "",
+    };
+    // That one also counts the bots, to know how much
+    // they'd inflate the numbers.
+    let query = format!(
+        "SELECT ifnull(sum(views), 0), ifnull(sum(visitors), 0), ifnull(sum(bots), 0) FROM (\
+    SELECT count(*) - ifnull(sum(is_bot), 0) AS views, \
+    count(DISTINCT CASE WHEN is_bot = 0 THEN pseudo_ip || '|' || pseudo_ua END) AS visitors, \
+    ifnull(sum(is_bot), 0) AS bots FROM article_stats WHERE {raw} {article} \
+    UNION ALL \
+    SELECT sum(views), sum(unique_visitors), sum(bot_views) FROM article_stats_daily \
+    WHERE {daily} {article})",
+        raw = RAW_STATS_RANGE,
+        daily = DAILY_STATS_RANGE,
+        article = article_clause
+    );
+    // An aggregate query always returns a row.
+    select_one(pool, &query, values, map_visitors_summary)?
+        .ok_or_else(|| eyre!("Visitors summary query returned nothing"))
+}
+
+// Used to add titles to the stats, which are in
+// another database.
+pub fn article_titles(pool: &Pool, article_ids: &[i32]) -> Result<Vec<(i32, String)>> {
+    if article_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let query = format!(
+        "SELECT id, title FROM articles WHERE id IN ({})",
+        generate_in_placeholders(article_ids.len())
+    );
+    select_many(pool, &query, article_ids, |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })
+}
+
+// Local day of a raw row, what the daily tables use.
+const ROLLUP_DAY: &str = "strftime('%Y-%m-%d', date, 'unixepoch', 'localtime')";
+// Raw rows the rollup processes, ?1 is "before".
+const ROLLUP_ROWS: &str = "strftime('%Y-%m-%d', date, 'unixepoch', 'localtime') \
+    < strftime('%Y-%m-%d', ?1, 'unixepoch', 'localtime') AND rolled_up = 0";
+
+// What happens to raw stats once they've been rolled up
+// into the daily tables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RawStatsAction {
+    Delete,
+    // Removes everything that could identify a visitor,
+    // only the article, date and country are left.
+    Anonymize,
+}
+
+// Rolls raw stats of the days before the one "before" (a
+// timestamp) is in into the daily aggregate tables, per
+// article and per location. Days are always rolled up as
+// a whole, the same day can't end up being rolled up in
+// two different runs (which would count its unique
+// visitors twice).
+// Everything happens in a single transaction, rows that
+// were already rolled up (and anonymized) are skipped.
+// Returns the amount of raw rows that were processed.
+pub fn rollup_article_stats(pool: &Pool, before: i64, action: RawStatsAction) -> Result<usize> {
+    let mut conn = pool.clone().get()?;
+    let tx = conn.transaction()?;
+    tx.execute(
+        &format!(
+            "INSERT INTO article_stats_daily \
+    (day, article_id, views, bot_views, unique_visitors) \
+    SELECT {day} AS day, article_id, \
+    count(*) - sum(is_bot), sum(is_bot), \
+    count(DISTINCT CASE WHEN is_bot = 0 THEN pseudo_ip || '|' || pseudo_ua END) \
+    FROM article_stats WHERE {rollup} \
+    GROUP BY day, article_id \
+    ON CONFLICT(day, article_id) DO UPDATE SET \
+    views = views + excluded.views, \
+    bot_views = bot_views + excluded.bot_views, \
+    unique_visitors = max(unique_visitors, excluded.unique_visitors)",
+            day = ROLLUP_DAY,
+            rollup = ROLLUP_ROWS
+        ),
+        params![before],
+    )
+    .context("Roll up article stats per article")?;
+    tx.execute(
+        &format!(
+            "INSERT INTO country_stats_daily \
+    (day, article_id, country, region, city, views, bot_views, unique_visitors) \
+    SELECT {day} AS day, article_id, \
+    ifnull(country, '') AS country, ifnull(region, '') AS region, ifnull(city, '') AS city, \
+    count(*) - sum(is_bot), sum(is_bot), \
+    count(DISTINCT CASE WHEN is_bot = 0 THEN pseudo_ip || '|' || pseudo_ua END) \
+    FROM article_stats WHERE {rollup} \
+    GROUP BY 1, 2, 3, 4, 5 \
+    ON CONFLICT(day, article_id, country, region, city) DO UPDATE SET \
+    views = views + excluded.views, \
+    bot_views = bot_views + excluded.bot_views, \
+    unique_visitors = max(unique_visitors, excluded.unique_visitors)",
+            day = ROLLUP_DAY,
+            rollup = ROLLUP_ROWS
+        ),
+        params![before],
+    )
+    .context("Roll up article stats per location")?;
+    let rows = match action {
+        RawStatsAction::Delete => tx.execute(
+            &format!("DELETE FROM article_stats WHERE {}", ROLLUP_ROWS),
+            params![before],
+        ),
+        RawStatsAction::Anonymize => tx.execute(
+            &format!(
+                "UPDATE article_stats SET pseudo_ua = '', pseudo_ip = '', \
+        client_ua = '', client_ip = '', region = '', city = '', rolled_up = 1 \
+        WHERE {}",
+                ROLLUP_ROWS
+            ),
+            params![before],
+        ),
+    }
+    .context("Remove or anonymize rolled up article stats")?;
+    tx.commit()?;
+    Ok(rows)
+}
+
+// Used for the RSS feed. It used to fetch the articles one by
+// one from their IDs to spare memory, but that was one query
+// per article (four actually).
+pub fn all_published_articles_and_shorts(pool: &Pool, order: Order) -> Result<Vec<Article>> {
+    let query = Query::new(QueryType::Select {
+        from: &["articles"],
+        fields: &article_fields(true),
+    })
+    .where_clause("articles.published = 1")
+    .order(OrderBy::new(order, "articles.id"))
+    .to_string();
+    let mut articles = select_many(pool, &query, NO_PARAMS, |row| {
+        full_article_mapper(row, None)
+    })?;
+    add_tags(pool, &mut articles)?;
+    Ok(articles)
 }
 
+// Only the data-transform binary uses this now.
+#[allow(dead_code)]
 pub fn all_articles_and_shorts_ids(
     pool: &Pool,
     order: Order,
@@ -883,3 +2273,729 @@ pub fn all_published_articles_and_shorts_urls(pool: &Pool) -> Result<Vec<(String
         Ok((computed_url, short))
     })
 }
+
+pub fn api_token_by_hash(pool: &Pool, token_hash: &str) -> Result<Option<ApiToken>> {
+    select_one(
+        pool,
+        "SELECT id, name, token_hash, scopes, date \
+    FROM api_tokens WHERE token_hash = ?",
+        params![token_hash],
+        map_api_token,
+    )
+}
+
+// Only the data-transform binary creates tokens.
+#[allow(dead_code)]
+pub fn insert_api_token(pool: &Pool, api_token: &mut ApiToken) -> Result<i32> {
+    let query = Query::new(QueryType::Insert {
+        table: "api_tokens",
+        fields: &["name", "token_hash", "scopes", "date"],
+        values: None,
+    })
+    .to_string();
+    let conn = pool.clone().get()?;
+    let mut stmt = conn.prepare(&query)?;
+    stmt.execute(params![
+        api_token.name,
+        api_token.token_hash,
+        api_token.scopes,
+        api_token.date
+    ])?;
+    let id: i32 = i32::try_from(conn.last_insert_rowid())?;
+    api_token.id = id;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2_sqlite::SqliteConnectionManager;
+    use std::cell::Cell;
+
+    thread_local! {
+        static QUERY_COUNT: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn count_query(_: &str) {
+        QUERY_COUNT.with(|c| c.set(c.get() + 1));
+    }
+
+    fn query_count() -> usize {
+        QUERY_COUNT.with(|c| c.get())
+    }
+
+    // Every connection to an in-memory database is its own
+    // database, so the pool can only have one.
+    fn article_pool(article_count: i32) -> Pool {
+        let manager = SqliteConnectionManager::memory().with_init(|c| {
+            c.trace(Some(count_query));
+            Ok(())
+        });
+        let pool = Pool::builder().max_size(1).build(manager).unwrap();
+        migrations::migrate(&pool, migrations::Database::Main, false).unwrap();
+        let conn = pool.get().unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (id, name) VALUES (1, 'DkVZ');
+            INSERT INTO tags (id, name, main_tag) VALUES (1, 'Science', 1), (2, 'Rust', 0);",
+        )
+        .unwrap();
+        for id in 1..=article_count {
+            // Even articles are from a user that doesn't exist.
+            conn.execute(
+                "INSERT INTO articles (id, title, article_url, date, user_id, summary, content) \
+                VALUES (?, 'Title', ?, 0, ?, 'Summary', 'Content')",
+                params![id, format!("article_{}", id), 2 - id % 2],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO article_tags (article_id, tag_id) VALUES (?, 1), (?, 2)",
+                params![id, id],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO comments (article_id, author, comment, date, status) \
+                VALUES (?, 'A', 'Approved', 0, 1), (?, 'B', 'Pending', 0, 0)",
+                params![id, id],
+            )
+            .unwrap();
+        }
+        pool
+    }
+
+    fn count_listing_queries(pool: &Pool, count: usize) -> usize {
+        let before = query_count();
+        let articles =
+            articles_from_to(
+                pool,
+                &ArticleSelector::Article,
+                PageStart::Offset(0),
+                count,
+                &ArticleFilter::default(),
+                Order::Desc,
+            )
+            .unwrap();
+        assert_eq!(count, articles.len());
+        query_count() - before
+    }
+
+    #[test]
+    fn article_listing_query_count_is_constant() {
+        let pool = article_pool(20);
+        // The articles, then their tags:
+        assert_eq!(2, count_listing_queries(&pool, 2));
+        assert_eq!(2, count_listing_queries(&pool, 20));
+    }
+
+    #[test]
+    fn listed_articles_are_complete() {
+        let pool = article_pool(2);
+        let articles =
+            articles_from_to(
+                &pool,
+                &ArticleSelector::Article,
+                PageStart::Offset(0),
+                2,
+                &ArticleFilter::default(),
+                Order::Asc,
+            )
+            .unwrap();
+        assert_eq!("DkVZ", articles[0].author);
+        assert_eq!(ANONYMOUS_USERNAME, articles[1].author);
+        for article in articles.iter() {
+            assert_eq!(1, article.comments_count);
+            assert_eq!(2, article.tags.len());
+            assert!(article.content.is_none());
+        }
+        let article = published_article_by_id_or_url(&pool, "article_2").unwrap().unwrap();
+        assert_eq!(2, article.tags.len());
+        assert_eq!(Some(String::from("Content")), article.content);
+    }
+
+    #[test]
+    fn drafts_are_not_public() {
+        let pool = article_pool(2);
+        pool.get()
+            .unwrap()
+            .execute("UPDATE articles SET published = 0 WHERE id = 2", NO_PARAMS)
+            .unwrap();
+        assert!(published_article_by_id_or_url(&pool, "2").unwrap().is_none());
+        assert!(published_article_by_id_or_url(&pool, "article_2").unwrap().is_none());
+        assert!(published_article_by_id_or_url(&pool, "1").unwrap().is_some());
+        assert!(article_by_id(&pool, 2).unwrap().is_some());
+    }
+
+    #[test]
+    fn scheduled_articles_get_published_on_time() {
+        let pool = article_pool(3);
+        schedule_article(&pool, 2, Some(1000)).unwrap();
+        schedule_article(&pool, 3, Some(2000)).unwrap();
+        assert!(published_article_by_id_or_url(&pool, "2").unwrap().is_none());
+        assert_eq!(Some(1000), next_publish_at(&pool).unwrap());
+        assert_eq!(0, publish_scheduled_articles(&pool, 999).unwrap());
+        assert_eq!(1, publish_scheduled_articles(&pool, 1500).unwrap());
+        let article = published_article_by_id_or_url(&pool, "2").unwrap().unwrap();
+        assert_eq!(1000, article.date);
+        assert_eq!(Some(2000), next_publish_at(&pool).unwrap());
+        // Publishing the article by hand cancels its schedule.
+        update_date_and_publish_tx(&pool.get().unwrap(), 3).unwrap();
+        assert_eq!(None, next_publish_at(&pool).unwrap());
+    }
+
+    #[test]
+    fn scheduled_articles_can_be_searched_once_published() {
+        let pool = article_pool(3);
+        schedule_article(&pool, 1, Some(1000)).unwrap();
+        schedule_article(&pool, 3, Some(5000)).unwrap();
+        // The index gets rebuilt while they're unpublished.
+        rebuild_fulltext(&pool).unwrap();
+        let found = |pool: &Pool| {
+            search_count(
+                pool,
+                &["Content"],
+                &ArticleSelector::All,
+                &ArticleFilter::default(),
+            )
+            .unwrap()
+        };
+        assert_eq!(0, found(&pool));
+        publish_scheduled_articles(&pool, 1000).unwrap();
+        assert_eq!(1, found(&pool));
+        update_date_and_publish_tx(&pool.get().unwrap(), 3).unwrap();
+        assert_eq!(2, found(&pool));
+    }
+
+    #[test]
+    fn revisions_can_be_restored() {
+        let pool = article_pool(1);
+        let change = |conn: &rusqlite::Connection| {
+            conn.execute_batch(
+                "UPDATE articles SET title = 'New title' WHERE id = 1;
+                DELETE FROM article_tags WHERE article_id = 1 AND tag_id = 2;",
+            )?;
+            Ok(1)
+        };
+        assert_eq!(1, with_revision(&pool, 1, RevisionAction::Update, "editor", change).unwrap());
+        // Changes that fail or do nothing don't leave a revision.
+        assert!(with_revision(&pool, 1, RevisionAction::Update, "editor", |_| {
+            Err(eyre!("Nope"))
+        })
+        .is_err());
+        assert_eq!(0, with_revision(&pool, 1, RevisionAction::Update, "editor", |_| Ok(0)).unwrap());
+        let revisions = article_revisions(&pool, 1).unwrap();
+        assert_eq!(1, revisions.len());
+        assert_eq!(None, revisions[0].content);
+        let revision = article_revision(&pool, 1, revisions[0].id).unwrap().unwrap();
+        assert_eq!(vec![1, 2], revision.tag_ids);
+        assert_eq!(Some(String::from("editor")), revision.author);
+        restore_article_revision(&pool, &revision, "editor").unwrap();
+        let current = current_article_revision(&pool, 1).unwrap().unwrap();
+        assert_eq!("Title", current.title);
+        assert_eq!(vec![1, 2], current.tag_ids);
+        // Restoring saved the state it replaced.
+        assert_eq!(2, article_revisions(&pool, 1).unwrap().len());
+        // Deleted articles come back with the same ID.
+        delete_article(&pool, 1).unwrap();
+        restore_article_revision(&pool, &revision, "editor").unwrap();
+        assert!(article_exists(&pool, 1).unwrap());
+        let found = search_published_articles(
+            &pool,
+            &[String::from("Content")],
+            &ArticleSelector::All,
+            &ArticleFilter::default(),
+            0,
+            10,
+        )
+        .unwrap();
+        assert_eq!(1, found.len());
+    }
+
+    #[test]
+    fn search_can_be_filtered_and_paginated() {
+        let pool = article_pool(5);
+        let conn = pool.get().unwrap();
+        conn.execute_batch(
+            "UPDATE articles SET date = id * 1000;
+            UPDATE articles SET short = 1 WHERE id = 3;
+            DELETE FROM article_tags WHERE article_id = 5 AND tag_id = 2;",
+        )
+        .unwrap();
+        drop(conn);
+        rebuild_fulltext(&pool).unwrap();
+        let terms = [String::from("Content")];
+        let ids = |selector: ArticleSelector, filter: &ArticleFilter, start: usize| {
+            search_published_articles(&pool, &terms, &selector, filter, start, 2)
+                .unwrap()
+                .iter()
+                .map(|a| a.id)
+                .collect::<Vec<i32>>()
+        };
+        // Even articles are from a user that doesn't exist.
+        let all = ArticleFilter::default();
+        assert_eq!(3, search_count(&pool, &terms, &ArticleSelector::All, &all).unwrap());
+        assert_eq!(2, ids(ArticleSelector::All, &all, 0).len());
+        assert_eq!(1, ids(ArticleSelector::All, &all, 2).len());
+        assert_eq!(vec![3], ids(ArticleSelector::Short, &all, 0));
+        let dates = ArticleFilter {
+            from_date: Some(2000),
+            to_date: Some(5000),
+            ..Default::default()
+        };
+        assert_eq!(vec![3], ids(ArticleSelector::All, &dates, 0));
+        let tags = ArticleFilter::tags(Some(TagFilter {
+            names: vec![String::from("Rust")],
+            ids: Vec::new(),
+            match_mode: TagMatch::All,
+        }));
+        assert_eq!(2, search_count(&pool, &terms, &ArticleSelector::All, &tags).unwrap());
+        assert_eq!(1, search_count(&pool, &terms, &ArticleSelector::Article, &tags).unwrap());
+    }
+
+    #[test]
+    fn search_ignores_accents_and_plurals() {
+        let pool = article_pool(1);
+        pool.get()
+            .unwrap()
+            .execute(
+                "UPDATE articles SET content = '<p>Un bel été pour écrire des articles</p>'",
+                NO_PARAMS,
+            )
+            .unwrap();
+        rebuild_fulltext(&pool).unwrap();
+        let found = |term: &str| {
+            let terms = crate::utils::text_utils::sanitize_search_terms(
+                &vec![String::from(term)],
+                10,
+                true,
+            );
+            // The search endpoint doesn't query anything then.
+            if terms.is_empty() {
+                return 0;
+            }
+            search_count(&pool, &terms, &ArticleSelector::All, &ArticleFilter::default()).unwrap()
+        };
+        assert_eq!(1, found("\"bel ete\""));
+        assert_eq!(0, found("\"ete bel\""));
+        assert_eq!(0, found("ete -ecrire"));
+        assert_eq!(1, found("ecri*"));
+        assert_eq!(0, found("title:ete"));
+        // Malformed queries never get to SQLite as such.
+        for query in &["\"", "-", "title:", "*", "(ete", "ete OR", "NEAR(ete", "ete:", "^ete", "'"] {
+            found(query);
+        }
+        assert_eq!(1, found("ete"));
+        assert_eq!(1, found("ÉCRIRE"));
+        assert_eq!(1, found("article"));
+        assert_eq!(0, found("hiver"));
+    }
+
+    #[test]
+    fn vocabulary_suggests_published_terms() {
+        let pool = article_pool(2);
+        let conn = pool.get().unwrap();
+        conn.execute(
+            "UPDATE articles SET content = '<p>Fromages et fromagerie</p>' WHERE id = 1",
+            NO_PARAMS,
+        )
+        .unwrap();
+        conn.execute(
+            "UPDATE articles SET content = '<p>Fromage secret</p>', published = 0 WHERE id = 2",
+            NO_PARAMS,
+        )
+        .unwrap();
+        // Drafts are indexed too, which is why we can't just
+        // read the vocabulary.
+        reindex_fulltext(&conn).unwrap();
+        insert_article_fulltext_by_values(
+            &conn,
+            &String::from("Brouillon"),
+            &Some(String::from("<p>Fromage secret</p>")),
+            2,
+        )
+        .unwrap();
+        drop(conn);
+        assert_eq!(
+            vec!["fromagerie", "fromages"],
+            vocabulary_completions(&pool, "froma", 10).unwrap()
+        );
+        assert!(vocabulary_completions(&pool, "sec", 10).unwrap().is_empty());
+        assert_eq!(1, published_term_count(&pool, "fromages").unwrap());
+        assert_eq!(0, published_term_count(&pool, "secret").unwrap());
+        assert_eq!(vec!["fromages"], close_terms(&pool, "fromagse", 2, 3).unwrap());
+        assert!(close_terms(&pool, "fromage", 1, 3).unwrap().contains(&String::from("fromages")));
+        assert!(close_terms(&pool, "secre", 1, 3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn related_articles_queries_skip_unpublished() {
+        let pool = article_pool(3);
+        let conn = pool.get().unwrap();
+        conn.execute_batch(
+            "UPDATE articles SET content = '<p>Rust et WebAssembly</p>' WHERE id > 1;
+            UPDATE articles SET published = 0 WHERE id = 3;
+            DELETE FROM article_tags WHERE article_id = 2 AND tag_id = 2;",
+        )
+        .unwrap();
+        reindex_fulltext(&conn).unwrap();
+        drop(conn);
+        assert_eq!(vec![(2, 1)], articles_sharing_tags(&pool, 1).unwrap());
+        let matches = fulltext_matches(&pool, "\"webassembly\"*", 1, 10).unwrap();
+        assert_eq!(vec![2], matches.iter().map(|m| m.0).collect::<Vec<i32>>());
+        assert!(fulltext_matches(&pool, "\"webassembly\"*", 2, 10).unwrap().is_empty());
+        let articles = published_articles_by_ids(&pool, &[2, 3, 1]).unwrap();
+        assert_eq!(vec![2, 1], articles.iter().map(|a| a.id).collect::<Vec<i32>>());
+        assert_eq!(None, articles[0].content);
+        assert_eq!(1, articles[0].tags.len());
+    }
+
+    #[test]
+    fn unknown_comment_status_is_pending() {
+        let pool = article_pool(1);
+        pool.get()
+            .unwrap()
+            .execute("UPDATE comments SET status = 7", NO_PARAMS)
+            .unwrap();
+        let comment = comment_by_id(&pool, 1).unwrap().unwrap();
+        assert_eq!(CommentStatus::Pending, comment.status);
+    }
+
+    // Two days of stats, the first one being rolled up.
+    fn rolled_up_stats_pool(action: RawStatsAction) -> (Pool, i64, i64) {
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(SqliteConnectionManager::memory())
+            .unwrap();
+        migrations::migrate(&pool, migrations::Database::Stats, false).unwrap();
+        // Noon UTC, so that the local day is the same.
+        let day1: i64 = 1_600_084_800;
+        let day2 = day1 + 24 * 3600;
+        let rows = [
+            (1, "a", day1, "BE", "Liege", 0),
+            (1, "a", day1, "BE", "Liege", 0),
+            (2, "b", day1, "FR", "Paris", 0),
+            (1, "c", day1, "BE", "Liege", 1),
+            (1, "a", day2, "BE", "Liege", 0),
+        ];
+        let conn = pool.get().unwrap();
+        for (article_id, visitor, date, country, city, is_bot) in rows.iter() {
+            conn.execute(
+                "INSERT INTO article_stats (article_id, pseudo_ua, pseudo_ip, client_ua, \
+                client_ip, date, country, region, city, is_bot) \
+                VALUES (?, ?, ?, 'UA', '127.0.0.1', ?, ?, 'Region', ?, ?)",
+                params![article_id, visitor, visitor, date, country, city, is_bot],
+            )
+            .unwrap();
+        }
+        drop(conn);
+        assert_eq!(4, rollup_article_stats(&pool, day2, action).unwrap());
+        // Nothing left to roll up for that day.
+        assert_eq!(0, rollup_article_stats(&pool, day2, action).unwrap());
+        (pool, day1, day2)
+    }
+
+    #[test]
+    fn weeks_are_iso_weeks() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let week = |day: &str| -> String {
+            let query = format!(
+                "SELECT {}",
+                StatsPeriod::Week.sql_expression(&format!("'{}'", day))
+            );
+            conn.query_row(&query, NO_PARAMS, |row| row.get(0)).unwrap()
+        };
+        assert_eq!("2020-W53", week("2021-01-01"));
+        assert_eq!("2020-W53", week("2021-01-03"));
+        assert_eq!("2021-W01", week("2021-01-04"));
+        assert_eq!("2021-W09", week("2021-03-07"));
+        assert_eq!("2020-W01", week("2019-12-30"));
+    }
+
+    #[test]
+    fn stats_reports_include_rolled_up_days() {
+        for action in [RawStatsAction::Delete, RawStatsAction::Anonymize].iter() {
+            let (pool, day1, day2) = rolled_up_stats_pool(*action);
+            let (from, to) = (day1 - 24 * 3600, day2 + 24 * 3600);
+
+            let periods = article_views_by_period(&pool, 1, StatsPeriod::Day, from, to).unwrap();
+            let periods: Vec<(i64, i64)> =
+                periods.iter().map(|p| (p.views, p.unique_visitors)).collect();
+            assert_eq!(vec![(2, 1), (1, 1)], periods);
+            let month = article_views_by_period(&pool, 1, StatsPeriod::Month, from, to).unwrap();
+            assert_eq!(1, month.len());
+            assert_eq!(3, month[0].views);
+
+            let top = top_articles(&pool, from, to, 10).unwrap();
+            let top: Vec<(i32, i64)> = top.iter().map(|a| (a.article_id, a.views)).collect();
+            assert_eq!(vec![(1, 3), (2, 1)], top);
+
+            let cities = views_by_location(&pool, LocationLevel::City, from, to, None).unwrap();
+            let cities: Vec<(Option<String>, i64)> =
+                cities.iter().map(|l| (l.city.clone(), l.views)).collect();
+            assert_eq!(
+                vec![(Some(String::from("Liege")), 3), (Some(String::from("Paris")), 1)],
+                cities
+            );
+            let countries =
+                views_by_location(&pool, LocationLevel::Country, from, to, Some(2)).unwrap();
+            assert_eq!(1, countries.len());
+            assert_eq!("FR", countries[0].country);
+            assert_eq!(None, countries[0].region);
+
+            let summary = visitors_summary(&pool, from, to, None).unwrap();
+            assert_eq!((4, 3, 1), (summary.views, summary.unique_visitors, summary.bot_views));
+            let summary = visitors_summary(&pool, day2, to, Some(1)).unwrap();
+            assert_eq!((1, 1, 0), (summary.views, summary.unique_visitors, summary.bot_views));
+        }
+    }
+
+    #[test]
+    fn trashed_articles_can_be_restored_or_purged() {
+        let pool = article_pool(3);
+        let trash = |id: i32, now: i64| {
+            with_revision(&pool, id, RevisionAction::Delete, "editor", |conn| {
+                trash_article_tx(conn, id, now)
+            })
+            .unwrap()
+        };
+        let restore = |id: i32| {
+            with_revision(&pool, id, RevisionAction::Restore, "editor", |conn| {
+                restore_trashed_article_tx(conn, id)
+            })
+            .unwrap()
+        };
+        schedule_article(&pool, 3, Some(5000)).unwrap();
+        assert_eq!(1, trash(1, 1000));
+        assert_eq!(1, trash(2, 2000));
+        assert_eq!(1, trash(3, 3000));
+        assert_eq!(0, trash(1, 4000));
+        // Trashing it again didn't save another revision.
+        assert_eq!(1, article_revisions(&pool, 1).unwrap().len());
+        assert!(published_article_by_id_or_url(&pool, "1").unwrap().is_none());
+        assert!(!article_exists(&pool, 1).unwrap());
+        assert_eq!(None, next_publish_at(&pool).unwrap());
+        assert_eq!(
+            vec![3, 2, 1],
+            trashed_articles(&pool).unwrap().iter().map(|a| a.id).collect::<Vec<i32>>()
+        );
+        // Restored articles are published again, or scheduled.
+        assert_eq!(1, restore(1));
+        assert_eq!(1, restore(3));
+        assert_eq!("restore", article_revisions(&pool, 1).unwrap()[0].action);
+        assert!(published_article_by_id_or_url(&pool, "1").unwrap().is_some());
+        assert_eq!(Some(5000), next_publish_at(&pool).unwrap());
+        assert_eq!(0, purge_trash(&pool, 2000).unwrap());
+        assert_eq!(1, purge_trash(&pool, 2001).unwrap());
+        assert_eq!(vec![1, 3], all_articles_and_shorts_ids(&pool, Order::Asc, false).unwrap());
+    }
+
+    fn listed_ids(pool: &Pool, start: PageStart, order: Order) -> Vec<i32> {
+        articles_from_to(pool, &ArticleSelector::Article, start, 3, &ArticleFilter::default(), order)
+            .unwrap()
+            .iter()
+            .map(|a| a.id)
+            .collect()
+    }
+
+    #[test]
+    fn keyset_pages_follow_the_order() {
+        let pool = article_pool(10);
+        assert_eq!(vec![4, 3, 2], listed_ids(&pool, PageStart::After(5), Order::Desc));
+        assert_eq!(vec![8, 7, 6], listed_ids(&pool, PageStart::Before(5), Order::Desc));
+        assert_eq!(vec![6, 7, 8], listed_ids(&pool, PageStart::After(5), Order::Asc));
+        assert_eq!(vec![2, 3, 4], listed_ids(&pool, PageStart::Before(5), Order::Asc));
+        assert_eq!(vec![1], listed_ids(&pool, PageStart::After(2), Order::Desc));
+    }
+
+    #[test]
+    fn keyset_pages_of_comments() {
+        let pool = article_pool(1);
+        let conn = pool.get().unwrap();
+        conn.execute_batch(
+            "INSERT INTO comments (id, article_id, author, comment, date, status, parent_id) \
+            VALUES (10, 1, 'C', 'Root', 0, 1, NULL), (11, 1, 'D', 'Reply', 0, 1, 10), \
+            (12, 1, 'E', 'Root', 0, 1, NULL);",
+        )
+        .unwrap();
+        drop(conn);
+        let ids = |start: PageStart| -> Vec<i32> {
+            comments_from_to(&pool, start, 1, 1)
+                .unwrap()
+                .iter()
+                .map(|c| c.id)
+                .collect()
+        };
+        // Comment 1 is the approved one from the fixture.
+        assert_eq!(vec![10, 11], ids(PageStart::After(1)));
+        assert_eq!(vec![10, 11], ids(PageStart::Before(12)));
+    }
+
+    #[test]
+    fn comments_of_trashed_articles_are_hidden() {
+        let pool = article_pool(2);
+        assert_eq!(1, top_level_comment_count(&pool, 1).unwrap());
+        assert!(published_article_exists(&pool, 1).unwrap());
+        assert_eq!(2, last_comment(&pool).unwrap().unwrap().article_id);
+        trash_article_tx(&pool.get().unwrap(), 2, 1000).unwrap();
+        assert_eq!(1, last_comment(&pool).unwrap().unwrap().article_id);
+        trash_article_tx(&pool.get().unwrap(), 1, 1000).unwrap();
+        assert_eq!(0, top_level_comment_count(&pool, 1).unwrap());
+        assert!(last_comment(&pool).unwrap().is_none());
+        assert!(comments_from_to(&pool, PageStart::Offset(0), 10, 1)
+            .unwrap()
+            .is_empty());
+        assert!(!published_article_exists(&pool, 1).unwrap());
+        assert!(!published_article_exists(&pool, 7).unwrap());
+    }
+
+    #[test]
+    fn restored_articles_can_be_searched() {
+        let pool = article_pool(1);
+        trash_article_tx(&pool.get().unwrap(), 1, 1000).unwrap();
+        // The index gets rebuilt while it's in the trash.
+        rebuild_fulltext(&pool).unwrap();
+        restore_trashed_article_tx(&pool.get().unwrap(), 1).unwrap();
+        let terms = [String::from("Content")];
+        let all = ArticleFilter::default();
+        assert_eq!(1, search_count(&pool, &terms, &ArticleSelector::All, &all).unwrap());
+    }
+
+    fn tagged_ids(pool: &Pool, names: &[&str], ids: &[i32], match_mode: TagMatch) -> Vec<i32> {
+        let tags = ArticleFilter::tags(Some(TagFilter {
+            names: names.iter().map(|n| n.to_string()).collect(),
+            ids: ids.to_vec(),
+            match_mode,
+        }));
+        let articles = articles_from_to(
+            pool,
+            &ArticleSelector::Article,
+            PageStart::Offset(0),
+            10,
+            &tags,
+            Order::Asc,
+        )
+        .unwrap();
+        assert_eq!(
+            articles.len() as i64,
+            article_count(pool, &ArticleSelector::Article, &tags).unwrap()
+        );
+        articles.iter().map(|a| a.id).collect()
+    }
+
+    #[test]
+    fn articles_can_be_filtered_by_tags() {
+        let pool = article_pool(3);
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "INSERT INTO tags (id, name, main_tag) VALUES (3, 'Other', 0);
+                INSERT INTO article_tags (article_id, tag_id) VALUES (1, 3);",
+            )
+            .unwrap();
+        assert_eq!(vec![1], tagged_ids(&pool, &["Science", "Other"], &[], TagMatch::All));
+        assert_eq!(vec![1], tagged_ids(&pool, &["Science"], &[3], TagMatch::All));
+        assert_eq!(vec![1, 2, 3], tagged_ids(&pool, &["Other"], &[2], TagMatch::Any));
+        assert_eq!(vec![1], tagged_ids(&pool, &["Other", "Nope"], &[], TagMatch::Any));
+        assert!(tagged_ids(&pool, &["Other", "Nope"], &[], TagMatch::All).is_empty());
+        let counts = all_tags_with_counts(&pool).unwrap();
+        assert_eq!(
+            vec![("Other", 1), ("Rust", 3), ("Science", 3)],
+            counts
+                .iter()
+                .map(|c| (c.tag.name.as_str(), c.articles))
+                .collect::<Vec<(&str, i64)>>()
+        );
+    }
+
+    fn tag_ids_for(pool: &Pool, article_id: i32) -> Vec<i32> {
+        let mut ids = article_by_id(pool, article_id)
+            .unwrap()
+            .unwrap()
+            .tags
+            .iter()
+            .map(|t| t.id)
+            .collect::<Vec<i32>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn merged_tags_move_their_articles() {
+        let pool = article_pool(2);
+        let mut other = Tag {
+            id: -1,
+            name: String::from("Other"),
+            main_tag: 12,
+        };
+        insert_tag(&pool, &mut other).unwrap();
+        assert_eq!(1, other.main_tag);
+        // Article 1 already has the new tag, only article 2
+        // gets moved.
+        pool.get()
+            .unwrap()
+            .execute(
+                "INSERT INTO article_tags (article_id, tag_id) VALUES (1, ?)",
+                params![other.id],
+            )
+            .unwrap();
+        assert_eq!(1, merge_tags(&pool, 2, other.id).unwrap());
+        assert!(!tag_exists(&pool, 2).unwrap());
+        assert_eq!(vec![1, other.id], tag_ids_for(&pool, 1));
+        assert_eq!(vec![1, other.id], tag_ids_for(&pool, 2));
+        // Merging a main tag into a tag that isn't keeps it a main tag.
+        update_tag(&pool, other.id, Some("Renamed"), Some(0)).unwrap();
+        merge_tags(&pool, 1, other.id).unwrap();
+        let merged = tag_by_name(&pool, "Renamed").unwrap().unwrap();
+        assert_eq!(1, merged.main_tag);
+        assert_eq!(vec![other.id], tag_ids_for(&pool, 2));
+        assert_eq!(1, delete_tag(&pool, other.id).unwrap());
+        assert!(tag_ids_for(&pool, 1).is_empty());
+    }
+
+    #[test]
+    fn articles_can_be_filtered_by_author() {
+        let pool = article_pool(5);
+        let filter = ArticleFilter::author(1);
+        let ids: Vec<i32> = articles_from_to(
+            &pool,
+            &ArticleSelector::Article,
+            PageStart::Offset(0),
+            10,
+            &filter,
+            Order::Asc,
+        )
+        .unwrap()
+        .iter()
+        .map(|a| a.id)
+        .collect();
+        assert_eq!(vec![1, 3, 5], ids);
+        assert_eq!(3, article_count(&pool, &ArticleSelector::Article, &filter).unwrap());
+        assert_eq!(3, user_article_count(&pool, 1).unwrap());
+    }
+
+    #[test]
+    fn users_can_be_edited() {
+        let pool = article_pool(0);
+        // Names that look like numbers are stored as integers.
+        let mut user = User {
+            id: -1,
+            name: String::from("42"),
+            bio: Some(String::from("Bio")),
+            avatar: None,
+            link: Some(String::from("https://dkvz.eu")),
+        };
+        insert_user(&pool, &mut user).unwrap();
+        assert_eq!("42", user_by_name(&pool, "42").unwrap().unwrap().name);
+        let update = UserUpdate {
+            id: user.id,
+            bio: Some(None),
+            avatar: Some(Some(String::from("me.png"))),
+            ..Default::default()
+        };
+        assert_eq!(1, update_user(&pool, &update).unwrap());
+        let updated = user_by_id(&pool, user.id).unwrap().unwrap();
+        assert_eq!(None, updated.bio);
+        assert_eq!(Some(String::from("me.png")), updated.avatar);
+        assert_eq!(user.link, updated.link);
+        assert_eq!(2, all_users(&pool).unwrap().len());
+        assert_eq!(1, delete_user(&pool, user.id).unwrap());
+    }
+}
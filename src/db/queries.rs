@@ -13,7 +13,7 @@ use std::fmt;
 use derive_more::Display;
 
 // Bunch of enums for query building:
-#[derive(Debug, Display)]
+#[derive(Debug, Display, Clone, Copy, PartialEq)]
 pub enum Order {
   #[display(fmt = "ASC")]
   Asc,
@@ -21,6 +21,15 @@ pub enum Order {
   Desc
 }
 
+impl Order {
+  pub fn reversed(self) -> Self {
+    match self {
+      Order::Asc => Order::Desc,
+      Order::Desc => Order::Asc
+    }
+  }
+}
+
 // When insert values aren't given, "?" prepared
 // statement placeholders are automatically
 // generated.
@@ -16,8 +16,11 @@ use std::sync::mpsc::{self, SyncSender, TrySendError};
 use std::thread::{self, JoinHandle};
 pub mod ip_location;
 pub mod pseudonymizer;
+pub mod retention;
+pub mod visit_filter;
 use ip_location::{GeoInfo, IpLocator};
 use pseudonymizer::WordlistPseudoyimizer;
+use visit_filter::{Visit, VisitFilter};
 
 #[derive(Debug)]
 pub struct BaseArticleStat {
@@ -43,6 +46,7 @@ impl StatsService {
     wordlist_path: &str,
     iploc_path: &str,
     message_queue_size: usize,
+    visit_filter: VisitFilter,
   ) -> Result<StatsService> {
     let mut pseudonymizer = WordlistPseudoyimizer::open(wordlist_path)?;
     let mut ip_locator = IpLocator::open(iploc_path)?;
@@ -62,6 +66,17 @@ impl StatsService {
               break;
             }
             StatsMessage::InsertArticleStats(base_article_stat) => {
+              let is_bot = match visit_filter.classify(
+                &base_article_stat.client_ua,
+                base_article_stat.client_ip,
+              ) {
+                Visit::Human => false,
+                Visit::Bot => true,
+                Visit::Ignored => {
+                  debug!("Not saving stats for ignored visit: {:?}", base_article_stat);
+                  continue;
+                }
+              };
               // I had to add this mess when I realized the API of Actix could
               // give me no client IP address at all.
               let (client_ip, geo_info, pseudo_ip): (String, Option<GeoInfo>, String) =
@@ -118,6 +133,7 @@ impl StatsService {
                 country: geo_info.country,
                 region: geo_info.region,
                 city: geo_info.city,
+                is_bot,
               };
               debug!("Inserting article stats: {:?}", article_stat);
               if let Err(e) = insert_article_stat(&connection, &article_stat) {
@@ -0,0 +1,32 @@
+use crate::db::{rollup_article_stats, Pool, RawStatsAction};
+use crate::utils::time_utils::days_ago_timestamp;
+use log::{error, info};
+use std::time::Duration;
+use tokio::task;
+
+// Periodically rolls raw stats older than "retention_days"
+// into the daily tables. Runs once right away when the
+// server starts. The actual database work happens in a
+// blocking task.
+pub fn start_rollup_task(
+  pool: Pool,
+  retention_days: u32,
+  action: RawStatsAction,
+  interval_hours: u64,
+) {
+  // An interval of 0 would make tokio panic.
+  let period = Duration::from_secs(interval_hours.max(1) * 3600);
+  actix_web::rt::spawn(async move {
+    let mut interval = tokio::time::interval(period);
+    loop {
+      interval.tick().await;
+      let pool = pool.clone();
+      let before = days_ago_timestamp(retention_days);
+      match task::spawn_blocking(move || rollup_article_stats(&pool, before, action)).await {
+        Ok(Ok(rows)) => info!("Stats rollup processed {} raw rows", rows),
+        Ok(Err(e)) => error!("Stats rollup failed - {}", e),
+        Err(e) => error!("Stats rollup task panicked - {}", e),
+      }
+    }
+  });
+}
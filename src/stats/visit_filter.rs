@@ -0,0 +1,113 @@
+use crate::utils::ip_utils::IpRange;
+use log::warn;
+use std::net::IpAddr;
+
+#[derive(Debug, PartialEq)]
+pub enum Visit {
+  Human,
+  Bot,
+  // Should not be saved at all.
+  Ignored
+}
+
+/**
+ * Decides what happens to a visit before it makes it
+ * into the stats database. User agents are matched
+ * against a list of lowercase patterns, which is way
+ * cheaper than any regex.
+ */
+pub struct VisitFilter {
+  bot_patterns: Vec<String>,
+  drop_bots: bool,
+  excluded_ips: Vec<IpRange>
+}
+
+impl VisitFilter {
+  pub fn new(
+    bot_patterns: Vec<String>,
+    drop_bots: bool,
+    excluded_ips: Vec<String>
+  ) -> Self {
+    // Invalid ranges are logged and ignored, I don't
+    // want the server to refuse to start for that.
+    let excluded_ips = excluded_ips
+      .iter()
+      .filter_map(|ip| match ip.parse::<IpRange>() {
+        Ok(range) => Some(range),
+        Err(e) => {
+          warn!("Ignoring excluded IP for stats - {}", e);
+          None
+        }
+      })
+      .collect();
+    Self {
+      bot_patterns: bot_patterns
+        .into_iter()
+        .map(|p| p.to_lowercase())
+        .collect(),
+      drop_bots,
+      excluded_ips
+    }
+  }
+
+  pub fn is_bot(&self, user_agent: &str) -> bool {
+    // No user agent at all is a pretty good hint.
+    if user_agent.trim().is_empty() {
+      return true;
+    }
+    let user_agent = user_agent.to_lowercase();
+    self.bot_patterns.iter().any(|p| user_agent.contains(p.as_str()))
+  }
+
+  pub fn classify(&self, user_agent: &str, ip: Option<IpAddr>) -> Visit {
+    if let Some(ip) = ip {
+      if self.excluded_ips.iter().any(|range| range.contains(&ip)) {
+        return Visit::Ignored;
+      }
+    }
+    if self.is_bot(user_agent) {
+      if self.drop_bots {
+        Visit::Ignored
+      } else {
+        Visit::Bot
+      }
+    } else {
+      Visit::Human
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FIREFOX_UA: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:86.0) \
+    Gecko/20100101 Firefox/86.0";
+
+  fn filter(drop_bots: bool) -> VisitFilter {
+    VisitFilter::new(
+      vec!["Bot".to_string(), "crawl".to_string()],
+      drop_bots,
+      vec!["10.0.0.0/8".to_string(), "nonsense".to_string()]
+    )
+  }
+
+  #[test]
+  fn classify_visits() {
+    let sut = filter(false);
+    let ip: Option<IpAddr> = "8.8.8.8".parse().ok();
+    assert_eq!(Visit::Human, sut.classify(FIREFOX_UA, ip));
+    assert_eq!(Visit::Bot, sut.classify("Googlebot/2.1", ip));
+    assert_eq!(Visit::Bot, sut.classify("", None));
+    assert_eq!(
+      Visit::Ignored,
+      sut.classify(FIREFOX_UA, "10.1.2.3".parse().ok())
+    );
+  }
+
+  #[test]
+  fn bots_can_be_dropped() {
+    let sut = filter(true);
+    assert_eq!(Visit::Ignored, sut.classify("SomeCrawler", None));
+  }
+}
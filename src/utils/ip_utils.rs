@@ -1,4 +1,5 @@
-//use std::net::IpAddr;
+use std::net::IpAddr;
+use std::str::FromStr;
 
 /*pub fn extract_first_bytes(ip: IpAddr) -> String {
   // We have to check the type of IP address, then
@@ -35,6 +36,60 @@ pub fn extract_first_bytes(ip: &str) -> String {
   return String::from(ip);
 }
 
+// Either a single IP address or a CIDR range like
+// 192.168.0.0/16, used to exclude visits from stats.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IpRange {
+  network: IpAddr,
+  prefix_len: u8
+}
+
+impl IpRange {
+  pub fn contains(&self, ip: &IpAddr) -> bool {
+    match (self.network, ip) {
+      (IpAddr::V4(net), IpAddr::V4(ip)) => {
+        prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+      },
+      (IpAddr::V6(net), IpAddr::V6(ip)) => {
+        prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+      },
+      _ => false
+    }
+  }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+  let full_bytes = (prefix_len / 8) as usize;
+  let remaining_bits = prefix_len % 8;
+  if net[..full_bytes] != ip[..full_bytes] {
+    return false;
+  }
+  if remaining_bits == 0 {
+    return true;
+  }
+  let mask = 0xffu8 << (8 - remaining_bits);
+  net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl FromStr for IpRange {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parts = s.trim().splitn(2, '/');
+    let network = IpAddr::from_str(parts.next().unwrap_or(""))
+      .map_err(|_| format!("Invalid IP address in range {}", s))?;
+    let max_len = if network.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match parts.next() {
+      Some(len) => len.parse::<u8>()
+        .ok()
+        .filter(|len| *len <= max_len)
+        .ok_or_else(|| format!("Invalid prefix length in range {}", s))?,
+      None => max_len
+    };
+    Ok(Self { network, prefix_len })
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -76,4 +131,19 @@ mod tests {
     assert_eq!(extract_first_bytes(sut), expected);
   }
 
-}
\ No newline at end of file
+  #[test]
+  fn ip_range_contains_addresses() {
+    let sut: IpRange = "192.168.0.0/16".parse().unwrap();
+    assert!(sut.contains(&"192.168.12.4".parse().unwrap()));
+    assert!(!sut.contains(&"192.169.0.1".parse().unwrap()));
+    assert!(!sut.contains(&"::1".parse().unwrap()));
+    let sut: IpRange = "10.0.0.0/9".parse().unwrap();
+    assert!(sut.contains(&"10.127.0.1".parse().unwrap()));
+    assert!(!sut.contains(&"10.128.0.1".parse().unwrap()));
+    let sut: IpRange = "::1".parse().unwrap();
+    assert!(sut.contains(&"::1".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+    assert!("not an address".parse::<IpRange>().is_err());
+  }
+
+}
@@ -1,6 +1,8 @@
 use lazy_static::lazy_static;
 use regex::{Regex, Captures};
 use std::borrow::Cow;
+use similar::TextDiff;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 
 // Stole this from StackOverflow, of course
 // https://stackoverflow.com/questions/53570839/quick-function-to-convert-a-strings-first-letter-to-uppercase
@@ -23,23 +25,265 @@ pub fn escape_html<T: AsRef<str>>(s: T) -> String {
     .replace(">", "&gt;")
 }
 
+// The search terms are a small query language, they're
+// joined with spaces and parsed as a single query:
+// - words are searched as is, they all have to be there
+// - "quoted text" is an exact phrase
+// - a trailing * makes a word (or phrase) a prefix
+// - -word (or -"some phrase") excludes results with it
+// - title:word (or title:"some phrase") only searches the
+//   titles
+// Returns the pieces of the FTS5 MATCH expression, to be
+// joined with spaces. Every word ends up in a quoted FTS5
+// string that only has letters and numbers in it, so
+// whatever the input, the expression is valid.
+// Returns nothing when there's nothing to include, FTS5
+// can't do a query that only excludes things.
+// Terms are normalized the same way the fulltext index
+// is (lowercase, no accents). With stem set to true, long
+// enough words are reduced to their French stem and made
+// into prefix queries, so that "articles" finds "article"
+// and the other way around.
 pub fn sanitize_search_terms(
   terms: &Vec<String>, 
-  max_search_terms: usize
+  max_search_terms: usize,
+  stem: bool
 ) -> Vec<String> {
-  lazy_static! {
-    static ref SEARCH_CLEANUP_REGEX: Regex = Regex::new(
-      r"[\[\]\s\$\^%\+-]"
-    ).unwrap();
+  let (included, excluded): (Vec<SearchTerm>, Vec<SearchTerm>) =
+    parse_search_query(&terms.join(" "), stem)
+      .into_iter()
+      .partition(|t| !t.excluded);
+  if included.is_empty() {
+    return Vec::new();
   }
+  // The FTS5 NOT operator has to come after what it's
+  // excluding things from.
+  included.iter()
+    .chain(excluded.iter())
+    .take(max_search_terms)
+    .map(SearchTerm::to_fts)
+    .collect()
+}
 
-  terms.iter()
+// Single words the search query is looking for, without
+// the phrases and exclusions, normalized but not stemmed.
+// That's what search suggestions work with.
+pub fn search_query_words(terms: &[String], max_search_terms: usize) -> Vec<String> {
+  parse_search_query(&terms.join(" "), false)
+    .into_iter()
+    .filter(|t| !t.excluded)
     .take(max_search_terms)
-    .map(|t| SEARCH_CLEANUP_REGEX.replace_all(t, "").to_string())
-    .filter(|t| !t.is_empty())
+    .filter(|t| t.words.len() == 1)
+    .flat_map(|t| t.words)
+    .collect()
+}
+
+// FTS5 MATCH expression that finds texts sharing words with
+// the given text (any of them, the best matches have the
+// most). Short words are skipped, they're mostly
+// articles and prepositions, and long ones are stemmed.
+// HTML tags are ignored, summaries can have some.
+// Returns None when no word is left.
+pub fn similarity_query(text: &str, max_terms: usize) -> Option<String> {
+  lazy_static! {
+    static ref HTML_TAG_REGEX: Regex = Regex::new("<[^>]*>").unwrap();
+  }
+  let text = HTML_TAG_REGEX.replace_all(text, " ");
+  let mut terms: Vec<String> = Vec::new();
+  for word in text.split_whitespace().flat_map(|w| search_words(w, true)) {
+    if word.chars().count() < MIN_SIMILARITY_WORD_LENGTH {
+      continue;
+    }
+    let term = SearchTerm {
+      prefix: word.chars().count() >= MIN_STEM_LENGTH,
+      words: vec![french_stem(&word)],
+      title_only: false,
+      excluded: false
+    }.to_fts();
+    if !terms.contains(&term) {
+      terms.push(term);
+    }
+    if terms.len() == max_terms {
+      break;
+    }
+  }
+  if terms.is_empty() {
+    None
+  } else {
+    Some(terms.join(" OR "))
+  }
+}
+
+// Levenshtein distance, in characters.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut previous: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut current = vec![i + 1; b.len() + 1];
+    for (j, cb) in b.iter().enumerate() {
+      let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+      current[j + 1] = substitution
+        .min(previous[j + 1] + 1)
+        .min(current[j] + 1);
+    }
+    previous = current;
+  }
+  previous[b.len()]
+}
+
+#[derive(Debug, PartialEq)]
+struct SearchTerm {
+  // Normalized words, more than one makes it a phrase.
+  words: Vec<String>,
+  prefix: bool,
+  title_only: bool,
+  excluded: bool
+}
+
+impl SearchTerm {
+  fn to_fts(&self) -> String {
+    let mut fts = format!("\"{}\"", self.words.join(" "));
+    if self.prefix {
+      fts.push('*');
+    }
+    if self.title_only {
+      fts = format!("title : {}", fts);
+    }
+    if self.excluded {
+      fts = format!("NOT {}", fts);
+    }
+    fts
+  }
+}
+
+const TITLE_FILTER: &str = "title:";
+
+// Never fails, anything that isn't part of the syntax
+// is considered text. Quotes that aren't closed run to
+// the end of the query.
+fn parse_search_query(query: &str, stem: bool) -> Vec<SearchTerm> {
+  let mut terms = Vec::new();
+  let mut rest = query.trim_start();
+  while !rest.is_empty() {
+    let excluded = rest.starts_with('-');
+    if excluded {
+      rest = &rest[1..];
+    }
+    let title_only = rest.len() >= TITLE_FILTER.len()
+      && rest.is_char_boundary(TITLE_FILTER.len())
+      && rest[..TITLE_FILTER.len()].eq_ignore_ascii_case(TITLE_FILTER);
+    if title_only {
+      rest = &rest[TITLE_FILTER.len()..];
+    }
+    let (text, quoted, prefix);
+    if let Some(quoted_text) = rest.strip_prefix('"') {
+      let end = quoted_text.find('"').unwrap_or(quoted_text.len());
+      text = &quoted_text[..end];
+      rest = quoted_text.get(end + 1..).unwrap_or("");
+      prefix = rest.starts_with('*');
+      quoted = true;
+    } else {
+      let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+      text = rest[..end].trim_end_matches('*');
+      prefix = rest[..end].ends_with('*');
+      rest = &rest[end..];
+      quoted = false;
+    }
+    // Whatever follows a phrase until the next space is
+    // ignored (including the prefix star).
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    rest = rest[end..].trim_start();
+    let mut words = search_words(text, !quoted);
+    if words.is_empty() {
+      continue;
+    }
+    let mut prefix = prefix;
+    if stem && !quoted && !prefix && words.len() == 1
+      && words[0].chars().count() >= MIN_STEM_LENGTH {
+      words[0] = french_stem(&words[0]);
+      prefix = true;
+    }
+    terms.push(SearchTerm {
+      words,
+      prefix,
+      title_only,
+      excluded
+    });
+  }
+  terms
+}
+
+// The words that get elided in front of a vowel. The
+// fulltext tokenizer sees "l'été" as "l" and "ete".
+const FRENCH_ELISIONS: &[&str] = &[
+  "l'", "d'", "j'", "m'", "n'", "s'", "t'", "c'", "qu'", "jusqu'", "lorsqu'", "puisqu'"
+];
+// Shorter words are left alone by the stemmer.
+const MIN_STEM_LENGTH: usize = 6;
+// Shorter words don't count when comparing texts.
+const MIN_SIMILARITY_WORD_LENGTH: usize = 4;
+
+// Splits the text the same way the fulltext tokenizer
+// does, on everything that isn't a letter or a number.
+// Elisions are removed from single words, phrases keep
+// them since they have to match word for word.
+fn search_words(text: &str, remove_elision: bool) -> Vec<String> {
+  let folded = fold_diacritics(&text.replace('’', "'"));
+  let text = if remove_elision {
+    FRENCH_ELISIONS.iter()
+      .find_map(|e| folded.strip_prefix(e))
+      .unwrap_or(&folded)
+  } else {
+    &folded
+  };
+  text.split(|c: char| !c.is_alphanumeric())
+    .filter(|w| !w.is_empty())
+    .map(String::from)
     .collect()
 }
 
+// Lowercase and without accents, "Été" becomes "ete".
+// Same thing the unicode61 tokenizer of the fulltext
+// table does with remove_diacritics.
+pub fn fold_diacritics(s: &str) -> String {
+  s.nfd()
+    .filter(|c| !is_combining_mark(*c))
+    .collect::<String>()
+    .to_lowercase()
+}
+
+// Minimal French stemmer, mostly removes plurals and
+// feminine endings. Based on the one Lucene uses, except
+// that words ending in "aux" lose the "ux" so that the
+// stem is also a prefix of the singular (chevaux and
+// cheval both start with "cheva").
+// Expects a word without accents.
+pub fn french_stem(word: &str) -> String {
+  let mut chars: Vec<char> = word.chars().collect();
+  if chars.len() < MIN_STEM_LENGTH {
+    return word.to_string();
+  }
+  if chars.ends_with(&['a', 'u', 'x']) {
+    chars.truncate(chars.len() - 2);
+    return chars.into_iter().collect();
+  }
+  // An "x" plural is the only ending.
+  if chars.last() == Some(&'x') {
+    chars.pop();
+    return chars.into_iter().collect();
+  }
+  for ending in ['s', 'r', 'e'].iter() {
+    if chars.last() == Some(ending) {
+      chars.pop();
+    }
+  }
+  let len = chars.len();
+  if len > 2 && chars[len - 1] == chars[len - 2] && chars[len - 1].is_alphabetic() {
+    chars.pop();
+  }
+  chars.into_iter().collect()
+}
+
 // Experimenting with Cow and regretting it here.
 // This time around I find relative links by assuming
 // they start with a leading "/", if they don't, they
@@ -110,6 +354,20 @@ pub fn truncate_utf8(input : &mut String, maxsize: usize) {
   }
 }
 
+// Unified diff of two texts, line by line. Returns None
+// when they're identical.
+pub fn line_diff(old: &str, new: &str) -> Option<String> {
+  if old == new {
+    return None;
+  }
+  Some(
+    TextDiff::from_lines(old, new)
+      .unified_diff()
+      .context_radius(3)
+      .to_string()
+  )
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -124,12 +382,14 @@ mod tests {
   #[test]
   fn sanitize_search_replaces_illegal_chars() {
     let sut: Vec<String> = vec![
-      String::from(" p otato-[po ^w%+-er"),
-      String::from("\npotato$   --[power]")
+      String::from(" pot^ato$ [power]"),
+      String::from("\n(NEAR AND:")
     ];
-    let processed = sanitize_search_terms(&sut, 10);
-    assert_eq!(processed[0], "potatopower");
-    assert_eq!(processed[1], "potatopower");
+    let processed = sanitize_search_terms(&sut, 10, false);
+    assert_eq!(
+      vec!["\"pot ato\"", "\"power\"", "\"near\"", "\"and\""],
+      processed
+    );
   }
 
   #[test]
@@ -141,9 +401,9 @@ mod tests {
       String::from("test4"),
       String::from("test5"),
     ];
-    let processed = sanitize_search_terms(&sut, 3);
+    let processed = sanitize_search_terms(&sut, 3, false);
     assert_eq!(processed.len(), 3);
-    assert_eq!(processed[2], "test3");
+    assert_eq!(processed[2], "\"test3\"");
   }
 
   #[test]
@@ -154,10 +414,112 @@ mod tests {
       String::from("  "),
       String::from("\n"),
     ];
-    let processed = sanitize_search_terms(&sut, 80);
+    let processed = sanitize_search_terms(&sut, 80, false);
     assert_eq!(processed.len(), 0);
   }
 
+  #[test]
+  fn sanitize_search_folds_accents_and_stems() {
+    let sut: Vec<String> = vec![
+      String::from("Été"),
+      String::from("l’Éléphant"),
+      String::from("articles"),
+      String::from("chevaux"),
+      String::from("pantal*")
+    ];
+    assert_eq!(
+      vec![
+        "\"ete\"",
+        "\"elephant\"",
+        "\"elephant\"*",
+        "\"articl\"*",
+        "\"cheva\"*",
+        "\"pantal\"*"
+      ],
+      [
+        sanitize_search_terms(&sut[0..2].to_vec(), 10, false),
+        sanitize_search_terms(&sut[1..].to_vec(), 10, true)
+      ].concat()
+    );
+  }
+
+  #[test]
+  fn search_query_syntax() {
+    let sut: Vec<String> = vec![
+      String::from("\"Un bel été\" pref* -exclu"),
+      String::from("Title:rust -title:\"C plus\"*")
+    ];
+    assert_eq!(
+      vec![
+        "\"un bel ete\"",
+        "\"pref\"*",
+        "title : \"rust\"",
+        "NOT \"exclu\"",
+        "NOT title : \"c plus\"*"
+      ],
+      sanitize_search_terms(&sut, 10, true)
+    );
+  }
+
+  #[test]
+  fn search_query_needs_something_to_include() {
+    let sut: Vec<String> = vec![String::from("-exclu -\"rien\"")];
+    assert!(sanitize_search_terms(&sut, 10, false).is_empty());
+  }
+
+  #[test]
+  fn search_query_unclosed_quote_runs_to_the_end() {
+    let sut: Vec<String> = vec![String::from("word \"l'été -dernier")];
+    assert_eq!(
+      vec!["\"word\"", "\"l ete dernier\""],
+      sanitize_search_terms(&sut, 10, false)
+    );
+  }
+
+  #[test]
+  fn search_query_words_skips_phrases_and_exclusions() {
+    let sut: Vec<String> = vec![String::from("\"un bel\" Été -hiver title:pantalons*")];
+    assert_eq!(vec!["ete", "pantalons"], search_query_words(&sut, 10));
+  }
+
+  #[test]
+  fn edit_distance_counts_characters() {
+    assert_eq!(0, edit_distance("ete", "ete"));
+    assert_eq!(1, edit_distance("pantalom", "pantalon"));
+    assert_eq!(2, edit_distance("elephnat", "elephant"));
+    assert_eq!(3, edit_distance("", "été"));
+  }
+
+  #[test]
+  fn similarity_query_skips_short_words() {
+    assert_eq!(
+      Some(String::from("\"rust\" OR \"webassembly\"*")),
+      similarity_query("Rust et WebAssembly", 10)
+    );
+    assert_eq!(
+      Some(String::from("\"articl\"*")),
+      similarity_query("L'été des articles, un article", 10)
+    );
+    assert_eq!(
+      Some(String::from("\"rust\"")),
+      similarity_query("Rust et WebAssembly", 1)
+    );
+    assert_eq!(None, similarity_query("<p>Un été</p>", 10));
+    assert_eq!(
+      Some(String::from("\"image\"")),
+      similarity_query("<strong class=\"big\">Une image</strong>", 10)
+    );
+  }
+
+  #[test]
+  fn french_stem_removes_plurals() {
+    assert_eq!("articl", french_stem("articles"));
+    assert_eq!("articl", french_stem("article"));
+    assert_eq!("cheva", french_stem("chevaux"));
+    assert_eq!("nouvel", french_stem("nouvelle"));
+    assert_eq!("ete", french_stem("ete"));
+  }
+
   // I know this is akin to testing the html_escape 
   // library but I need to know if it does what I'm 
   // expecting.
@@ -203,6 +565,15 @@ mod tests {
     );
   }
 
+  #[test]
+  fn line_diff_shows_changed_lines() {
+    assert_eq!(None, line_diff("same\ntext", "same\ntext"));
+    let diff = line_diff("first\nsecond\n", "first\nthird\n").unwrap();
+    assert!(diff.contains("-second\n"));
+    assert!(diff.contains("+third\n"));
+    assert!(diff.contains(" first\n"));
+  }
+
   #[test]
   fn single_link_to_absolute_returns_none() {
     let sut = "https://wikipedia.org/something/something";
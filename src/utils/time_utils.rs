@@ -1,4 +1,4 @@
-use chrono::{Local, TimeZone};
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone};
 
 // Very specific date format the old API is doing: dd/MM/yyyy HH:mm:ssZ
 // chrono formatting reference:
@@ -20,6 +20,42 @@ pub fn timestamp_to_date_string(timestamp: i64, format: DateFormat) -> String {
   d.format(format_str).to_string()
 }
 
+// Parses a date in the USCompact format and gives the
+// timestamp at midnight local time for that day.
+pub fn day_start_timestamp(date: &str) -> Option<i64> {
+  local_midnight_timestamp(date, 0)
+}
+
+// Midnight at the end of that day, AKA the start of
+// the next day.
+pub fn day_end_timestamp(date: &str) -> Option<i64> {
+  local_midnight_timestamp(date, 1)
+}
+
+fn local_midnight_timestamp(date: &str, days_offset: i64) -> Option<i64> {
+  let d = NaiveDate::parse_from_str(date, DATE_FORMAT_USCOMPACT).ok()?
+    + Duration::days(days_offset);
+  Local
+    .from_local_datetime(&d.and_hms(0, 0, 0))
+    .earliest()
+    .map(|dt| dt.timestamp())
+}
+
+// Midnight local time, "days" days ago.
+pub fn days_ago_timestamp(days: u32) -> i64 {
+  (Local::today() - Duration::days(days.into()))
+    .and_hms(0, 0, 0)
+    .timestamp()
+}
+
+// Dates with a time and an offset, e.g.
+// 2021-03-07T21:59:00+01:00 (what JSON.stringify does).
+pub fn rfc3339_timestamp(date: &str) -> Option<i64> {
+  DateTime::parse_from_rfc3339(date.trim())
+    .ok()
+    .map(|dt| dt.timestamp())
+}
+
 pub fn current_timestamp() -> i64 {
   Local::now().timestamp()
 }
@@ -38,4 +74,23 @@ mod tests {
     let result = timestamp_to_date_string(timestamp, DateFormat::Standard);
     assert_eq!("07/03/2021 21:59:00+01:00", result);
   }
+
+  #[test]
+  fn day_timestamps_cover_a_day() {
+    let start = day_start_timestamp("2021-03-07").unwrap();
+    let end = day_end_timestamp("2021-03-07").unwrap();
+    assert_eq!(86400, end - start);
+    assert_eq!(
+      "2021-03-07",
+      timestamp_to_date_string(start, DateFormat::USCompact)
+    );
+    assert_eq!(None, day_start_timestamp("07/03/2021"));
+  }
+
+  #[test]
+  fn rfc3339_dates_have_an_offset() {
+    assert_eq!(Some(1615150740), rfc3339_timestamp("2021-03-07T21:59:00+01:00"));
+    assert_eq!(Some(1615150740), rfc3339_timestamp("2021-03-07T20:59:00.000Z"));
+    assert_eq!(None, rfc3339_timestamp("2021-03-07 21:59:00"));
+  }
 }
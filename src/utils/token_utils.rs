@@ -0,0 +1,68 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// API tokens are only shown once when they're created,
+// the database only ever sees the SHA-256 hash.
+pub fn hash_token(token: &str) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(token.as_bytes());
+  format!("{:x}", hasher.finalize())
+}
+
+fn preview_mac(secret: &[u8], article_id: i32, expires: i64) -> HmacSha256 {
+  // Any key length is fine for HMAC, new_varkey can't fail.
+  let mut mac = HmacSha256::new_varkey(secret).expect("HMAC accepts any key length");
+  mac.update(format!("{}:{}", article_id, expires).as_bytes());
+  mac
+}
+
+// Preview links for drafts carry the article ID, an
+// expiry timestamp and this signature (URL-safe base64
+// of a HMAC-SHA256).
+pub fn sign_preview(secret: &[u8], article_id: i32, expires: i64) -> String {
+  base64::encode_config(
+    preview_mac(secret, article_id, expires).finalize().into_bytes(),
+    base64::URL_SAFE_NO_PAD,
+  )
+}
+
+// Doesn't check the expiry, only that the link wasn't
+// tampered with. The comparison is constant time.
+pub fn valid_preview_signature(
+  secret: &[u8],
+  article_id: i32,
+  expires: i64,
+  signature: &str,
+) -> bool {
+  match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+    Ok(signature) => preview_mac(secret, article_id, expires)
+      .verify(&signature)
+      .is_ok(),
+    Err(_) => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn hash_token_is_hex_sha256() {
+    assert_eq!(
+      "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+      hash_token("test")
+    );
+  }
+
+  #[test]
+  fn preview_signature_is_tied_to_article_and_expiry() {
+    let signature = sign_preview(b"secret", 12, 1000);
+    assert!(valid_preview_signature(b"secret", 12, 1000, &signature));
+    assert!(!valid_preview_signature(b"secret", 13, 1000, &signature));
+    assert!(!valid_preview_signature(b"secret", 12, 2000, &signature));
+    assert!(!valid_preview_signature(b"other", 12, 1000, &signature));
+    assert!(!valid_preview_signature(b"secret", 12, 1000, "not base64!"));
+  }
+}